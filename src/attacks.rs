@@ -0,0 +1,310 @@
+// Precomputed attack tables. Knight, king and pawn attacks are plain
+// per-square lookups; rook and bishop attacks go through magic bitboards
+// (or PEXT when built with the "pext" feature). Everything is generated
+// once, on first use.
+
+use std::sync::LazyLock;
+
+use crate::*;
+
+const ROOK_DELTAS: [(File, Rank); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
+const BISHOP_DELTAS: [(File, Rank); 4] = [(1, 1), (1, -1), (-1, -1), (-1, 1)];
+const KING_DELTAS: [(File, Rank); 8] = [
+    (0, 1), (1, 0), (0, -1), (-1, 0),
+    (1, 1), (1, -1), (-1, -1), (-1, 1),
+];
+const KNIGHT_DELTAS: [(File, Rank); 8] = [
+    (1, 2), (2, 1), (2, -1), (1, -2),
+    (-1, -2), (-2, -1), (-2, 1), (-1, 2),
+];
+
+// Seeds that find all magics quickly, one per rank of the square.
+const MAGIC_SEEDS: [u64; 8] = [
+    728, 10316, 55013, 32803, 12281, 15100, 16645, 255,
+];
+
+struct Magic {
+    mask: Bitboard,
+    magic: u64,
+    shift: u32,
+    offset: usize,
+}
+
+struct AttackTables {
+    knight: [Bitboard; 64],
+    king: [Bitboard; 64],
+    pawn: [[Bitboard; 64]; 2],
+    rook_magics: Vec<Magic>,
+    bishop_magics: Vec<Magic>,
+    sliders: Vec<Bitboard>,
+    // Indexed by 64 * a + b, empty when a and b are not on a common line.
+    between: Vec<Bitboard>,
+    line: Vec<Bitboard>,
+}
+
+static ATTACK_TABLES: LazyLock<AttackTables> = LazyLock::new(init_attack_tables);
+
+fn offset_sq(sq: Sq, df: File, dr: Rank) -> Option<Sq> {
+    let fr = sq_to_filerank(sq);
+    let f = fr.f + df;
+    let r = fr.r + dr;
+    if (0..=7).contains(&f) && (0..=7).contains(&r) {
+        Some(fr_to_sq(f, r))
+    } else {
+        None
+    }
+}
+
+fn step_attacks_slow(sq: Sq, deltas: &[(File, Rank)]) -> Bitboard {
+    let mut result = 0;
+    for &(df, dr) in deltas {
+        if let Some(to) = offset_sq(sq, df, dr) {
+            result |= sq_bb(to);
+        }
+    }
+    result
+}
+
+// Walks every ray square by square, stopping at (and including) the first
+// occupied square. This is the reference the magic tables are built from.
+fn slider_attacks_slow(
+    sq: Sq, occupied: Bitboard, deltas: &[(File, Rank)]
+) -> Bitboard {
+    let mut result = 0;
+    for &(df, dr) in deltas {
+        let mut cur = sq;
+        while let Some(to) = offset_sq(cur, df, dr) {
+            result |= sq_bb(to);
+            if occupied & sq_bb(to) != 0 {
+                break;
+            }
+            cur = to;
+        }
+    }
+    result
+}
+
+fn pawn_attacks_slow(color: Color, sq: Sq) -> Bitboard {
+    step_attacks_slow(sq, &[(1, color), (-1, color)])
+}
+
+// The squares whose occupancy matters for a slider on sq: every ray
+// square except the last one before the board edge.
+fn slider_mask(sq: Sq, deltas: &[(File, Rank)]) -> Bitboard {
+    let mut result = 0;
+    for &(df, dr) in deltas {
+        let mut cur = sq;
+        while let Some(to) = offset_sq(cur, df, dr) {
+            if offset_sq(to, df, dr).is_none() {
+                break;
+            }
+            result |= sq_bb(to);
+            cur = to;
+        }
+    }
+    result
+}
+
+fn sparse_random(state: &mut u64) -> u64 {
+    random_next(state) & random_next(state) & random_next(state)
+}
+
+#[cfg(not(feature = "pext"))]
+fn magic_index(m: &Magic, occupied: Bitboard) -> usize {
+    m.offset + ((occupied & m.mask).wrapping_mul(m.magic) >> m.shift) as usize
+}
+
+#[cfg(feature = "pext")]
+fn magic_index(m: &Magic, occupied: Bitboard) -> usize {
+    // Checked once in init_attack_tables.
+    m.offset + unsafe { pext(occupied, m.mask) } as usize
+}
+
+#[cfg(feature = "pext")]
+#[target_feature(enable = "bmi2")]
+unsafe fn pext(occupied: Bitboard, mask: Bitboard) -> u64 {
+    std::arch::x86_64::_pext_u64(occupied, mask)
+}
+
+// Fills sliders with the attack sets for sq and returns its Magic. With
+// PEXT the index is the extracted occupancy itself and no search is needed.
+fn init_slider_sq(
+    sq: Sq, deltas: &[(File, Rank)], sliders: &mut Vec<Bitboard>
+) -> Magic {
+    let mask = slider_mask(sq, deltas);
+    let bits = bb_popcount(mask);
+    let size = 1usize << bits;
+    let mut occupancies = Vec::with_capacity(size);
+    let mut references = Vec::with_capacity(size);
+    let mut subset: Bitboard = 0;
+    loop {
+        occupancies.push(subset);
+        references.push(slider_attacks_slow(sq, subset, deltas));
+        subset = subset.wrapping_sub(mask) & mask;
+        if subset == 0 {
+            break;
+        }
+    }
+    let offset = sliders.len();
+    sliders.resize(offset + size, 0);
+    let mut m = Magic{mask, magic: 0, shift: 64 - bits, offset};
+    if cfg!(feature = "pext") {
+        for i in 0 .. size {
+            sliders[magic_index(&m, occupancies[i])] = references[i];
+        }
+        return m;
+    }
+    let mut rng = MAGIC_SEEDS[sq_to_filerank(sq).r as usize];
+    // epoch[i] records which attempt last wrote sliders[offset + i], so
+    // the table does not need clearing between attempts.
+    let mut epoch = vec![0u32; size];
+    let mut attempt = 0;
+    loop {
+        m.magic = sparse_random(&mut rng);
+        if bb_popcount(mask.wrapping_mul(m.magic) >> 56) < 6 {
+            continue;
+        }
+        attempt += 1;
+        let mut ok = true;
+        for i in 0 .. size {
+            let idx = magic_index(&m, occupancies[i]);
+            if epoch[idx - offset] < attempt {
+                epoch[idx - offset] = attempt;
+                sliders[idx] = references[i];
+            } else if sliders[idx] != references[i] {
+                ok = false;
+                break;
+            }
+        }
+        if ok {
+            return m;
+        }
+    }
+}
+
+fn init_attack_tables() -> AttackTables {
+    #[cfg(feature = "pext")]
+    if !std::arch::is_x86_feature_detected!("bmi2") {
+        panic!("Built with the pext feature but the CPU lacks BMI2");
+    }
+    let mut t = AttackTables{
+        knight: [0; 64],
+        king: [0; 64],
+        pawn: [[0; 64]; 2],
+        rook_magics: Vec::with_capacity(64),
+        bishop_magics: Vec::with_capacity(64),
+        sliders: Vec::new(),
+        between: vec![0; 64 * 64],
+        line: vec![0; 64 * 64],
+    };
+    for sq in 0 .. 64 {
+        t.knight[sq as usize] = step_attacks_slow(sq, &KNIGHT_DELTAS);
+        t.king[sq as usize] = step_attacks_slow(sq, &KING_DELTAS);
+        t.pawn[color_index(COLOR_WHITE)][sq as usize] =
+            pawn_attacks_slow(COLOR_WHITE, sq);
+        t.pawn[color_index(COLOR_BLACK)][sq as usize] =
+            pawn_attacks_slow(COLOR_BLACK, sq);
+        let m = init_slider_sq(sq, &ROOK_DELTAS, &mut t.sliders);
+        t.rook_magics.push(m);
+        let m = init_slider_sq(sq, &BISHOP_DELTAS, &mut t.sliders);
+        t.bishop_magics.push(m);
+    }
+    for a in 0 .. 64 {
+        for b in 0 .. 64 {
+            if a == b {
+                continue;
+            }
+            for deltas in [&ROOK_DELTAS, &BISHOP_DELTAS] {
+                if slider_attacks_slow(a, 0, deltas) & sq_bb(b) != 0 {
+                    let i = 64 * a as usize + b as usize;
+                    t.between[i] = slider_attacks_slow(a, sq_bb(b), deltas)
+                        & slider_attacks_slow(b, sq_bb(a), deltas);
+                    t.line[i] = (slider_attacks_slow(a, 0, deltas)
+                        & slider_attacks_slow(b, 0, deltas))
+                        | sq_bb(a) | sq_bb(b);
+                }
+            }
+        }
+    }
+    t
+}
+
+pub fn knight_attacks(sq: Sq) -> Bitboard {
+    ATTACK_TABLES.knight[sq as usize]
+}
+
+pub fn king_attacks(sq: Sq) -> Bitboard {
+    ATTACK_TABLES.king[sq as usize]
+}
+
+// Squares attacked by a pawn of the given color standing on sq.
+pub fn pawn_attacks(color: Color, sq: Sq) -> Bitboard {
+    ATTACK_TABLES.pawn[color_index(color)][sq as usize]
+}
+
+pub fn rook_attacks(sq: Sq, occupied: Bitboard) -> Bitboard {
+    let t = &*ATTACK_TABLES;
+    t.sliders[magic_index(&t.rook_magics[sq as usize], occupied)]
+}
+
+pub fn bishop_attacks(sq: Sq, occupied: Bitboard) -> Bitboard {
+    let t = &*ATTACK_TABLES;
+    t.sliders[magic_index(&t.bishop_magics[sq as usize], occupied)]
+}
+
+pub fn queen_attacks(sq: Sq, occupied: Bitboard) -> Bitboard {
+    rook_attacks(sq, occupied) | bishop_attacks(sq, occupied)
+}
+
+// The squares strictly between a and b if they share a rank, file or
+// diagonal, otherwise empty.
+pub fn between_bb(a: Sq, b: Sq) -> Bitboard {
+    ATTACK_TABLES.between[64 * a as usize + b as usize]
+}
+
+// The whole rank, file or diagonal through a and b, otherwise empty.
+pub fn line_bb(a: Sq, b: Sq) -> Bitboard {
+    ATTACK_TABLES.line[64 * a as usize + b as usize]
+}
+
+// Attacks of a non-pawn piece base from sq.
+pub fn piece_attacks(pb: Piece, sq: Sq, occupied: Bitboard) -> Bitboard {
+    match pb {
+        N_BASE => knight_attacks(sq),
+        B_BASE => bishop_attacks(sq, occupied),
+        R_BASE => rook_attacks(sq, occupied),
+        Q_BASE => queen_attacks(sq, occupied),
+        K_BASE => king_attacks(sq),
+        _ => panic!("Unexpected!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Compares every table against the slow generators, using random
+    // occupancies for the sliders.
+    #[test]
+    fn attack_tables() {
+        let mut rng = 1070372;
+        for sq in 0 .. 64 {
+            assert_eq!(knight_attacks(sq), step_attacks_slow(sq, &KNIGHT_DELTAS));
+            assert_eq!(king_attacks(sq), step_attacks_slow(sq, &KING_DELTAS));
+            for color in [COLOR_WHITE, COLOR_BLACK] {
+                assert_eq!(pawn_attacks(color, sq), pawn_attacks_slow(color, sq));
+            }
+            for _ in 0 .. 1000 {
+                let occupied = random_next(&mut rng) & random_next(&mut rng);
+                assert_eq!(
+                    rook_attacks(sq, occupied),
+                    slider_attacks_slow(sq, occupied, &ROOK_DELTAS),
+                    "rook on {}", sq_to_algstring(sq));
+                assert_eq!(
+                    bishop_attacks(sq, occupied),
+                    slider_attacks_slow(sq, occupied, &BISHOP_DELTAS),
+                    "bishop on {}", sq_to_algstring(sq));
+            }
+        }
+    }
+}
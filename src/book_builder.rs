@@ -0,0 +1,358 @@
+// Builds Polyglot books from PGN. Games are streamed one at a time and
+// their moves counted per position. The counts are kept in memory up to a
+// limit; past it they are sorted and spilled to a run file, and the runs
+// are merged at the end, so memory stays bounded however large the input.
+// The merge reads a bounded number of runs at once, merging them into
+// longer runs first if there are more.
+
+use std::collections::{BinaryHeap, HashMap};
+use std::cmp::Reverse;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::*;
+
+// Key, move, wins, draws and losses, big-endian.
+const RUN_RECORD_SIZE: usize = 22;
+
+pub struct BookBuildOptions {
+    // Both players must be rated at least this.
+    pub min_elo: Option<u32>,
+    // The results of the games to use.
+    pub results: Vec<String>,
+    // Moves are counted up to this ply of each game.
+    pub max_ply: usize,
+    // A move must have been played in at least this many games.
+    pub min_games: u32,
+    // Positions and moves held in memory before a run is spilled.
+    pub max_entries: usize,
+    // Run files open at once while merging, at least 2.
+    pub max_merge_runs: usize,
+}
+
+// Wins, draws and losses for the side playing the move.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MoveStats {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct BookBuildSummary {
+    pub games: u64,
+    pub games_used: u64,
+    pub errors: u64,
+    pub runs: usize,
+    pub entries: u64,
+}
+
+pub fn default_book_build_options() -> BookBuildOptions {
+    BookBuildOptions{
+        min_elo: None,
+        results: vec![String::from("1-0"), String::from("0-1"), String::from("1/2-1/2")],
+        max_ply: 30,
+        min_games: 1,
+        max_entries: 4_000_000,
+        max_merge_runs: 64,
+    }
+}
+
+fn add_move_stats(a: &mut MoveStats, b: &MoveStats) {
+    a.wins += b.wins;
+    a.draws += b.draws;
+    a.losses += b.losses;
+}
+
+// Two points a win and one a draw, as Polyglot counts them.
+pub fn book_move_weight(stats: &MoveStats) -> u32 {
+    2 * stats.wins + stats.draws
+}
+
+pub fn book_game_accepted(game: &PgnGame, options: &BookBuildOptions) -> bool {
+    if !options.results.contains(&game.result) {
+        return false;
+    }
+    match options.min_elo {
+        Some(min) => ["WhiteElo", "BlackElo"].iter().all(|tag| {
+            matches!(pgn_tag(game, tag).and_then(|elo| elo.parse::<u32>().ok()), Some(elo) if elo >= min)
+        }),
+        None => true,
+    }
+}
+
+fn write_run_record<W: Write>(w: &mut W, key: u64, mov: u16, stats: &MoveStats) -> std::io::Result<()> {
+    let mut bytes = [0; RUN_RECORD_SIZE];
+    bytes[0 .. 8].copy_from_slice(&key.to_be_bytes());
+    bytes[8 .. 10].copy_from_slice(&mov.to_be_bytes());
+    bytes[10 .. 14].copy_from_slice(&stats.wins.to_be_bytes());
+    bytes[14 .. 18].copy_from_slice(&stats.draws.to_be_bytes());
+    bytes[18 .. 22].copy_from_slice(&stats.losses.to_be_bytes());
+    w.write_all(&bytes)
+}
+
+// None at the end of the run.
+fn read_run_record<R: Read>(r: &mut R) -> std::io::Result<Option<(u64, u16, MoveStats)>> {
+    let mut bytes = [0; RUN_RECORD_SIZE];
+    match r.read_exact(&mut bytes) {
+        Ok(()) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => { return Ok(None); },
+        Err(e) => { return Err(e); },
+    }
+    let u32_at = |i: usize| u32::from_be_bytes(bytes[i .. i + 4].try_into().unwrap());
+    Ok(Some((
+        u64::from_be_bytes(bytes[0 .. 8].try_into().unwrap()),
+        u16::from_be_bytes(bytes[8 .. 10].try_into().unwrap()),
+        MoveStats{wins: u32_at(10), draws: u32_at(14), losses: u32_at(18)},
+    )))
+}
+
+fn run_path(temp_dir: &Path, index: usize) -> PathBuf {
+    temp_dir.join(format!("book-run-{}-{}.tmp", std::process::id(), index))
+}
+
+// Writes the counts sorted by key and move to a new run file and empties
+// them.
+fn spill_run(
+    stats: &mut HashMap<(u64, u16), MoveStats>, temp_dir: &Path, runs: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let path = run_path(temp_dir, runs.len());
+    let mut records: Vec<((u64, u16), MoveStats)> = stats.drain().collect();
+    records.sort_unstable_by_key(|(k, _)| *k);
+    let mut w = BufWriter::new(std::fs::File::create(&path)?);
+    // Listed before it is written, so that a failed run is removed too.
+    runs.push(path);
+    for ((key, mov), s) in records.iter() {
+        write_run_record(&mut w, *key, *mov, s)?;
+    }
+    w.flush()
+}
+
+// Writes the book entries of one position: the moves played often enough
+// that ever scored, heaviest first, scaled down if needed to fit 16 bits.
+fn write_position_entries<W: Write>(
+    w: &mut W, key: u64, moves: &mut Vec<(u16, MoveStats)>, options: &BookBuildOptions,
+) -> std::io::Result<u64> {
+    let mut weighted: Vec<(u16, u32)> = moves.drain(..)
+        .filter(|(_, s)| s.wins + s.draws + s.losses >= options.min_games)
+        .map(|(mov, s)| (mov, book_move_weight(&s)))
+        .filter(|(_, weight)| *weight > 0)
+        .collect();
+    let max = weighted.iter().map(|(_, weight)| *weight).max().unwrap_or(0) as u64;
+    weighted.sort_by_key(|(mov, weight)| (Reverse(*weight), *mov));
+    for (mov, weight) in weighted.iter() {
+        let weight = if max > u16::MAX as u64 {
+            (*weight as u64 * u16::MAX as u64 / max).max(1) as u16
+        } else {
+            *weight as u16
+        };
+        w.write_all(&polyglot_entry_to_bytes(&PolyglotEntry{key, mov: *mov, weight, learn: 0}))?;
+    }
+    Ok(weighted.len() as u64)
+}
+
+// Merges the sorted runs, adding up the counts of the same move in the
+// same position, and passes the totals to func in key and move order.
+fn merge_run_records<F>(runs: &[PathBuf], mut func: F) -> std::io::Result<()>
+where
+    F: FnMut(u64, u16, &MoveStats) -> std::io::Result<()>,
+{
+    let mut readers = Vec::new();
+    for path in runs {
+        readers.push(BufReader::new(std::fs::File::open(path)?));
+    }
+    let mut heap = BinaryHeap::new();
+    let mut pending = Vec::new();
+    for (i, r) in readers.iter_mut().enumerate() {
+        if let Some((key, mov, stats)) = read_run_record(r)? {
+            heap.push(Reverse((key, mov, i)));
+            pending.push(stats);
+        } else {
+            pending.push(MoveStats::default());
+        }
+    }
+    let mut current: Option<(u64, u16, MoveStats)> = None;
+    while let Some(Reverse((key, mov, i))) = heap.pop() {
+        match current.as_mut() {
+            Some((k, m, s)) if *k == key && *m == mov => add_move_stats(s, &pending[i]),
+            _ => {
+                if let Some((k, m, s)) = current.replace((key, mov, pending[i])) {
+                    func(k, m, &s)?;
+                }
+            },
+        }
+        if let Some((key, mov, stats)) = read_run_record(&mut readers[i])? {
+            heap.push(Reverse((key, mov, i)));
+            pending[i] = stats;
+        }
+    }
+    if let Some((k, m, s)) = current {
+        func(k, m, &s)?;
+    }
+    Ok(())
+}
+
+// Merges the first max_merge_runs runs into a new one at the end until no
+// more than that many are left. The merged runs are removed.
+fn reduce_runs(
+    runs: &mut Vec<PathBuf>, temp_dir: &Path, next_run: &mut usize, options: &BookBuildOptions,
+) -> std::io::Result<()> {
+    let fan_in = options.max_merge_runs.max(2);
+    while runs.len() > fan_in {
+        let group: Vec<PathBuf> = runs.drain(.. fan_in).collect();
+        let path = run_path(temp_dir, *next_run);
+        *next_run += 1;
+        runs.push(path.clone());
+        let merged = std::fs::File::create(&path).and_then(|file| {
+            let mut w = BufWriter::new(file);
+            merge_run_records(&group, |key, mov, stats| write_run_record(&mut w, key, mov, stats))?;
+            w.flush()
+        });
+        for path in group.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+        merged?;
+    }
+    Ok(())
+}
+
+// Merges the runs and writes the book.
+fn merge_runs<W: Write>(runs: &[PathBuf], w: &mut W, options: &BookBuildOptions) -> std::io::Result<u64> {
+    let mut entries = 0;
+    let mut current_key = None;
+    let mut moves: Vec<(u16, MoveStats)> = Vec::new();
+    merge_run_records(runs, |key, mov, stats| {
+        if current_key != Some(key) {
+            if let Some(k) = current_key {
+                entries += write_position_entries(w, k, &mut moves, options)?;
+            }
+            current_key = Some(key);
+        }
+        moves.push((mov, *stats));
+        Ok(())
+    })?;
+    if let Some(k) = current_key {
+        entries += write_position_entries(w, k, &mut moves, options)?;
+    }
+    Ok(entries)
+}
+
+// Reads every game of reader and writes a book of the accepted ones to
+// writer, spilling runs to temp_dir when the counts outgrow memory. Games
+// that fail to parse are counted and skipped.
+pub fn build_polyglot_book<R: BufRead, W: Write>(
+    reader: R, writer: &mut W, options: &BookBuildOptions, temp_dir: &Path,
+) -> std::io::Result<BookBuildSummary> {
+    let mut summary = BookBuildSummary::default();
+    let mut stats: HashMap<(u64, u16), MoveStats> = HashMap::new();
+    let mut runs = Vec::new();
+    let mut result = Ok(());
+    for_each_pgn_game(reader, |game| {
+        summary.games += 1;
+        let game = match game {
+            Ok(game) => game,
+            Err(_) => {
+                summary.errors += 1;
+                return;
+            },
+        };
+        if result.is_err() || !book_game_accepted(&game, options) {
+            return;
+        }
+        summary.games_used += 1;
+        let white_score = match game.result.as_str() {
+            "1-0" => 1,
+            "0-1" => -1,
+            _ => 0,
+        };
+        for (pos, node) in game.positions.iter().zip(game.line.moves.iter()).take(options.max_ply) {
+            let mov = unpack_move(pos, node.mov);
+            let entry = stats.entry((polyglot_key(pos), move_to_polyglot(&mov))).or_default();
+            match white_score * pos.active_color {
+                1 => entry.wins += 1,
+                -1 => entry.losses += 1,
+                _ => entry.draws += 1,
+            }
+        }
+        if stats.len() >= options.max_entries {
+            result = spill_run(&mut stats, temp_dir, &mut runs);
+        }
+    });
+    if result.is_ok() && !stats.is_empty() {
+        result = spill_run(&mut stats, temp_dir, &mut runs);
+    }
+    summary.runs = runs.len();
+    let mut next_run = runs.len();
+    let mut w = BufWriter::new(writer);
+    let merged = result
+        .and_then(|()| reduce_runs(&mut runs, temp_dir, &mut next_run, options))
+        .and_then(|()| merge_runs(&runs, &mut w, options));
+    for path in runs.iter() {
+        let _ = std::fs::remove_file(path);
+    }
+    summary.entries = merged?;
+    w.flush()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a book from a few games, spilling a run after every game, and
+    // reads it back.
+    #[test]
+    fn book_builder() {
+        let pgn = "\
+    [WhiteElo \"2500\"]\n[BlackElo \"2400\"]\n[Result \"1-0\"]\n\n1. e4 e5 2. Nf3 1-0\n\n\
+    [WhiteElo \"2500\"]\n[BlackElo \"2400\"]\n[Result \"0-1\"]\n\n1. e4 c5 0-1\n\n\
+    [WhiteElo \"2500\"]\n[BlackElo \"2400\"]\n[Result \"1/2-1/2\"]\n\n1. d4 d5 1/2-1/2\n\n\
+    [WhiteElo \"1200\"]\n[BlackElo \"2400\"]\n[Result \"1-0\"]\n\n1. b4 e5 1-0\n\n\
+    [WhiteElo \"2500\"]\n[BlackElo \"2400\"]\n[Result \"*\"]\n\n1. c4 *\n\n\
+    [WhiteElo \"2500\"]\n[BlackElo \"2400\"]\n[Result \"1-0\"]\n\n1. e4 f6 1-0\n";
+        let mut options = default_book_build_options();
+        options.min_elo = Some(2000);
+        options.max_ply = 2;
+        options.max_entries = 1;
+        let mut bytes = Vec::new();
+        let summary = build_polyglot_book(pgn.as_bytes(), &mut bytes, &options, &std::env::temp_dir()).unwrap();
+        assert_eq!((summary.games, summary.games_used, summary.errors, summary.runs), (6, 4, 0, 4));
+        let book = parse_polyglot_book(&bytes);
+        let start = decode_fen(String::from(STARTING_FEN));
+        let weights = |pos: &Position| -> Vec<(String, u16)> {
+            polyglot_book_moves(&book, pos).iter()
+                .map(|(mov, weight)| (move_to_string(mov, pos), *weight))
+                .collect()
+        };
+        // e4 won twice and lost once; d4 drew.
+        assert_eq!(weights(&start), vec![(String::from("e4"), 4), (String::from("d4"), 1)]);
+        // Black's e5 and f6 lost, so only c5 is kept; Nf3 is past max_ply.
+        let e4 = decode_fen(String::from("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"));
+        assert_eq!(weights(&e4), vec![(String::from("c5"), 2)]);
+        // With d5 after d4.
+        assert_eq!(summary.entries, 4);
+        options.min_games = 2;
+        let mut bytes = Vec::new();
+        build_polyglot_book(pgn.as_bytes(), &mut bytes, &options, &std::env::temp_dir()).unwrap();
+        assert_eq!(bytes.len(), POLYGLOT_ENTRY_SIZE);
+        // Merging two runs at a time takes several passes and gives the
+        // same book.
+        options.min_games = 1;
+        let mut expected = Vec::new();
+        build_polyglot_book(pgn.as_bytes(), &mut expected, &options, &std::env::temp_dir()).unwrap();
+        options.max_merge_runs = 2;
+        let mut bytes = Vec::new();
+        build_polyglot_book(pgn.as_bytes(), &mut bytes, &options, &std::env::temp_dir()).unwrap();
+        assert_eq!(bytes, expected);
+        let prefix = format!("book-run-{}-", std::process::id());
+        assert!(std::fs::read_dir(std::env::temp_dir()).unwrap()
+            .all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(&prefix)));
+        // A directory in the way of the second run fails the build, and the
+        // first run is removed all the same.
+        let dir = std::env::temp_dir().join(format!("book-builder-{}", std::process::id()));
+        std::fs::create_dir_all(run_path(&dir, 1)).unwrap();
+        assert!(build_polyglot_book(pgn.as_bytes(), &mut Vec::new(), &options, &dir).is_err());
+        assert!(!run_path(&dir, 0).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+// Knowledge of particular endings that material and piece-square tables
+// get wrong: a KPK bitbase, evaluations of the endings that are won by
+// driving the king somewhere or drawn by a fortress, and factors that
+// scale down the endgame score when the side ahead cannot win.
+
+use std::sync::LazyLock;
+
+use crate::*;
+
+const DARK_SQUARES_BB: Bitboard = 0xAA55AA55AA55AA55;
+
+// Below any mate or tablebase win, above any material count.
+pub const KNOWN_WIN: Score = 10000;
+// The endgame score is scaled by this over SCALE_NORMAL.
+pub const SCALE_NORMAL: i32 = 64;
+
+// An evaluation from the point of view of strong, the side whose material
+// key is first.
+type EndgameFn = fn(&Position, Color) -> Score;
+
+// By the material keys of the stronger side and the other, for up to five
+// men.
+const ENDGAME_EVALUATORS: [((u32, u32), EndgameFn); 7] = [
+    ((0x00001, 0x00000), evaluate_kpk), // KPvK
+    ((0x00020, 0x00000), evaluate_draw), // KNNvK
+    ((0x00110, 0x00000), evaluate_kbnk), // KBNvK
+    ((0x01000, 0x00001), evaluate_krkp), // KRvKP
+    ((0x01000, 0x00100), evaluate_krkb), // KRvKB
+    ((0x01000, 0x00010), evaluate_krkn), // KRvKN
+    ((0x10000, 0x00001), evaluate_kqkp), // KQvKP
+];
+
+// White king, black king, side to move (white, then black), and a white
+// pawn on the files a to d of ranks 2 to 7.
+const KPK_SIZE: usize = 64 * 64 * 2 * 24;
+
+// Bit set for each position white wins.
+static KPK_BITBASE: LazyLock<Vec<u64>> = LazyLock::new(generate_kpk_bitbase);
+
+fn kpk_index(white_to_move: bool, white_king: Sq, black_king: Sq, pawn: Sq) -> usize {
+    let file = (pawn & 7) as usize;
+    let rank = (pawn >> 3) as usize;
+    white_king as usize | (black_king as usize) << 6 | (!white_to_move as usize) << 12
+        | file << 13 | (6 - rank) << 15
+}
+
+fn sq_distance(a: Sq, b: Sq) -> i32 {
+    let a = sq_to_filerank(a);
+    let b = sq_to_filerank(b);
+    ((a.f - b.f).abs()).max((a.r - b.r).abs()) as i32
+}
+
+fn is_dark_sq(sq: Sq) -> bool {
+    (sq / 8 + sq % 8) % 2 == 0
+}
+
+// Retrograde classification as in Stockfish: every position starts as
+// illegal, won, drawn or unknown, and unknown ones take the best result
+// for the side to move among their successors until nothing changes.
+fn generate_kpk_bitbase() -> Vec<u64> {
+    const INVALID: u8 = 0;
+    const UNKNOWN: u8 = 1;
+    const DRAW: u8 = 2;
+    const WIN: u8 = 4;
+    let mut db = vec![INVALID; KPK_SIZE];
+    let mut positions = Vec::new();
+    for file in 0 .. 4 {
+        for rank in 1 .. 7 {
+            let pawn = 8 * rank + file;
+            let push = pawn + 8;
+            for white_king in 0 .. 64 {
+                for black_king in 0 .. 64 {
+                    for white_to_move in [true, false] {
+                        let idx = kpk_index(white_to_move, white_king, black_king, pawn);
+                        let black_king_bb = sq_bb(black_king);
+                        db[idx] = if sq_distance(white_king, black_king) <= 1 || white_king == pawn
+                            || black_king == pawn
+                            || (white_to_move && pawn_attacks(COLOR_WHITE, pawn) & black_king_bb != 0) {
+                            INVALID
+                        } else if white_to_move && rank == 6 && white_king != push && black_king != push
+                            && (sq_distance(black_king, push) > 1 || sq_distance(white_king, push) == 1) {
+                            WIN
+                        } else if !white_to_move
+                            && (king_attacks(black_king) & !(king_attacks(white_king) | pawn_attacks(COLOR_WHITE, pawn)) == 0
+                                || king_attacks(black_king) & !king_attacks(white_king) & sq_bb(pawn) != 0) {
+                            DRAW
+                        } else {
+                            UNKNOWN
+                        };
+                        if db[idx] == UNKNOWN {
+                            positions.push((idx, white_to_move, white_king, black_king, pawn));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(idx, white_to_move, white_king, black_king, pawn) in positions.iter() {
+            if db[idx] != UNKNOWN {
+                continue;
+            }
+            let (good, bad) = if white_to_move { (WIN, DRAW) } else { (DRAW, WIN) };
+            let mut r = INVALID;
+            let mover = if white_to_move { white_king } else { black_king };
+            for_each_sq_in_bb(king_attacks(mover), |sq| {
+                r |= if white_to_move {
+                    db[kpk_index(false, sq, black_king, pawn)]
+                } else {
+                    db[kpk_index(true, white_king, sq, pawn)]
+                };
+            });
+            if white_to_move {
+                if pawn >> 3 < 6 {
+                    r |= db[kpk_index(false, white_king, black_king, pawn + 8)];
+                }
+                if pawn >> 3 == 1 && pawn + 8 != white_king && pawn + 8 != black_king {
+                    r |= db[kpk_index(false, white_king, black_king, pawn + 16)];
+                }
+            }
+            let result = if r & good != 0 { good } else if r & UNKNOWN != 0 { UNKNOWN } else { bad };
+            if result != UNKNOWN {
+                db[idx] = result;
+                changed = true;
+            }
+        }
+    }
+    let mut bits = vec![0; KPK_SIZE / 64];
+    for (idx, &result) in db.iter().enumerate() {
+        if result == WIN {
+            bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+    bits
+}
+
+// Whether strong, with king and pawn against the bare king, wins with
+// stm to move.
+pub fn kpk_probe(strong_king: Sq, pawn: Sq, weak_king: Sq, strong: Color, stm: Color) -> bool {
+    // Seen from white, with the pawn on the queenside.
+    let flip_rank = if strong == COLOR_WHITE { 0 } else { 56 };
+    let flip_file = if pawn & 7 > 3 { 7 } else { 0 };
+    let normalize = |sq: Sq| sq ^ flip_rank ^ flip_file;
+    let idx = kpk_index(stm == strong, normalize(strong_king), normalize(weak_king), normalize(pawn));
+    KPK_BITBASE[idx / 64] & (1 << (idx % 64)) != 0
+}
+
+fn king_sq(pos: &Position, color: Color) -> Sq {
+    bb_lsb(pieces_bb(pos, K_BASE * color))
+}
+
+fn single_sq(pos: &Position, piece: Piece) -> Sq {
+    bb_lsb(pieces_bb(pos, piece))
+}
+
+// 10 in the centre to 70 in the corners.
+fn push_to_edge(sq: Sq) -> Score {
+    let fr = sq_to_filerank(sq);
+    5 * ((2 * fr.f as Score - 7).abs() + (2 * fr.r as Score - 7).abs())
+}
+
+// 120 for adjacent squares down to 0 for the farthest.
+fn push_close(a: Sq, b: Sq) -> Score {
+    140 - 20 * sq_distance(a, b)
+}
+
+fn push_away(a: Sq, b: Sq) -> Score {
+    120 - push_close(a, b)
+}
+
+// 0 far from them up to 210 in the corners of the bishop's color.
+fn push_to_bishop_corner(sq: Sq, dark_bishop: bool) -> Score {
+    let (c1, c2) = if dark_bishop { (0, 63) } else { (7, 56) };
+    let manhattan = |a: Sq, b: Sq| {
+        let a = sq_to_filerank(a);
+        let b = sq_to_filerank(b);
+        ((a.f - b.f).abs() + (a.r - b.r).abs()) as Score
+    };
+    30 * (7 - manhattan(sq, c1).min(manhattan(sq, c2)).min(7))
+}
+
+fn non_pawn_material(pos: &Position, color: Color) -> Score {
+    [R_BASE, N_BASE, B_BASE, Q_BASE].iter()
+        .map(|&pb| bb_popcount(pieces_bb(pos, pb * color)) as Score * PIECE_VALUES[piece_index(pb)])
+        .sum()
+}
+
+// A queen, a rook, bishops of both colors, or bishop and knight.
+fn has_mating_material(pos: &Position, color: Color) -> bool {
+    let bishops = pieces_bb(pos, B_BASE * color);
+    pieces_bb(pos, Q_BASE * color) | pieces_bb(pos, R_BASE * color) != 0
+        || (bishops & DARK_SQUARES_BB != 0 && bishops & !DARK_SQUARES_BB != 0)
+        || (bishops != 0 && pieces_bb(pos, N_BASE * color) != 0)
+}
+
+// Mating the bare king of the other side: drive it to the edge and bring
+// the king close.
+fn evaluate_kxk(pos: &Position, strong: Color) -> Score {
+    let strong_king = king_sq(pos, strong);
+    let weak_king = king_sq(pos, -strong);
+    let pawns = bb_popcount(pieces_bb(pos, P_BASE * strong)) as Score;
+    KNOWN_WIN + non_pawn_material(pos, strong) + pawns * PIECE_VALUES[0]
+        + push_to_edge(weak_king) + push_close(strong_king, weak_king)
+}
+
+fn evaluate_draw(_: &Position, _: Color) -> Score {
+    0
+}
+
+fn evaluate_kpk(pos: &Position, strong: Color) -> Score {
+    let pawn = single_sq(pos, P_BASE * strong);
+    if !kpk_probe(king_sq(pos, strong), pawn, king_sq(pos, -strong), strong, pos.active_color) {
+        return 0;
+    }
+    let rank = if strong == COLOR_WHITE { pawn >> 3 } else { 7 - (pawn >> 3) };
+    KNOWN_WIN + PIECE_VALUES[0] + 10 * rank as Score
+}
+
+// Mate can only be given in a corner of the bishop's color.
+fn evaluate_kbnk(pos: &Position, strong: Color) -> Score {
+    let strong_king = king_sq(pos, strong);
+    let weak_king = king_sq(pos, -strong);
+    let dark_bishop = is_dark_sq(single_sq(pos, B_BASE * strong));
+    KNOWN_WIN + PIECE_VALUES[piece_index(B_BASE)] + PIECE_VALUES[piece_index(N_BASE)]
+        + push_close(strong_king, weak_king) + 2 * push_to_bishop_corner(weak_king, dark_bishop)
+}
+
+// A win when the rook can stop the pawn before the king supports it, and
+// otherwise close to a draw, less so the farther the pawn is from
+// promoting. Worked out with strong as white.
+fn evaluate_krkp(pos: &Position, strong: Color) -> Score {
+    let flip = if strong == COLOR_WHITE { 0 } else { 56 };
+    let strong_king = king_sq(pos, strong) ^ flip;
+    let weak_king = king_sq(pos, -strong) ^ flip;
+    let rook = single_sq(pos, R_BASE * strong) ^ flip;
+    let pawn = single_sq(pos, P_BASE * -strong) ^ flip;
+    let queening = pawn & 7;
+    let strong_to_move = (pos.active_color == strong) as i32;
+    let rook_value = PIECE_VALUES[piece_index(R_BASE)];
+    // The king stands in the way of the pawn, or the other king is too far
+    // from it and the rook.
+    if (strong_king & 7) == (pawn & 7) && strong_king < pawn {
+        return rook_value - sq_distance(strong_king, pawn);
+    }
+    if sq_distance(weak_king, pawn) >= 4 - strong_to_move && sq_distance(weak_king, rook) >= 3 {
+        return rook_value - sq_distance(strong_king, pawn);
+    }
+    if weak_king >> 3 <= 2 && sq_distance(weak_king, pawn) == 1 && strong_king >> 3 >= 3
+        && sq_distance(strong_king, pawn) > 2 + strong_to_move {
+        return 80 - 8 * sq_distance(strong_king, pawn);
+    }
+    200 - 8 * (sq_distance(strong_king, pawn - 8) - sq_distance(weak_king, pawn - 8) - sq_distance(pawn, queening))
+}
+
+// Usually a draw with the bishop's king in a corner the bishop does not
+// control: there the rook side has no mating net. Elsewhere it has some
+// chances, the better the nearer the king is to the other corners.
+fn evaluate_krkb(pos: &Position, strong: Color) -> Score {
+    let weak_king = king_sq(pos, -strong);
+    let dark_bishop = is_dark_sq(single_sq(pos, B_BASE * -strong));
+    if push_to_bishop_corner(weak_king, !dark_bishop) >= 150 {
+        return 0;
+    }
+    push_to_edge(weak_king) / 2 + push_to_bishop_corner(weak_king, dark_bishop) / 3
+}
+
+// A draw unless the knight strays from its king.
+fn evaluate_krkn(pos: &Position, strong: Color) -> Score {
+    let weak_king = king_sq(pos, -strong);
+    let knight = single_sq(pos, N_BASE * -strong);
+    push_to_edge(weak_king) / 2 + push_away(weak_king, knight)
+}
+
+// A win except against a rook or bishop pawn on the seventh rank with
+// its king beside it and the queen's king far away.
+fn evaluate_kqkp(pos: &Position, strong: Color) -> Score {
+    let strong_king = king_sq(pos, strong);
+    let weak_king = king_sq(pos, -strong);
+    let pawn = single_sq(pos, P_BASE * -strong);
+    let mut score = push_close(strong_king, weak_king);
+    let pawn_rank = if strong == COLOR_WHITE { 7 - (pawn >> 3) } else { pawn >> 3 };
+    let file = pawn & 7;
+    if pawn_rank != 6 || sq_distance(weak_king, pawn) != 1 || [1, 3, 4, 6].contains(&file) {
+        score += PIECE_VALUES[piece_index(Q_BASE)] - PIECE_VALUES[0];
+    }
+    score
+}
+
+// The numbers of queens, rooks, bishops, knights and pawns of color, a
+// hex digit each, so that KRP is 0x01001.
+fn side_material_key(pos: &Position, color: Color) -> u32 {
+    [Q_BASE, R_BASE, B_BASE, N_BASE, P_BASE].iter()
+        .fold(0, |key, &pb| key << 4 | bb_popcount(pieces_bb(pos, pb * color)))
+}
+
+// The evaluation of a recognised ending from white's point of view, or
+// None to evaluate pos as usual.
+pub fn endgame_evaluate_white(pos: &Position) -> Option<Score> {
+    if bb_popcount(occupied_bb(pos)) <= 5 {
+        let white = side_material_key(pos, COLOR_WHITE);
+        let black = side_material_key(pos, COLOR_BLACK);
+        for (key, evaluator) in ENDGAME_EVALUATORS {
+            if key == (white, black) {
+                return Some(evaluator(pos, COLOR_WHITE));
+            }
+            if key == (black, white) {
+                return Some(-evaluator(pos, COLOR_BLACK));
+            }
+        }
+    }
+    for strong in [COLOR_WHITE, COLOR_BLACK] {
+        if color_bb(pos, -strong) == pieces_bb(pos, K_BASE * -strong) && has_mating_material(pos, strong) {
+            return Some(evaluate_kxk(pos, strong) * strong as Score);
+        }
+    }
+    None
+}
+
+fn rook_file_pawns_drawn(pos: &Position, strong: Color) -> bool {
+    let pawns = pieces_bb(pos, P_BASE * strong);
+    let file_a = file_bb(0);
+    let file_h = file_bb(7);
+    if pawns == 0 || (pawns & !file_a != 0 && pawns & !file_h != 0) {
+        return false;
+    }
+    let file = bb_lsb(pawns) & 7;
+    let queening = file + if strong == COLOR_WHITE { 56 } else { 0 };
+    let weak_king = king_sq(pos, -strong);
+    let bishops = pieces_bb(pos, B_BASE * strong);
+    let others = non_pawn_material(pos, strong) - bb_popcount(bishops) as Score * PIECE_VALUES[piece_index(B_BASE)];
+    if others != 0 || non_pawn_material(pos, -strong) != 0 {
+        return false;
+    }
+    if bishops == 0 {
+        // The king in front of all the pawns stops them.
+        let frontmost = if strong == COLOR_WHITE { 63 - pawns.leading_zeros() as Sq } else { bb_lsb(pawns) };
+        let ahead = if strong == COLOR_WHITE { weak_king >> 3 > frontmost >> 3 } else { weak_king >> 3 < frontmost >> 3 };
+        return ((weak_king & 7) - file).abs() <= 1 && ahead;
+    }
+    // A bishop that cannot drive the king out of the queening corner.
+    let wrong_bishop = if is_dark_sq(queening) { bishops & DARK_SQUARES_BB == 0 } else { bishops & !DARK_SQUARES_BB == 0 };
+    wrong_bishop && sq_distance(weak_king, queening) <= 1
+}
+
+fn is_opposite_bishops(pos: &Position) -> bool {
+    let white = pieces_bb(pos, B_BASE);
+    let black = pieces_bb(pos, -B_BASE);
+    bb_popcount(white) == 1 && bb_popcount(black) == 1
+        && is_dark_sq(bb_lsb(white)) != is_dark_sq(bb_lsb(black))
+}
+
+// Out of SCALE_NORMAL, how much of an endgame advantage strong can hope
+// to turn into a win.
+pub fn endgame_scale_factor(pos: &Position, strong: Color) -> i32 {
+    if rook_file_pawns_drawn(pos, strong) {
+        return 0;
+    }
+    let strong_material = non_pawn_material(pos, strong);
+    let weak_material = non_pawn_material(pos, -strong);
+    // Without pawns a minor piece more is not enough.
+    if pieces_bb(pos, P_BASE * strong) == 0 && strong_material - weak_material <= PIECE_VALUES[piece_index(B_BASE)] {
+        return if strong_material < PIECE_VALUES[piece_index(R_BASE)] { 0 } else { 16 };
+    }
+    if is_opposite_bishops(pos) {
+        let bishop = PIECE_VALUES[piece_index(B_BASE)];
+        if strong_material == bishop && weak_material == bishop {
+            let their_pawns = pieces_bb(pos, P_BASE * -strong);
+            let mut passed = 0;
+            for_each_sq_in_bb(pieces_bb(pos, P_BASE * strong), |sq| {
+                if their_pawns & passed_pawn_span(strong, sq) == 0 {
+                    passed += 1;
+                }
+            });
+            return (18 + 4 * passed).min(SCALE_NORMAL);
+        }
+        let pieces = bb_popcount(color_bb(pos, strong) & !pieces_bb(pos, P_BASE * strong)) as i32 - 1;
+        return (22 + 3 * pieces).min(SCALE_NORMAL);
+    }
+    SCALE_NORMAL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the bitbase against the generated KPvK table and the endings
+    // against known results.
+    #[test]
+    fn endgames() {
+        let mut tbs = new_tablebases();
+        generate_tablebase(&mut tbs, "KPvK").unwrap();
+        let mut checked = 0;
+        for white_king in 0 .. 64 {
+            for black_king in 0 .. 64 {
+                for pawn in 8 .. 56 {
+                    for stm in [COLOR_WHITE, COLOR_BLACK] {
+                        if sq_distance(white_king, black_king) <= 1 || pawn == white_king || pawn == black_king {
+                            continue;
+                        }
+                        let mut pos = empty_position();
+                        set_piece_at_sq(&mut pos, white_king, K_BASE);
+                        set_piece_at_sq(&mut pos, black_king, -K_BASE);
+                        set_piece_at_sq(&mut pos, pawn, P_BASE);
+                        pos.active_color = stm;
+                        let Some(outcome) = tb_probe(&tbs, &pos) else {
+                            continue;
+                        };
+                        let wins = kpk_probe(white_king, pawn, black_king, COLOR_WHITE, stm);
+                        let white_wins = match outcome {
+                            TbOutcome::Win(_) => stm == COLOR_WHITE,
+                            TbOutcome::Draw => false,
+                            TbOutcome::Loss(_) => stm == COLOR_BLACK,
+                        };
+                        assert_eq!(wins, white_wins, "{}", encode_fen(&pos));
+                        // The same with the colors reversed.
+                        let black_wins = kpk_probe(white_king ^ 56, pawn ^ 56, black_king ^ 56, COLOR_BLACK, -stm);
+                        assert_eq!(wins, black_wins, "{}", encode_fen(&pos));
+                        checked += 1;
+                    }
+                }
+            }
+        }
+        assert!(checked > 300000);
+        let eval = |fen: &str| evaluate_white(&decode_fen(String::from(fen)));
+        // Won pawn endings are known wins, drawn ones draws.
+        assert!(eval("4k3/8/4K3/4P3/8/8/8/8 b - - 0 1") > KNOWN_WIN);
+        assert_eq!(eval("4k3/4P3/4K3/8/8/8/8/8 b - - 0 1"), 0);
+        assert_eq!(eval("8/8/8/8/3k4/8/3K4/3q4 w - - 0 1"), -eval("3Q4/3k4/8/3K4/8/8/8/8 b - - 0 1"));
+        // The bare king is driven to the edge, and in KBNK to the corner of
+        // the bishop's color.
+        assert!(eval("8/8/8/3k4/8/8/8/R3K3 w - - 0 1") > KNOWN_WIN);
+        assert!(eval("k7/8/2K5/8/8/8/8/R7 w - - 0 1") > eval("8/8/8/3k4/8/3K4/8/R7 w - - 0 1"));
+        assert!(eval("8/8/8/3k4/8/8/8/Q3K3 w - - 0 1") > eval("8/8/8/3k4/8/8/8/R3K3 w - - 0 1"));
+        let dark_corner = eval("7k/8/5K2/8/8/8/8/2B1N3 w - - 0 1");
+        let light_corner = eval("k7/8/2K5/8/8/8/8/2B1N3 w - - 0 1");
+        assert!(dark_corner > KNOWN_WIN && dark_corner > light_corner + 200);
+        // KRvKB is drawn in the safe corner, KRvKP won with the king in front.
+        assert_eq!(eval("k7/8/1K6/8/8/8/3b4/7R w - - 0 1"), 0);
+        assert!(eval("7k/8/6K1/8/8/8/3b4/R7 w - - 0 1") > 0);
+        assert!(eval("8/8/8/3p4/8/3K4/8/R6k w - - 0 1") > eval("8/8/8/8/8/8/k2p4/R6K w - - 0 1") + 200);
+        // A rook pawn with the wrong bishop is a draw, with the right one won.
+        assert_eq!(eval("k7/8/8/8/8/8/P7/2B1K3 w - - 0 1"), 0);
+        assert!(eval("k7/8/8/8/8/8/P7/1B2K3 w - - 0 1") > 200);
+        assert_eq!(eval("8/8/8/8/8/k2b4/p7/K7 w - - 0 1"), 0);
+        // Opposite bishops take most of a pawn's worth away.
+        let opposite = eval("4k3/5p2/3b4/8/3P4/2PB4/5K2/8 w - - 0 1");
+        let same = eval("4k3/5p2/4b3/8/3P4/2PB4/5K2/8 w - - 0 1");
+        assert!(opposite > 0 && opposite < same / 2);
+        // A lone minor piece cannot win.
+        assert_eq!(eval("8/8/3k4/8/8/3BK3/8/8 w - - 0 1"), 0);
+        let pos = decode_fen(String::from("8/8/3k4/2p5/8/3RK3/3P4/8 w - - 0 1"));
+        assert_eq!((side_material_key(&pos, COLOR_WHITE), side_material_key(&pos, COLOR_BLACK)), (0x01001, 0x00001));
+    }
+}
@@ -0,0 +1,382 @@
+// Extended Position Description: the first four FEN fields followed by
+// semicolon-terminated operations, each an opcode and its operands. Also
+// the runner for test suites (WAC, STS, ECM and the like) written in it.
+
+use std::io::BufRead;
+
+use crate::*;
+
+pub struct EpdOperation {
+    pub opcode: String,
+    pub operands: Vec<String>,
+}
+
+pub struct Epd {
+    // Clocks come from the hmvc and fmvn opcodes, 0 and 1 without them.
+    pub position: Position,
+    pub operations: Vec<EpdOperation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EpdErrorKind {
+    BadFen(FenError),
+    UnterminatedString,
+    // The opcode and the move that does not parse.
+    BadMove(String, MoveParseError),
+    // The opcode whose operand should be a single integer.
+    BadNumber(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpdError {
+    pub kind: EpdErrorKind,
+    // Character offset into the line.
+    pub offset: usize,
+}
+
+impl std::fmt::Display for EpdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.kind {
+            EpdErrorKind::BadFen(e) => write!(f, "{}", e),
+            EpdErrorKind::UnterminatedString =>
+                write!(f, "unterminated string at offset {}", self.offset),
+            EpdErrorKind::BadMove(opcode, e) =>
+                write!(f, "{} in {} at offset {}", e, opcode, self.offset),
+            EpdErrorKind::BadNumber(opcode) =>
+                write!(f, "{} needs a single integer at offset {}", opcode, self.offset),
+        }
+    }
+}
+
+impl std::error::Error for EpdError {}
+
+// Opcodes whose operands are moves from the position, and those that take
+// one integer.
+const EPD_MOVE_OPCODES: [&str; 2] = ["bm", "am"];
+const EPD_NUMBER_OPCODES: [&str; 7] = ["dm", "acd", "ce", "acn", "acs", "hmvc", "fmvn"];
+
+fn epd_error(kind: EpdErrorKind, offset: usize) -> EpdError {
+    EpdError{kind, offset}
+}
+
+// EPD files in the wild hold both SAN and coordinate moves.
+fn parse_epd_move(pos: &Position, s: &str) -> Result<Move, MoveParseError> {
+    parse_san(pos, s).or_else(|e| parse_uci(pos, s).map_err(|_| e))
+}
+
+// Splits the text after the position fields into operations. chars are
+// the characters of the whole line, from start on.
+fn parse_epd_operations(
+    chars: &[char], start: usize
+) -> Result<Vec<(usize, EpdOperation)>, EpdError> {
+    let mut result = Vec::new();
+    let mut i = start;
+    loop {
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        if i >= chars.len() {
+            return Ok(result);
+        }
+        let op_offset = i;
+        let mut words: Vec<String> = Vec::new();
+        // The last operation may lack its semicolon.
+        while i < chars.len() && chars[i] != ';' {
+            if chars[i].is_whitespace() {
+                i += 1;
+            } else if chars[i] == '"' {
+                let quote = i;
+                i += 1;
+                let mut word = String::new();
+                while i < chars.len() && chars[i] != '"' {
+                    word.push(chars[i]);
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err(epd_error(EpdErrorKind::UnterminatedString, quote));
+                }
+                i += 1;
+                words.push(word);
+            } else {
+                let mut word = String::new();
+                while i < chars.len() && !chars[i].is_whitespace() && chars[i] != ';' {
+                    word.push(chars[i]);
+                    i += 1;
+                }
+                words.push(word);
+            }
+        }
+        i += 1;
+        if !words.is_empty() {
+            let opcode = words.remove(0);
+            result.push((op_offset, EpdOperation{opcode, operands: words}));
+        }
+    }
+}
+
+// Parses one EPD record. The operands of bm, am and pv must be legal moves
+// and those of dm, acd, ce and the other counters integers; other opcodes
+// are kept as they are.
+pub fn parse_epd(line: &str) -> Result<Epd, EpdError> {
+    let chars: Vec<char> = line.chars().collect();
+    let fields = split_fields_with_offsets(line);
+    let n_fields = fields.len().min(4);
+    let mut position = parse_fen_position_fields(&fields[.. n_fields], chars.len())
+        .map_err(|e| { let offset = e.offset; epd_error(EpdErrorKind::BadFen(e), offset) })?;
+    let (last_offset, last_field) = fields[3];
+    let rest = last_offset + last_field.chars().count();
+    position.halfmoves = 0;
+    position.fullmoves = 1;
+    let mut operations = Vec::new();
+    for (offset, op) in parse_epd_operations(&chars, rest)? {
+        let opcode = op.opcode.as_str();
+        if EPD_MOVE_OPCODES.contains(&opcode) {
+            for s in op.operands.iter() {
+                if let Err(e) = parse_epd_move(&position, s) {
+                    return Err(epd_error(EpdErrorKind::BadMove(op.opcode.clone(), e), offset));
+                }
+            }
+        } else if opcode == "pv" {
+            let mut cur = copy_position(&position);
+            for s in op.operands.iter() {
+                match parse_epd_move(&cur, s) {
+                    Ok(mov) => { make_move(&mut cur, &mov); },
+                    Err(e) => {
+                        return Err(epd_error(EpdErrorKind::BadMove(op.opcode.clone(), e), offset));
+                    },
+                }
+            }
+        } else if EPD_NUMBER_OPCODES.contains(&opcode) {
+            let n = match op.operands.as_slice() {
+                [s] => s.parse::<i64>().ok(),
+                _ => None,
+            };
+            let Some(n) = n else {
+                return Err(epd_error(EpdErrorKind::BadNumber(op.opcode.clone()), offset));
+            };
+            match opcode {
+                "hmvc" => { position.halfmoves = n as i32; },
+                "fmvn" => { position.fullmoves = n as i32; },
+                _ => {},
+            }
+        }
+        operations.push(op);
+    }
+    Ok(Epd{position, operations})
+}
+
+pub fn epd_operands<'a>(epd: &'a Epd, opcode: &str) -> Option<&'a [String]> {
+    epd.operations.iter()
+        .find(|op| op.opcode == opcode)
+        .map(|op| op.operands.as_slice())
+}
+
+// The first operand of opcode, for the string opcodes: id, c0 to c9.
+pub fn epd_string<'a>(epd: &'a Epd, opcode: &str) -> Option<&'a str> {
+    epd_operands(epd, opcode)?.first().map(|s| s.as_str())
+}
+
+// For the integer opcodes: dm, acd, ce and so on.
+pub fn epd_number(epd: &Epd, opcode: &str) -> Option<i64> {
+    epd_string(epd, opcode)?.parse().ok()
+}
+
+// The moves of bm or am; empty if the opcode is absent.
+pub fn epd_moves(epd: &Epd, opcode: &str) -> Vec<PackedMove> {
+    let pos = &epd.position;
+    epd_operands(epd, opcode).unwrap_or(&[]).iter()
+        .map(|s| pack_move(pos, &parse_epd_move(pos, s).unwrap()))
+        .collect()
+}
+
+// The predicted variation, as moves played one after the other.
+pub fn epd_pv(epd: &Epd) -> Vec<PackedMove> {
+    let mut cur = copy_position(&epd.position);
+    let mut result = Vec::new();
+    for s in epd_operands(epd, "pv").unwrap_or(&[]) {
+        let mov = parse_epd_move(&cur, s).unwrap();
+        result.push(pack_move(&cur, &mov));
+        make_move(&mut cur, &mov);
+    }
+    result
+}
+
+// The points STS awards per move, written in c0 as "Bxe3=10, Nd1=3".
+// Empty when c0 is absent or in another format.
+pub fn epd_move_points(epd: &Epd) -> Vec<(PackedMove, u32)> {
+    let Some(c0) = epd_string(epd, "c0") else {
+        return Vec::new();
+    };
+    let mut result = Vec::new();
+    for item in c0.split(',') {
+        let Some((san, points)) = item.split_once('=') else {
+            return Vec::new();
+        };
+        let mov = parse_epd_move(&epd.position, san.trim());
+        let points = points.trim().parse::<u32>();
+        match (mov, points) {
+            (Ok(mov), Ok(points)) => result.push((pack_move(&epd.position, &mov), points)),
+            _ => { return Vec::new(); },
+        }
+    }
+    result
+}
+
+pub struct EpdTestOutcome {
+    pub result: SearchResult,
+    pub passed: bool,
+    pub points: u32,
+    pub max_points: u32,
+}
+
+// Searches the position of epd and checks the move against bm and am, or
+// the score against dm. Positions from STS also score points from c0.
+// None if the record has nothing to check against.
+pub fn run_epd_test(
+    s: &mut Searcher, epd: &Epd, limits: &SearchLimits
+) -> Option<EpdTestOutcome> {
+    let best_moves = epd_moves(epd, "bm");
+    let avoid_moves = epd_moves(epd, "am");
+    let mate = epd_number(epd, "dm");
+    let move_points = epd_move_points(epd);
+    if best_moves.is_empty() && avoid_moves.is_empty() && mate.is_none() && move_points.is_empty() {
+        return None;
+    }
+    let mut limits = limits.clone();
+    if let Some(n) = mate {
+        limits.mate = Some(n as u32);
+    }
+    searcher_clear(s);
+    let result = search(s, &epd.position, &[], &limits, &mut |_| {});
+    let best = result.best_move;
+    let passed = match mate {
+        Some(n) => result.score >= MATE_BOUND && mate_in_moves(result.score) as i64 <= n,
+        None => (best_moves.is_empty() || best_moves.contains(&best))
+            && !avoid_moves.contains(&best),
+    };
+    let (points, max_points) = if move_points.is_empty() {
+        (passed as u32, 1)
+    } else {
+        (
+            move_points.iter().filter(|(pm, _)| *pm == best).map(|(_, p)| *p).sum(),
+            move_points.iter().map(|(_, p)| *p).max().unwrap(),
+        )
+    };
+    Some(EpdTestOutcome{result, passed, points, max_points})
+}
+
+// Runs every record of a suite and prints a line per position and a
+// summary. Malformed records are reported and skipped.
+pub fn run_test_suite<R: BufRead>(reader: R, limits: &SearchLimits, hash_megabytes: usize) {
+    let mut s = new_searcher(hash_megabytes);
+    let mut n_tests = 0;
+    let mut n_passed = 0;
+    let mut points = 0;
+    let mut max_points = 0;
+    let start = std::time::Instant::now();
+    for (i, line) in reader.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("line {}: read error: {}", i + 1, e);
+                break;
+            },
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let epd = match parse_epd(&line) {
+            Ok(epd) => epd,
+            Err(e) => {
+                eprintln!("line {}: {}", i + 1, e);
+                continue;
+            },
+        };
+        let Some(outcome) = run_epd_test(&mut s, &epd, limits) else {
+            eprintln!("line {}: nothing to test", i + 1);
+            continue;
+        };
+        n_tests += 1;
+        if outcome.passed {
+            n_passed += 1;
+        }
+        points += outcome.points;
+        max_points += outcome.max_points;
+        let pos = &epd.position;
+        let best = if outcome.result.best_move == NULL_PACKED_MOVE {
+            String::from("-")
+        } else {
+            move_to_string(&unpack_move(pos, outcome.result.best_move), pos)
+        };
+        let mut expected: Vec<String> = ["bm", "am", "dm"].iter()
+            .filter_map(|&opcode| {
+                epd_operands(&epd, opcode).map(|ops| format!("{} {}", opcode, ops.join(" ")))
+            })
+            .collect();
+        if outcome.max_points > 1 {
+            expected.push(format!("{}/{} points", outcome.points, outcome.max_points));
+        }
+        println!("{}: {} {} {} depth {} ({})",
+            epd_string(&epd, "id").map(String::from).unwrap_or(format!("line {}", i + 1)),
+            if outcome.passed { "PASS" } else { "FAIL" },
+            best,
+            score_to_uci_string(outcome.result.score),
+            outcome.result.depth,
+            expected.join("; "));
+    }
+    println!("Passed {}/{}, points {}/{} in {:.1}s",
+        n_passed, n_tests, points, max_points, start.elapsed().as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses a few records, good and bad, and runs a small suite.
+    #[test]
+    fn epd() {
+        let epd = parse_epd(concat!(
+            "2rr3k/pp3pp1/1nnqbN1p/3pN3/2pP4/2P3Q1/PPB4P/R4RK1 w - - ",
+            "bm Qg6; id \"WAC.001\"; c0 \"Qg6=10, Nxf7+=2\"; acd 12; ce +300; ",
+            "pv Qg6 fxg6 Bxg6; hmvc 3; fmvn 21")).unwrap();
+        assert_eq!(epd_string(&epd, "id"), Some("WAC.001"));
+        assert_eq!(epd_number(&epd, "acd"), Some(12));
+        assert_eq!(epd_number(&epd, "ce"), Some(300));
+        assert_eq!(epd_operands(&epd, "nope"), None);
+        assert_eq!((epd.position.halfmoves, epd.position.fullmoves), (3, 21));
+        let pos = &epd.position;
+        assert_eq!(epd_moves(&epd, "bm").iter().map(|&pm| packed_move_to_uci_string(pm)).collect::<Vec<_>>(), ["g3g6"]);
+        assert_eq!(epd_pv(&epd).len(), 3);
+        let points: Vec<(String, u32)> = epd_move_points(&epd).iter()
+            .map(|&(pm, p)| (move_to_string(&unpack_move(pos, pm), pos), p))
+            .collect();
+        assert_eq!(points, [(String::from("Qg6"), 10), (String::from("Nxf7+"), 2)]);
+        // A semicolon inside a string and a missing final semicolon.
+        let epd = parse_epd("4k3/8/8/8/8/8/8/4K3 w - - c1 \"a;b\"; am Kd1").unwrap();
+        assert_eq!(epd_string(&epd, "c1"), Some("a;b"));
+        assert_eq!(epd_moves(&epd, "am").len(), 1);
+        let errors = [
+            ("4k3/8/8/8/8/8/8/4K3 w - -; bm Kd1;", 24),
+            ("4k3/8/8/8/8/8/8/4K3 w - - bm Ke3;", 26),
+            ("4k3/8/8/8/8/8/8/4K3 w - - dm x;", 26),
+            ("4k3/8/8/8/8/8/8/4K3 w - - id \"open;", 29),
+        ];
+        for (line, offset) in errors {
+            match parse_epd(line) {
+                Err(e) => assert_eq!(e.offset, offset, "{}: {}", line, e),
+                Ok(_) => panic!("Unexpected! {}", line),
+            }
+        }
+        let suite = concat!(
+            "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - dm 1; id \"back rank\";\n",
+            "r3k3/8/8/1N6/8/8/4P3/4K3 w - - bm Nc7+; id \"fork\";\n",
+            "r3k3/8/8/1N6/8/8/4P3/4K3 w - - am Nc7+; id \"avoid\";\n");
+        let mut s = new_searcher(1);
+        let mut limits = default_search_limits();
+        limits.depth = Some(4);
+        let passed: Vec<bool> = suite.lines()
+            .map(|line| run_epd_test(&mut s, &parse_epd(line).unwrap(), &limits).unwrap().passed)
+            .collect();
+        assert_eq!(passed, [true, true, false]);
+    }
+}
@@ -0,0 +1,260 @@
+// Static evaluation in centipawns: material and piece-square tables,
+// blended between middlegame and endgame values by the material left on
+// the board, plus a few pawn structure terms. Endings the endgame module
+// knows are evaluated or scaled there.
+
+use crate::*;
+
+pub type Score = i32;
+
+// Indexed by piece_index: pawn, rook, knight, bishop, queen, king.
+const MATERIAL_MG: [Score; 6] = [82, 477, 337, 365, 1025, 0];
+const MATERIAL_EG: [Score; 6] = [94, 512, 281, 297, 936, 0];
+
+// Rough values for move ordering and material counting.
+pub const PIECE_VALUES: [Score; 6] = [100, 500, 300, 300, 900, 20000];
+
+// Contribution of each piece to the game phase; 24 with all pieces on the
+// board, 0 with only kings and pawns.
+const PHASE_WEIGHTS: [i32; 6] = [0, 2, 1, 1, 4, 0];
+const MAX_PHASE: i32 = 24;
+
+const BISHOP_PAIR: Score = 30;
+const DOUBLED_PAWN: Score = -12;
+const ISOLATED_PAWN: Score = -10;
+// Indexed by the number of ranks the pawn has advanced.
+const PASSED_PAWN_MG: [Score; 8] = [0, 5, 10, 15, 25, 40, 60, 0];
+const PASSED_PAWN_EG: [Score; 8] = [0, 10, 15, 25, 45, 75, 120, 0];
+const TEMPO: Score = 10;
+
+// The tables read like a board diagram from white's side: a8 first, h1
+// last. Black uses them mirrored.
+const PAWN_TABLE: [Score; 64] = [
+     0,   0,   0,   0,   0,   0,   0,   0,
+    50,  50,  50,  50,  50,  50,  50,  50,
+    10,  10,  20,  30,  30,  20,  10,  10,
+     5,   5,  10,  25,  25,  10,   5,   5,
+     0,   0,   0,  20,  20,   0,   0,   0,
+     5,  -5, -10,   0,   0, -10,  -5,   5,
+     5,  10,  10, -20, -20,  10,  10,   5,
+     0,   0,   0,   0,   0,   0,   0,   0,
+];
+
+const KNIGHT_TABLE: [Score; 64] = [
+   -50, -40, -30, -30, -30, -30, -40, -50,
+   -40, -20,   0,   0,   0,   0, -20, -40,
+   -30,   0,  10,  15,  15,  10,   0, -30,
+   -30,   5,  15,  20,  20,  15,   5, -30,
+   -30,   0,  15,  20,  20,  15,   0, -30,
+   -30,   5,  10,  15,  15,  10,   5, -30,
+   -40, -20,   0,   5,   5,   0, -20, -40,
+   -50, -40, -30, -30, -30, -30, -40, -50,
+];
+
+const BISHOP_TABLE: [Score; 64] = [
+   -20, -10, -10, -10, -10, -10, -10, -20,
+   -10,   0,   0,   0,   0,   0,   0, -10,
+   -10,   0,   5,  10,  10,   5,   0, -10,
+   -10,   5,   5,  10,  10,   5,   5, -10,
+   -10,   0,  10,  10,  10,  10,   0, -10,
+   -10,  10,  10,  10,  10,  10,  10, -10,
+   -10,   5,   0,   0,   0,   0,   5, -10,
+   -20, -10, -10, -10, -10, -10, -10, -20,
+];
+
+const ROOK_TABLE: [Score; 64] = [
+     0,   0,   0,   0,   0,   0,   0,   0,
+     5,  10,  10,  10,  10,  10,  10,   5,
+    -5,   0,   0,   0,   0,   0,   0,  -5,
+    -5,   0,   0,   0,   0,   0,   0,  -5,
+    -5,   0,   0,   0,   0,   0,   0,  -5,
+    -5,   0,   0,   0,   0,   0,   0,  -5,
+    -5,   0,   0,   0,   0,   0,   0,  -5,
+     0,   0,   0,   5,   5,   0,   0,   0,
+];
+
+const QUEEN_TABLE: [Score; 64] = [
+   -20, -10, -10,  -5,  -5, -10, -10, -20,
+   -10,   0,   0,   0,   0,   0,   0, -10,
+   -10,   0,   5,   5,   5,   5,   0, -10,
+    -5,   0,   5,   5,   5,   5,   0,  -5,
+     0,   0,   5,   5,   5,   5,   0,  -5,
+   -10,   5,   5,   5,   5,   5,   0, -10,
+   -10,   0,   5,   0,   0,   0,   0, -10,
+   -20, -10, -10,  -5,  -5, -10, -10, -20,
+];
+
+const KING_TABLE_MG: [Score; 64] = [
+   -30, -40, -40, -50, -50, -40, -40, -30,
+   -30, -40, -40, -50, -50, -40, -40, -30,
+   -30, -40, -40, -50, -50, -40, -40, -30,
+   -30, -40, -40, -50, -50, -40, -40, -30,
+   -20, -30, -30, -40, -40, -30, -30, -20,
+   -10, -20, -20, -20, -20, -20, -20, -10,
+    20,  20,   0,   0,   0,   0,  20,  20,
+    20,  30,  10,   0,   0,  10,  30,  20,
+];
+
+const KING_TABLE_EG: [Score; 64] = [
+   -50, -40, -30, -20, -20, -30, -40, -50,
+   -30, -20, -10,   0,   0, -10, -20, -30,
+   -30, -10,  20,  30,  30,  20, -10, -30,
+   -30, -10,  30,  40,  40,  30, -10, -30,
+   -30, -10,  30,  40,  40,  30, -10, -30,
+   -30, -10,  20,  30,  30,  20, -10, -30,
+   -30, -30,   0,   0,   0,   0, -30, -30,
+   -50, -30, -30, -30, -30, -30, -30, -50,
+];
+
+const FILE_A_BB: Bitboard = 0x0101010101010101;
+
+pub fn file_bb(f: File) -> Bitboard {
+    FILE_A_BB << f
+}
+
+fn adjacent_files_bb(f: File) -> Bitboard {
+    let mut result = 0;
+    if f > 0 {
+        result |= file_bb(f - 1);
+    }
+    if f < 7 {
+        result |= file_bb(f + 1);
+    }
+    result
+}
+
+// The squares in front of sq, as seen by color, on its own and the
+// adjacent files. No enemy pawn there means a pawn on sq is passed.
+pub fn passed_pawn_span(color: Color, sq: Sq) -> Bitboard {
+    let fr = sq_to_filerank(sq);
+    let files = file_bb(fr.f) | adjacent_files_bb(fr.f);
+    let ranks_ahead = if color == COLOR_WHITE {
+        if fr.r == 7 { 0 } else { !0u64 << (8 * (fr.r + 1)) }
+    } else if fr.r == 0 {
+        0
+    } else {
+        !0u64 >> (8 * (8 - fr.r))
+    };
+    files & ranks_ahead
+}
+
+// Table index for a piece of the given color on sq.
+fn table_index(color: Color, sq: Sq) -> usize {
+    if color == COLOR_WHITE {
+        (sq ^ 56) as usize
+    } else {
+        sq as usize
+    }
+}
+
+fn pawn_structure(pos: &Position, color: Color) -> (Score, Score) {
+    let pawns = pieces_bb(pos, P_BASE * color);
+    let their_pawns = pieces_bb(pos, -P_BASE * color);
+    let mut mg = 0;
+    let mut eg = 0;
+    for f in 0 .. 8 {
+        let n = bb_popcount(pawns & file_bb(f)) as Score;
+        if n > 1 {
+            mg += DOUBLED_PAWN * (n - 1);
+            eg += DOUBLED_PAWN * (n - 1);
+        }
+        if n > 0 && pawns & adjacent_files_bb(f) == 0 {
+            mg += ISOLATED_PAWN * n;
+            eg += ISOLATED_PAWN * n;
+        }
+    }
+    for_each_sq_in_bb(pawns, |sq| {
+        if their_pawns & passed_pawn_span(color, sq) == 0 {
+            let r = sq_to_filerank(sq).r;
+            let advanced = if color == COLOR_WHITE { r } else { 7 - r } as usize;
+            mg += PASSED_PAWN_MG[advanced];
+            eg += PASSED_PAWN_EG[advanced];
+        }
+    });
+    (mg, eg)
+}
+
+// 0 (bare kings and pawns) to MAX_PHASE (all pieces on the board).
+pub fn game_phase(pos: &Position) -> i32 {
+    let mut phase = 0;
+    for (i, &weight) in PHASE_WEIGHTS.iter().enumerate() {
+        phase += weight * bb_popcount(pos.piece_bb[i]) as i32;
+    }
+    phase.min(MAX_PHASE)
+}
+
+// The evaluation from white's point of view.
+pub fn evaluate_white(pos: &Position) -> Score {
+    if let Some(score) = endgame_evaluate_white(pos) {
+        return score;
+    }
+    let mut mg = 0;
+    let mut eg = 0;
+    for color in [COLOR_WHITE, COLOR_BLACK] {
+        let mut side_mg = 0;
+        let mut side_eg = 0;
+        for pb in [P_BASE, R_BASE, N_BASE, B_BASE, Q_BASE, K_BASE] {
+            let i = piece_index(pb);
+            for_each_sq_in_bb(pieces_bb(pos, pb * color), |sq| {
+                let t = table_index(color, sq);
+                side_mg += MATERIAL_MG[i];
+                side_eg += MATERIAL_EG[i];
+                let (table_mg, table_eg) = match pb {
+                    P_BASE => (PAWN_TABLE[t], PAWN_TABLE[t]),
+                    R_BASE => (ROOK_TABLE[t], ROOK_TABLE[t]),
+                    N_BASE => (KNIGHT_TABLE[t], KNIGHT_TABLE[t]),
+                    B_BASE => (BISHOP_TABLE[t], BISHOP_TABLE[t]),
+                    Q_BASE => (QUEEN_TABLE[t], QUEEN_TABLE[t]),
+                    _ => (KING_TABLE_MG[t], KING_TABLE_EG[t]),
+                };
+                side_mg += table_mg;
+                side_eg += table_eg;
+            });
+        }
+        if bb_popcount(pieces_bb(pos, B_BASE * color)) >= 2 {
+            side_mg += BISHOP_PAIR;
+            side_eg += BISHOP_PAIR;
+        }
+        let (pawns_mg, pawns_eg) = pawn_structure(pos, color);
+        mg += (side_mg + pawns_mg) * color as Score;
+        eg += (side_eg + pawns_eg) * color as Score;
+    }
+    let phase = game_phase(pos);
+    let score = (mg * phase + eg * (MAX_PHASE - phase)) / MAX_PHASE;
+    let strong = if score > 0 { COLOR_WHITE } else { COLOR_BLACK };
+    score * endgame_scale_factor(pos, strong) / SCALE_NORMAL
+}
+
+// The evaluation from the point of view of the side to move, which is what
+// the search works with.
+pub fn evaluate(pos: &Position) -> Score {
+    evaluate_white(pos) * pos.active_color as Score + TEMPO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The evaluation must not depend on which side is white: mirroring the
+    // board must not change it.
+    #[test]
+    fn evaluation() {
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "4k3/8/8/3P4/8/8/2p5/4K3 b - - 0 1",
+        ];
+        for fen in fens {
+            let pos = decode_fen(String::from(fen));
+            let mut mirrored = empty_position();
+            for_each_sq_in_bb(occupied_bb(&pos), |sq| {
+                set_piece_at_sq(&mut mirrored, sq ^ 56, -piece_at_sq(&pos, sq));
+            });
+            mirrored.active_color = -pos.active_color;
+            assert_eq!(evaluate(&pos), evaluate(&mirrored), "{}", fen);
+        }
+        let start = decode_fen(String::from(fens[0]));
+        assert_eq!(evaluate_white(&start), 0);
+    }
+}
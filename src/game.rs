@@ -0,0 +1,419 @@
+// A game as a tree of moves: the starting position, the line that was
+// played and any variations branching off it, plus a cursor that can be
+// moved to any node. The position at the cursor is kept up to date.
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GameTermination {
+    Checkmate,
+    Stalemate,
+    InsufficientMaterial,
+    FiftyMoveRule,
+    ThreefoldRepetition,
+    Resignation,
+    TimeForfeit,
+    Agreement,
+}
+
+pub struct GameNode {
+    // None for the root.
+    pub parent: Option<usize>,
+    // The move leading to this node, NULL_PACKED_MOVE for the root.
+    pub mov: PackedMove,
+    // The first child continues the main line; the others are variations.
+    pub children: Vec<usize>,
+    // repetition_hash of the position at this node.
+    pub hash: u64,
+    // Halfmove clock of the position at this node. Positions before the
+    // clock was last reset cannot repeat.
+    pub halfmoves: i32,
+    pub nags: Vec<u8>,
+    // Comments after the move; for the root, before the first move.
+    pub comments: Vec<String>,
+}
+
+pub struct Game {
+    pub tags: Vec<(String, String)>,
+    pub start: Position,
+    // Indexed by node id. Node 0 is the root. Removed nodes stay in place
+    // but are unlinked from their parent.
+    pub nodes: Vec<GameNode>,
+    pub current: usize,
+    // The position at nodes[current].
+    pub position: Position,
+    // "1-0", "0-1", "1/2-1/2" or "*".
+    pub result: String,
+    pub termination: Option<GameTermination>,
+}
+
+// The hash used to recognise repeated positions. An en passant square
+// only makes positions different when a capture on it is possible.
+pub fn repetition_hash(pos: &Position) -> u64 {
+    match pos.en_passant {
+        Some(ep) => {
+            let pawns = pieces_bb(pos, P_BASE * pos.active_color);
+            let capturable = pawn_attacks(-pos.active_color, ep) & pawns != 0;
+            if capturable {
+                pos.hash
+            } else {
+                pos.hash ^ zobrist_en_passant_key(pos.en_passant)
+            }
+        },
+        None => pos.hash,
+    }
+}
+
+fn game_node(parent: Option<usize>, mov: PackedMove, pos: &Position) -> GameNode {
+    GameNode{
+        parent,
+        mov,
+        children: Vec::new(),
+        hash: repetition_hash(pos),
+        halfmoves: pos.halfmoves,
+        nags: Vec::new(),
+        comments: Vec::new(),
+    }
+}
+
+pub fn new_game(start: &Position) -> Game {
+    Game{
+        tags: Vec::new(),
+        start: copy_position(start),
+        nodes: vec![game_node(None, NULL_PACKED_MOVE, start)],
+        current: 0,
+        position: copy_position(start),
+        result: String::from("*"),
+        termination: None,
+    }
+}
+
+// The node ids from the root to node, both included.
+pub fn game_path_to(game: &Game, node: usize) -> Vec<usize> {
+    let mut path = vec![node];
+    let mut cur = node;
+    while let Some(parent) = game.nodes[cur].parent {
+        path.push(parent);
+        cur = parent;
+    }
+    path.reverse();
+    path
+}
+
+// Plays mov, which must be legal at the current node, and moves the cursor
+// to the resulting node. If the move was already recorded there its node
+// is reused, otherwise it is added as a new variation (or as the main line
+// if the node had no continuation). Returns the node id.
+pub fn game_push(game: &mut Game, mov: &Move) -> usize {
+    let pm = pack_move(&game.position, mov);
+    make_move(&mut game.position, mov);
+    let existing = game.nodes[game.current].children.iter()
+        .find(|&&child| game.nodes[child].mov == pm)
+        .copied();
+    let node = match existing {
+        Some(node) => node,
+        None => {
+            let node = game.nodes.len();
+            game.nodes.push(game_node(Some(game.current), pm, &game.position));
+            game.nodes[game.current].children.push(node);
+            node
+        },
+    };
+    game.current = node;
+    node
+}
+
+// Moves the cursor back one move, keeping the node so that game_redo can
+// return to it. Returns false at the root. The result stands, as the
+// moves that led to it are still there.
+pub fn game_undo(game: &mut Game) -> bool {
+    match game.nodes[game.current].parent {
+        Some(parent) => {
+            game_go_to(game, parent);
+            true
+        },
+        None => false,
+    }
+}
+
+// Moves the cursor forward along the main continuation. Returns false if
+// there is none.
+pub fn game_redo(game: &mut Game) -> bool {
+    match game.nodes[game.current].children.first() {
+        Some(&child) => {
+            let mov = unpack_move(&game.position, game.nodes[child].mov);
+            make_move(&mut game.position, &mov);
+            game.current = child;
+            true
+        },
+        None => false,
+    }
+}
+
+// Takes back the current move and deletes it, together with everything
+// that follows it. The game is unfinished again. Returns the move, or None
+// at the root.
+pub fn game_pop(game: &mut Game) -> Option<PackedMove> {
+    let node = game.current;
+    let parent = game.nodes[node].parent?;
+    game_go_to(game, parent);
+    game.nodes[parent].children.retain(|&child| child != node);
+    game.nodes[node].parent = None;
+    game.result = String::from("*");
+    game.termination = None;
+    Some(game.nodes[node].mov)
+}
+
+// Moves the cursor to any node, replaying the moves from the start.
+pub fn game_go_to(game: &mut Game, node: usize) {
+    let path = game_path_to(game, node);
+    let mut pos = copy_position(&game.start);
+    for &id in &path[1 ..] {
+        let mov = unpack_move(&pos, game.nodes[id].mov);
+        make_move(&mut pos, &mov);
+    }
+    game.position = pos;
+    game.current = node;
+}
+
+pub fn game_go_to_start(game: &mut Game) {
+    game_go_to(game, 0);
+}
+
+// Follows the main continuation from the cursor to its end.
+pub fn game_go_to_end(game: &mut Game) {
+    while game_redo(game) {}
+}
+
+// Makes the line through node the main line at every branch point above
+// it.
+pub fn game_promote_to_main_line(game: &mut Game, node: usize) {
+    let mut cur = node;
+    while let Some(parent) = game.nodes[cur].parent {
+        let children = &mut game.nodes[parent].children;
+        let i = children.iter().position(|&child| child == cur).unwrap();
+        children.remove(i);
+        children.insert(0, cur);
+        cur = parent;
+    }
+}
+
+// The node ids of the main line, root excluded.
+pub fn game_main_line(game: &Game) -> Vec<usize> {
+    let mut result = Vec::new();
+    let mut cur = 0;
+    while let Some(&child) = game.nodes[cur].children.first() {
+        result.push(child);
+        cur = child;
+    }
+    result
+}
+
+// The repetition hashes of the positions before the cursor, oldest
+// first, as the search takes them.
+pub fn game_history_hashes(game: &Game) -> Vec<u64> {
+    let path = game_path_to(game, game.current);
+    path[.. path.len() - 1].iter().map(|&id| game.nodes[id].hash).collect()
+}
+
+// How many times the position at the cursor has occurred on the way to
+// it, itself included.
+pub fn game_repetition_count(game: &Game) -> u32 {
+    let path = game_path_to(game, game.current);
+    let current = &game.nodes[game.current];
+    let mut count = 0;
+    for &id in path.iter().rev() {
+        let node = &game.nodes[id];
+        if node.hash == current.hash {
+            count += 1;
+        }
+        if node.halfmoves == 0 {
+            break;
+        }
+    }
+    count
+}
+
+pub fn game_is_threefold_repetition(game: &Game) -> bool {
+    game_repetition_count(game) >= 3
+}
+
+// Works out whether the position at the cursor ends the game, by rule or
+// by a draw that can be claimed, and records the result if so.
+pub fn game_update_result(game: &mut Game) -> Option<GameTermination> {
+    let pos = &game.position;
+    let no_moves = generate_legal_moves(pos).len == 0;
+    let termination = if no_moves && is_king_in_check(pos, false) {
+        Some(GameTermination::Checkmate)
+    } else if no_moves {
+        Some(GameTermination::Stalemate)
+    } else if is_insufficient_material(pos) {
+        Some(GameTermination::InsufficientMaterial)
+    } else if game_is_threefold_repetition(game) {
+        Some(GameTermination::ThreefoldRepetition)
+    } else if pos.halfmoves >= 100 {
+        Some(GameTermination::FiftyMoveRule)
+    } else {
+        None
+    };
+    if let Some(t) = termination {
+        let loser = game.position.active_color;
+        let result = if t != GameTermination::Checkmate {
+            "1/2-1/2"
+        } else if loser == COLOR_WHITE {
+            "0-1"
+        } else {
+            "1-0"
+        };
+        game_set_result(game, result, t);
+    }
+    termination
+}
+
+// For endings that do not follow from the position: resignation, time
+// forfeit or agreement.
+pub fn game_set_result(game: &mut Game, result: &str, termination: GameTermination) {
+    game.result = String::from(result);
+    game.termination = Some(termination);
+}
+
+fn add_pgn_line(game: &mut Game, line: &PgnLine) {
+    let branch_point = game.current;
+    game.nodes[branch_point].comments.extend(line.comments.iter().cloned());
+    for node in line.moves.iter() {
+        let before = game.current;
+        let mov = unpack_move(&game.position, node.mov);
+        let id = game_push(game, &mov);
+        game.nodes[id].nags = node.nags.clone();
+        game.nodes[id].comments = node.comments.clone();
+        for variation in node.variations.iter() {
+            game_go_to(game, before);
+            add_pgn_line(game, variation);
+            game_go_to(game, id);
+        }
+    }
+    game_go_to(game, branch_point);
+}
+
+pub fn game_from_pgn(pgn: &PgnGame) -> Game {
+    let mut game = new_game(&pgn.positions[0]);
+    game.tags = pgn.tags.clone();
+    game.result = pgn.result.clone();
+    add_pgn_line(&mut game, &pgn.line);
+    game
+}
+
+fn game_line_to_pgn(game: &Game, first: usize, pos: &Position) -> PgnLine {
+    // first is a child of the branch point; the line continues along main
+    // children from there.
+    let mut line = PgnLine{comments: Vec::new(), moves: Vec::new()};
+    let mut cur = copy_position(pos);
+    let mut id = Some(first);
+    while let Some(node_id) = id {
+        let node = &game.nodes[node_id];
+        let parent = node.parent.unwrap();
+        // Alternatives are attached to the main continuation only.
+        let siblings = &game.nodes[parent].children;
+        let variations = if siblings[0] == node_id {
+            siblings[1 ..].iter()
+                .map(|&sibling| game_line_to_pgn(game, sibling, &cur))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mov = unpack_move(&cur, node.mov);
+        line.moves.push(PgnNode{
+            san: move_to_string(&mov, &cur),
+            mov: node.mov,
+            nags: node.nags.clone(),
+            comments: node.comments.clone(),
+            variations,
+        });
+        make_move(&mut cur, &mov);
+        id = node.children.first().copied();
+    }
+    line
+}
+
+pub fn game_to_pgn(game: &Game) -> PgnGame {
+    let mut line = match game.nodes[0].children.first() {
+        Some(&first) => game_line_to_pgn(game, first, &game.start),
+        None => PgnLine{comments: Vec::new(), moves: Vec::new()},
+    };
+    line.comments = game.nodes[0].comments.clone();
+    let mut positions = vec![copy_position(&game.start)];
+    for &id in game_main_line(game).iter() {
+        let last = positions.last().unwrap();
+        positions.push(position_after_move(last, &unpack_move(last, game.nodes[id].mov)));
+    }
+    let mut tags: Vec<(String, String)> = game.tags.iter()
+        .filter(|(name, _)| name != "Result")
+        .cloned()
+        .collect();
+    tags.push((String::from("Result"), game.result.clone()));
+    PgnGame{
+        tags,
+        start_fen: encode_fen(&game.start),
+        line,
+        result: game.result.clone(),
+        positions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Exercises pushing, variations, navigation, repetition and results.
+    #[test]
+    fn game() {
+        let start = decode_fen(String::from(STARTING_FEN));
+        let mut game = new_game(&start);
+        let play = |game: &mut Game, san: &str| {
+            let mov = parse_san(&game.position, san).unwrap();
+            game_push(game, &mov)
+        };
+        for san in ["Nf3", "Nf6", "Ng1", "Ng8", "Nf3", "Nf6", "Ng1"] {
+            play(&mut game, san);
+            assert_eq!(game_update_result(&mut game), None);
+        }
+        assert_eq!(game_repetition_count(&game), 2);
+        play(&mut game, "Ng8");
+        assert_eq!(game_repetition_count(&game), 3);
+        assert_eq!(game_update_result(&mut game), Some(GameTermination::ThreefoldRepetition));
+        assert_eq!(game.result, "1/2-1/2");
+        // Back to move 1 and play a variation.
+        game_go_to(&mut game, 1);
+        assert!(game_undo(&mut game));
+        assert_eq!(game.current, 0);
+        let e4 = play(&mut game, "e4");
+        assert_eq!(game.nodes[0].children.len(), 2);
+        for san in ["e5", "Qh5", "Nc6", "Bc4", "Nf6", "Qxf7#"] {
+            play(&mut game, san);
+        }
+        assert_eq!(game_update_result(&mut game), Some(GameTermination::Checkmate));
+        assert_eq!(game.result, "1-0");
+        let mate = game.current;
+        assert_eq!(game_pop(&mut game), Some(parse_uci(&game.position, "h5f7").map(
+            |mov| pack_move(&game.position, &mov)).unwrap()));
+        assert_eq!(game.nodes[game.current].children.len(), 0);
+        assert_eq!(game.nodes[mate].parent, None);
+        assert_eq!((game.result.as_str(), game.termination), ("*", None));
+        assert_eq!(game_to_pgn(&game).result, "*");
+        game_promote_to_main_line(&mut game, e4);
+        assert_eq!(game_main_line(&game).len(), 6);
+        game_go_to_start(&mut game);
+        game_go_to_end(&mut game);
+        assert_eq!(encode_fen(&game.position),
+            "r1bqkb1r/pppp1ppp/2n2n2/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w KQkq - 4 4");
+        // Through PGN and back.
+        let pgn = game_to_pgn(&game);
+        assert_eq!(pgn.line.moves.len(), 6);
+        assert_eq!(pgn.line.moves[0].variations.len(), 1);
+        assert_eq!(pgn.line.moves[0].variations[0].moves.len(), 8);
+        let again = game_from_pgn(&pgn);
+        assert_eq!(write_pgn(&game_to_pgn(&again)), write_pgn(&pgn));
+        let mut game = new_game(&decode_fen(String::from("8/8/8/8/8/2k5/8/K1b5 w - - 0 1")));
+        assert_eq!(game_update_result(&mut game), Some(GameTermination::InsufficientMaterial));
+    }
+}
@@ -2,6 +2,39 @@
 #![allow(unused_variables)]
 
 use std::collections::HashMap;
+use std::io::BufRead;
+
+mod attacks;
+mod book_builder;
+mod endgame;
+mod epd;
+mod eval;
+mod game;
+mod pgn;
+mod polyglot;
+mod problem;
+mod search;
+mod syzygy;
+mod tablebase;
+mod timeman;
+mod uci;
+mod xboard;
+
+use attacks::*;
+use book_builder::*;
+use endgame::*;
+use epd::*;
+use eval::*;
+use game::*;
+use pgn::*;
+use polyglot::*;
+use problem::*;
+use search::*;
+use syzygy::*;
+use tablebase::*;
+use timeman::*;
+use uci::*;
+use xboard::*;
 
 type Sq = i8;
 type AlgSq = [String; 2];
@@ -12,6 +45,10 @@ type Rank = i8;
 type Color = i8;
 type Ply = u32;
 type Val = f64;
+type Bitboard = u64;
+// from (bits 0-5), to (bits 6-11) and one of the MOVE_FLAG_* values (bits
+// 12-15).
+type PackedMove = u16;
 
 const COLOR_WHITE: Color = 1;
 const COLOR_BLACK: Color = -1;
@@ -85,6 +122,79 @@ const KING_DIRECTIONS: [Direction; 8] = [
     DIR_UR, DIR_DR, DIR_DL, DIR_UL,
 ];
 
+// Indices into Position.castling.
+const CASTLING_WHITE_KINGSIDE: usize = 0;
+const CASTLING_WHITE_QUEENSIDE: usize = 1;
+const CASTLING_BLACK_KINGSIDE: usize = 2;
+const CASTLING_BLACK_QUEENSIDE: usize = 3;
+
+const PROMOTION_BASES: [Piece; 4] = [Q_BASE, R_BASE, B_BASE, N_BASE];
+
+const MOVE_FLAG_NORMAL: u16 = 0;
+const MOVE_FLAG_CASTLING: u16 = 1;
+const MOVE_FLAG_EN_PASSANT: u16 = 2;
+// Promotions are MOVE_FLAG_PROMOTION + index into PROMOTION_BASES.
+const MOVE_FLAG_PROMOTION: u16 = 4;
+
+// a1 to a1, never a real move.
+const NULL_PACKED_MOVE: PackedMove = 0;
+
+// Enough for any legal position (the known maximum is 218). parse_fen
+// rejects positions with more pseudo-legal moves for either side, so a
+// MoveList never overflows.
+const MAX_MOVES: usize = 256;
+
+struct ZobristKeys {
+    // Indexed by piece_index + 6 * color_index, as in zobrist_piece_key,
+    // and square.
+    pieces: [[u64; 64]; 12],
+    castling: [u64; 4],
+    en_passant_file: [u64; 8],
+    black_to_move: u64,
+}
+
+// xorshift64*, deterministic for a given seed. const so that the Zobrist
+// keys can be built at compile time.
+const fn random_next(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(2685821657736338717)
+}
+
+const fn init_zobrist_keys() -> ZobristKeys {
+    let mut state = 1070372;
+    let mut keys = ZobristKeys{
+        pieces: [[0; 64]; 12],
+        castling: [0; 4],
+        en_passant_file: [0; 8],
+        black_to_move: 0,
+    };
+    let mut i = 0;
+    while i < 12 {
+        let mut sq = 0;
+        while sq < 64 {
+            keys.pieces[i][sq] = random_next(&mut state);
+            sq += 1;
+        }
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 4 {
+        keys.castling[i] = random_next(&mut state);
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 8 {
+        keys.en_passant_file[i] = random_next(&mut state);
+        i += 1;
+    }
+    keys.black_to_move = random_next(&mut state);
+    keys
+}
+
+const ZOBRIST: ZobristKeys = init_zobrist_keys();
+
 fn char_to_piece(c: char) -> Direction {
     match c {
         'R' => R_WHITE,
@@ -130,11 +240,18 @@ struct MoveVal {
 
 struct Position {
     placement: [Piece; 64],
+    // One bitboard per piece base (indexed by piece_index) and one per
+    // color (indexed by color_index), kept in sync with placement.
+    piece_bb: [Bitboard; 6],
+    color_bb: [Bitboard; 2],
     active_color: Color,
     castling: [bool; 4],
     en_passant: Option<Sq>,
     halfmoves: i32,
     fullmoves: i32,
+    // Zobrist hash, maintained incrementally by set_piece_at_sq and
+    // make_move.
+    hash: u64,
     evaluation: Option<HashMap<Ply, Vec<MoveVal>>>,
     moves: Option<Vec<Move>>,
     is_king_in_check: Option<bool>,
@@ -145,11 +262,14 @@ struct Position {
 fn empty_position() -> Position {
     Position{
         placement: [EMPTY; 64],
+        piece_bb: [0; 6],
+        color_bb: [0; 2],
         active_color: COLOR_WHITE,
         castling: [false; 4],
         en_passant: None,
         halfmoves: 0,
         fullmoves: 0,
+        hash: 0,
         evaluation: None,
         moves: None,
         is_king_in_check: None,
@@ -167,9 +287,35 @@ struct Move {
     piece: Piece,
     from: Sq,
     to: Sq,
+    // The piece the pawn turns into, or EMPTY.
+    promotion: Piece,
     leads_to: Option<Position>,
 }
 
+// Fixed-capacity move list that lives on the stack, for the hot paths that
+// cannot afford a Vec<Move> per node.
+struct MoveList {
+    moves: [PackedMove; MAX_MOVES],
+    len: usize,
+}
+
+// Everything make_move overwrites that cannot be recomputed from the move,
+// so that unmake_move can restore the position exactly. The cached
+// expansion of the position is parked here as well.
+struct Undo {
+    captured: Piece,
+    castling: [bool; 4],
+    en_passant: Option<Sq>,
+    halfmoves: i32,
+    fullmoves: i32,
+    hash: u64,
+    evaluation: Option<HashMap<Ply, Vec<MoveVal>>>,
+    moves: Option<Vec<Move>>,
+    is_king_in_check: Option<bool>,
+    is_king_in_checkmate: Option<bool>,
+    is_king_in_stalemate: Option<bool>,
+}
+
 fn sq_to_filerank(sq: Sq) -> FileRank {
     FileRank{
         f: sq % 8,
@@ -190,9 +336,107 @@ fn piece_at_sq(pos: &Position, sq: Sq) -> Piece {
 }
 
 fn set_piece_at_sq(pos: &mut Position, sq: Sq, piece: Piece) {
+    let old_piece = pos.placement[sq as usize];
+    let bb = sq_bb(sq);
+    if old_piece != EMPTY {
+        pos.piece_bb[piece_index(old_piece)] &= !bb;
+        pos.color_bb[color_index(piece_color(old_piece))] &= !bb;
+        pos.hash ^= zobrist_piece_key(old_piece, sq);
+    }
+    if piece != EMPTY {
+        pos.piece_bb[piece_index(piece)] |= bb;
+        pos.color_bb[color_index(piece_color(piece))] |= bb;
+        pos.hash ^= zobrist_piece_key(piece, sq);
+    }
     pos.placement[sq as usize] = piece;
 }
 
+fn zobrist_piece_key(piece: Piece, sq: Sq) -> u64 {
+    let i = piece_index(piece) + 6 * color_index(piece_color(piece));
+    ZOBRIST.pieces[i][sq as usize]
+}
+
+fn zobrist_en_passant_key(en_passant: Option<Sq>) -> u64 {
+    match en_passant {
+        Some(sq) => ZOBRIST.en_passant_file[sq_to_filerank(sq).f as usize],
+        None => 0,
+    }
+}
+
+fn zobrist_castling_key(castling: &[bool; 4]) -> u64 {
+    let mut result = 0;
+    for (i, &right) in castling.iter().enumerate() {
+        if right {
+            result ^= ZOBRIST.castling[i];
+        }
+    }
+    result
+}
+
+// Computes the hash from scratch. Used after building a position by other
+// means than make_move.
+fn position_hash(pos: &Position) -> u64 {
+    let mut result = 0;
+    for_each_sq_in_bb(occupied_bb(pos), |sq| {
+        result ^= zobrist_piece_key(piece_at_sq(pos, sq), sq);
+    });
+    result ^= zobrist_castling_key(&pos.castling);
+    result ^= zobrist_en_passant_key(pos.en_passant);
+    if pos.active_color == COLOR_BLACK {
+        result ^= ZOBRIST.black_to_move;
+    }
+    result
+}
+
+fn sq_bb(sq: Sq) -> Bitboard {
+    1 << sq
+}
+
+fn bb_popcount(bb: Bitboard) -> u32 {
+    bb.count_ones()
+}
+
+fn bb_lsb(bb: Bitboard) -> Sq {
+    bb.trailing_zeros() as Sq
+}
+
+fn bb_pop_lsb(bb: &mut Bitboard) -> Sq {
+    let sq = bb_lsb(*bb);
+    *bb &= *bb - 1;
+    sq
+}
+
+fn for_each_sq_in_bb(mut bb: Bitboard, mut func: impl FnMut(Sq)) {
+    while bb != 0 {
+        func(bb_pop_lsb(&mut bb));
+    }
+}
+
+fn color_index(color: Color) -> usize {
+    match color {
+        COLOR_WHITE => 0,
+        COLOR_BLACK => 1,
+        _ => panic!("Unexpected!"),
+    }
+}
+
+fn piece_index(piece: Piece) -> usize {
+    (piece_base(piece) - 1) as usize
+}
+
+fn color_bb(pos: &Position, color: Color) -> Bitboard {
+    pos.color_bb[color_index(color)]
+}
+
+fn occupied_bb(pos: &Position) -> Bitboard {
+    pos.color_bb[0] | pos.color_bb[1]
+}
+
+fn pieces_bb(pos: &Position, piece: Piece) -> Bitboard {
+    pos.piece_bb[piece_index(piece)]
+        & pos.color_bb[color_index(piece_color(piece))]
+}
+
 fn algsq_to_sq(algsq: AlgSq) -> Sq {
     filerank_to_sq(&(FileRank{
         f: match algsq[0].as_str() {
@@ -261,6 +505,20 @@ fn sq_to_algstring(sq: Sq) -> String {
     sq_to_algsq(sq).join("")
 }
 
+// Like algsq_to_sq, but returns None instead of panicking.
+fn algstring_to_sq(s: &str) -> Option<Sq> {
+    let chars: Vec<char> = s.chars().collect();
+    if chars.len() != 2
+        || !('a' ..= 'h').contains(&chars[0])
+        || !('1' ..= '8').contains(&chars[1])
+    {
+        return None;
+    }
+    Some(fr_to_sq(
+        (chars[0] as u8 - b'a') as File,
+        (chars[1] as u8 - b'1') as Rank))
+}
+
 fn is_there_piece_at_sq(
     pos: &Position, sq: Sq
 ) -> bool {
@@ -301,113 +559,325 @@ fn toggled_color(color: Color) -> Color {
     }
 }
 
-fn decode_fen(fen_string: String) -> Position {
-    let mut p = empty_position();
-    let mut state = 0;
-    let mut f = 0;
-    let mut r = 7;
-    let mut en_passant = [String::from(""), String::from("")];
-    let mut en_passant_index = 0;
-    let mut halfmoves = String::from("");
-    let mut fullmoves = String::from("");
-    for c in fen_string.chars() {
-        if c == ' ' {
-            state += 1;
-        } else if state == 0 {
-            match c {
-                'r' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), R_BLACK);
-                    f += 1;
-                },
-                'n' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), N_BLACK);
-                    f += 1;
-                },
-                'b' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), B_BLACK);
-                    f += 1;
-                },
-                'q' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), Q_BLACK);
-                    f += 1;
-                },
-                'k' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), K_BLACK);
-                    f += 1;
-                },
-                'p' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), P_BLACK);
-                    f += 1;
-                },
-                'R' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), R_WHITE);
-                    f += 1;
-                },
-                'N' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), N_WHITE);
-                    f += 1;
-                },
-                'B' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), B_WHITE);
-                    f += 1;
-                },
-                'Q' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), Q_WHITE);
-                    f += 1;
-                },
-                'K' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), K_WHITE);
-                    f += 1;
-                },
-                'P' => {
-                    set_piece_at_sq(&mut p, fr_to_sq(f, r), P_WHITE);
-                    f += 1;
-                },
-                '1' => { f += 1 },
-                '2' => { f += 2 },
-                '3' => { f += 3 },
-                '4' => { f += 4 },
-                '5' => { f += 5 },
-                '6' => { f += 6 },
-                '7' => { f += 7 },
-                '8' => { f += 8 },
-                '/' => { r -= 1; f = 0 },
-                _ => panic!("Unexpected!"),
-            }
-        } else if state == 1 {
-            match c {
-                'w' => { p.active_color = COLOR_WHITE },
-                'b' => { p.active_color = COLOR_BLACK },
-                _ => panic!("Unexpected!"),
-            }
-        } else if state == 2 {
-            match c {
-                'K' => { p.castling[0] = true },
-                'Q' => { p.castling[1] = true },
-                'k' => { p.castling[2] = true },
-                'q' => { p.castling[3] = true },
-                '-' => {},
-                _ => panic!("Unexpected!"),
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FenField {
+    Placement,
+    ActiveColor,
+    Castling,
+    EnPassant,
+    Halfmoves,
+    Fullmoves,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FenErrorKind {
+    BadPieceLetter(char),
+    // More than eight squares in a rank.
+    RankOverflow,
+    // Fewer than eight squares in a rank.
+    RankUnderflow,
+    TooManyRanks,
+    TooFewRanks,
+    BadActiveColor,
+    BadCastling(char),
+    BadEnPassant,
+    NonNumericClock,
+    MissingField,
+    TooManyFields,
+    // A side has more pseudo-legal moves than a MoveList holds, which
+    // takes far more material than a game can have.
+    TooManyMoves,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FenError {
+    kind: FenErrorKind,
+    field: FenField,
+    // Character offset into the FEN string.
+    offset: usize,
+}
+
+impl std::fmt::Display for FenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let what = match &self.kind {
+            FenErrorKind::BadPieceLetter(c) => format!("bad piece letter '{}'", c),
+            FenErrorKind::RankOverflow => String::from("more than 8 squares in rank"),
+            FenErrorKind::RankUnderflow => String::from("fewer than 8 squares in rank"),
+            FenErrorKind::TooManyRanks => String::from("more than 8 ranks"),
+            FenErrorKind::TooFewRanks => String::from("fewer than 8 ranks"),
+            FenErrorKind::BadActiveColor => String::from("side to move is not 'w' or 'b'"),
+            FenErrorKind::BadCastling(c) => format!("bad castling character '{}'", c),
+            FenErrorKind::BadEnPassant => String::from("bad en passant square"),
+            FenErrorKind::NonNumericClock => String::from("clock is not a number"),
+            FenErrorKind::MissingField => String::from("missing field"),
+            FenErrorKind::TooManyFields => String::from("unexpected extra field"),
+            FenErrorKind::TooManyMoves => format!("more than {} moves for a side", MAX_MOVES),
+        };
+        write!(f, "{} in {:?} field at offset {}", what, self.field, self.offset)
+    }
+}
+
+impl std::error::Error for FenError {}
+
+fn fen_error(kind: FenErrorKind, field: FenField, offset: usize) -> FenError {
+    FenError{kind, field, offset}
+}
+
+// Splits s on runs of whitespace, keeping the character offset of each
+// field.
+fn split_fields_with_offsets(s: &str) -> Vec<(usize, &str)> {
+    let mut result = Vec::new();
+    let mut start: Option<(usize, usize)> = None;
+    for (i, (byte_i, c)) in s.char_indices().enumerate() {
+        if c.is_whitespace() {
+            if let Some((offset, byte_start)) = start {
+                result.push((offset, &s[byte_start .. byte_i]));
+                start = None;
             }
-        } else if state == 3 {
-            match c {
-                _ => {
-                    en_passant[en_passant_index] = String::from(c);
-                    en_passant_index += 1;
-                },
+        } else if start.is_none() {
+            start = Some((i, byte_i));
+        }
+    }
+    if let Some((offset, byte_start)) = start {
+        result.push((offset, &s[byte_start ..]));
+    }
+    result
+}
+
+fn parse_fen_placement(
+    p: &mut Position, offset: usize, field: &str
+) -> Result<(), FenError> {
+    let err = |kind, i| Err(fen_error(kind, FenField::Placement, offset + i));
+    let mut f: File = 0;
+    let mut r: Rank = 7;
+    let mut n_chars = 0;
+    for (i, c) in field.chars().enumerate() {
+        n_chars = i + 1;
+        match c {
+            '/' => {
+                if f < 8 {
+                    return err(FenErrorKind::RankUnderflow, i);
+                }
+                if r == 0 {
+                    return err(FenErrorKind::TooManyRanks, i);
+                }
+                r -= 1;
+                f = 0;
+            },
+            '1' ..= '8' => {
+                f += c.to_digit(10).unwrap() as File;
+                if f > 8 {
+                    return err(FenErrorKind::RankOverflow, i);
+                }
+            },
+            'r' | 'n' | 'b' | 'q' | 'k' | 'p' | 'R' | 'N' | 'B' | 'Q' | 'K' | 'P' => {
+                if f >= 8 {
+                    return err(FenErrorKind::RankOverflow, i);
+                }
+                set_piece_at_sq(p, fr_to_sq(f, r), char_to_piece(c));
+                f += 1;
+            },
+            _ => { return err(FenErrorKind::BadPieceLetter(c), i); },
+        }
+    }
+    if f < 8 {
+        return err(FenErrorKind::RankUnderflow, n_chars);
+    }
+    if r > 0 {
+        return err(FenErrorKind::TooFewRanks, n_chars);
+    }
+    Ok(())
+}
+
+fn parse_fen_active_color(
+    p: &mut Position, offset: usize, field: &str
+) -> Result<(), FenError> {
+    match field {
+        "w" => { p.active_color = COLOR_WHITE; },
+        "b" => { p.active_color = COLOR_BLACK; },
+        _ => {
+            return Err(fen_error(
+                FenErrorKind::BadActiveColor, FenField::ActiveColor, offset));
+        },
+    }
+    Ok(())
+}
+
+fn parse_fen_castling(
+    p: &mut Position, offset: usize, field: &str
+) -> Result<(), FenError> {
+    if field == "-" {
+        return Ok(());
+    }
+    for (i, c) in field.chars().enumerate() {
+        let right = match c {
+            'K' => CASTLING_WHITE_KINGSIDE,
+            'Q' => CASTLING_WHITE_QUEENSIDE,
+            'k' => CASTLING_BLACK_KINGSIDE,
+            'q' => CASTLING_BLACK_QUEENSIDE,
+            _ => usize::MAX,
+        };
+        if right == usize::MAX || p.castling[right] {
+            return Err(fen_error(
+                FenErrorKind::BadCastling(c), FenField::Castling, offset + i));
+        }
+        p.castling[right] = true;
+    }
+    Ok(())
+}
+
+fn parse_fen_en_passant(
+    p: &mut Position, offset: usize, field: &str
+) -> Result<(), FenError> {
+    if field == "-" {
+        return Ok(());
+    }
+    let chars: Vec<char> = field.chars().collect();
+    let valid = chars.len() == 2
+        && ('a' ..= 'h').contains(&chars[0])
+        && (chars[1] == '3' || chars[1] == '6');
+    if !valid {
+        return Err(fen_error(
+            FenErrorKind::BadEnPassant, FenField::EnPassant, offset));
+    }
+    p.en_passant = Some(algsq_to_sq(
+        [String::from(chars[0]), String::from(chars[1])]));
+    Ok(())
+}
+
+fn parse_fen_clock(
+    field_kind: FenField, offset: usize, field: &str
+) -> Result<i32, FenError> {
+    match field.parse::<u16>() {
+        Ok(n) if field.chars().all(|c| c.is_ascii_digit()) => Ok(n as i32),
+        _ => Err(fen_error(FenErrorKind::NonNumericClock, field_kind, offset)),
+    }
+}
+
+// Parses the four position fields shared by FEN and EPD: placement, side
+// to move, castling and en passant. end is the character offset reported
+// when a field is missing.
+fn parse_fen_position_fields(
+    fields: &[(usize, &str)], end: usize
+) -> Result<Position, FenError> {
+    let mut p = empty_position();
+    let kinds = [
+        FenField::Placement, FenField::ActiveColor,
+        FenField::Castling, FenField::EnPassant,
+    ];
+    for (i, &kind) in kinds.iter().enumerate() {
+        let Some(&(offset, field)) = fields.get(i) else {
+            return Err(fen_error(FenErrorKind::MissingField, kind, end));
+        };
+        match kind {
+            FenField::Placement => parse_fen_placement(&mut p, offset, field)?,
+            FenField::ActiveColor => parse_fen_active_color(&mut p, offset, field)?,
+            FenField::Castling => parse_fen_castling(&mut p, offset, field)?,
+            _ => parse_fen_en_passant(&mut p, offset, field)?,
+        }
+    }
+    // Every position a MoveList is built for has to fit in it.
+    let active_color = p.active_color;
+    for color in [COLOR_WHITE, COLOR_BLACK] {
+        p.active_color = color;
+        let mut n_moves = 0;
+        for_each_pseudo_legal_move_from_position(&p, |_| n_moves += 1);
+        if n_moves > MAX_MOVES {
+            return Err(fen_error(FenErrorKind::TooManyMoves, FenField::Placement, fields[0].0));
+        }
+    }
+    p.active_color = active_color;
+    p.hash = position_hash(&p);
+    Ok(p)
+}
+
+// With default_missing_clocks, a FEN that stops after the en passant
+// field gets halfmoves 0 and fullmoves 1 instead of an error.
+fn parse_fen_with_options(
+    fen: &str, default_missing_clocks: bool
+) -> Result<Position, FenError> {
+    let fields = split_fields_with_offsets(fen);
+    let end = fen.chars().count();
+    let mut p = parse_fen_position_fields(&fields, end)?;
+    p.halfmoves = 0;
+    p.fullmoves = 1;
+    for (i, kind) in [(4, FenField::Halfmoves), (5, FenField::Fullmoves)] {
+        match fields.get(i) {
+            Some(&(offset, field)) => {
+                let n = parse_fen_clock(kind, offset, field)?;
+                if kind == FenField::Halfmoves {
+                    p.halfmoves = n;
+                } else {
+                    p.fullmoves = n;
+                }
+            },
+            None => {
+                if !default_missing_clocks {
+                    return Err(fen_error(FenErrorKind::MissingField, kind, end));
+                }
+            },
+        }
+    }
+    if let Some(&(offset, _)) = fields.get(6) {
+        return Err(fen_error(
+            FenErrorKind::TooManyFields, FenField::Fullmoves, offset));
+    }
+    Ok(p)
+}
+
+fn parse_fen(fen: &str) -> Result<Position, FenError> {
+    parse_fen_with_options(fen, false)
+}
+
+// For FENs known to be well formed.
+fn decode_fen(fen_string: String) -> Position {
+    match parse_fen(&fen_string) {
+        Ok(p) => p,
+        Err(e) => panic!("Unexpected! {}", e),
+    }
+}
+
+fn encode_fen(pos: &Position) -> String {
+    let mut result = String::new();
+    for r in (0 .. 8).rev() {
+        let mut empty_count = 0;
+        for f in 0 .. 8 {
+            let piece = piece_at_sq(pos, fr_to_sq(f, r));
+            if piece == EMPTY {
+                empty_count += 1;
+            } else {
+                if empty_count > 0 {
+                    result += &empty_count.to_string();
+                    empty_count = 0;
+                }
+                result.push(piece_to_char(piece));
             }
-        } else if state == 4 {
-            if en_passant[0] != String::from("-") {
-                p.en_passant = Some(algsq_to_sq(en_passant.clone()));
+        }
+        if empty_count > 0 {
+            result += &empty_count.to_string();
+        }
+        if r > 0 {
+            result.push('/');
+        }
+    }
+    result.push(' ');
+    result.push(if pos.active_color == COLOR_WHITE { 'w' } else { 'b' });
+    result.push(' ');
+    if pos.castling.iter().any(|&c| c) {
+        for (i, c) in ['K', 'Q', 'k', 'q'].iter().enumerate() {
+            if pos.castling[i] {
+                result.push(*c);
             }
-            halfmoves.push(c);
-        } else if state == 5 {
-            p.halfmoves = halfmoves.parse().unwrap();
-            fullmoves.push(c);
         }
+    } else {
+        result.push('-');
+    }
+    result.push(' ');
+    match pos.en_passant {
+        Some(sq) => { result += &sq_to_algstring(sq); },
+        None => { result.push('-'); },
     }
-    p.fullmoves = fullmoves.parse().unwrap();
-    p
+    result + &format!(" {} {}", pos.halfmoves, pos.fullmoves)
 }
 
 fn next_sq_in_dir(sq: Sq, dir:Direction) -> Option<Sq> {
@@ -434,140 +904,45 @@ fn next_sq_in_dir(sq: Sq, dir:Direction) -> Option<Sq> {
         DIR_NUL => { n_dir = true; r += 2; f -= 1 },
         _ => panic!("Unexpected!"),
     };
-    if n_dir || f < 0 || f > 7 || r < 0 || r > 7 {
+    if n_dir || !(0..=7).contains(&f) || !(0..=7).contains(&r) {
         None
     } else {
         Some(fr_to_sq(f, r))
     }
 }
 
-fn apply_dir_u(fr: &mut FileRank) -> bool { fr.r += 1; fr.r > 7 }
-fn apply_dir_r(fr: &mut FileRank) -> bool { fr.f += 1; fr.f > 7 }
-fn apply_dir_d(fr: &mut FileRank) -> bool { fr.r -= 1; fr.r < 0 }
-fn apply_dir_l(fr: &mut FileRank) -> bool { fr.f -= 1; fr.f < 0 }
-fn apply_dir_ur(fr: &mut FileRank) -> bool {
-    apply_dir_u(fr) || apply_dir_r(fr)
-}
-fn apply_dir_dr(fr: &mut FileRank) -> bool {
-    apply_dir_d(fr) || apply_dir_r(fr)
-}
-fn apply_dir_dl(fr: &mut FileRank) -> bool {
-    apply_dir_d(fr) || apply_dir_l(fr)
-}
-fn apply_dir_ul(fr: &mut FileRank) -> bool {
-    apply_dir_u(fr) || apply_dir_l(fr)
-}
-fn apply_dir_nur(fr: &mut FileRank) -> bool {
-    apply_dir_u(fr) || apply_dir_u(fr) || apply_dir_r(fr)
-}
-fn apply_dir_nru(fr: &mut FileRank) -> bool {
-    apply_dir_r(fr) || apply_dir_r(fr) || apply_dir_u(fr)
-}
-fn apply_dir_nrd(fr: &mut FileRank) -> bool {
-    apply_dir_r(fr) || apply_dir_r(fr) || apply_dir_d(fr)
-}
-fn apply_dir_ndr(fr: &mut FileRank) -> bool {
-    apply_dir_d(fr) || apply_dir_d(fr) || apply_dir_r(fr)
-}
-fn apply_dir_ndl(fr: &mut FileRank) -> bool {
-    apply_dir_d(fr) || apply_dir_d(fr) || apply_dir_l(fr)
-}
-fn apply_dir_nld(fr: &mut FileRank) -> bool {
-    apply_dir_l(fr) || apply_dir_l(fr) || apply_dir_d(fr)
-}
-fn apply_dir_nlu(fr: &mut FileRank) -> bool {
-    apply_dir_l(fr) || apply_dir_l(fr) || apply_dir_u(fr)
-}
-fn apply_dir_nul(fr: &mut FileRank) -> bool {
-    apply_dir_u(fr) || apply_dir_u(fr) || apply_dir_l(fr)
-}
-
 fn for_each_legal_sq_for_pawn(
-    pos: &Position, sq: Sq, fr0: &FileRank,
-    color: Color, mut func_for_sqs: impl FnMut(Sq),
+    pos: &Position, sq: Sq, color: Color,
+    mut func_for_sqs: impl FnMut(Sq),
     mut func_for_captures: impl FnMut(Sq, Piece) -> bool
 ) {
-    let mut fr = FileRank{f: fr0.f, r: fr0.r};
-    if color == COLOR_WHITE {
-        // One forward.
-        apply_dir_u(&mut fr);
-        {
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_found == EMPTY {
-                func_for_sqs(filerank_to_sq(&fr));
-            }
-        }
-        if fr0.r == 1 {
-            // Two forward.
-            apply_dir_u(&mut fr);
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_found == EMPTY {
-                func_for_sqs(filerank_to_sq(&fr));
-            }
-        }
-        // Captures
-        {
-            fr = FileRank{f: fr0.f, r: fr0.r};
-            apply_dir_ur(&mut fr);
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_color(piece_found) == -color {
-                if func_for_captures(filerank_to_sq(&fr), piece_found) {
-                    return;
-                }
-                func_for_sqs(filerank_to_sq(&fr));
-            }
-        }
-        {
-            fr = FileRank{f: fr0.f, r: fr0.r};
-            apply_dir_ul(&mut fr);
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_color(piece_found) == -color {
-                if func_for_captures(filerank_to_sq(&fr), piece_found) {
-                    return;
-                }
-                func_for_sqs(filerank_to_sq(&fr));
-            }
-        }
+    let occupied = occupied_bb(pos);
+    let r = sq_to_filerank(sq).r;
+    let (step, start_rank, last_rank) = if color == COLOR_WHITE {
+        (8, 1, 7)
     } else {
+        (-8, 6, 0)
+    };
+    if r != last_rank {
         // One forward.
-        apply_dir_d(&mut fr);
-        {
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_found == EMPTY {
-                func_for_sqs(filerank_to_sq(&fr));
-            }
-        }
-        if fr0.r == 6 {
+        let one = sq + step;
+        if occupied & sq_bb(one) == 0 {
+            func_for_sqs(one);
             // Two forward.
-            apply_dir_d(&mut fr);
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_found == EMPTY {
-                func_for_sqs(filerank_to_sq(&fr));
-            }
-        }
-        // Captures
-        {
-            fr = FileRank{f: fr0.f, r: fr0.r};
-            apply_dir_dr(&mut fr);
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_color(piece_found) == -color {
-                if func_for_captures(filerank_to_sq(&fr), piece_found) {
-                    return;
-                }
-                func_for_sqs(filerank_to_sq(&fr));
+            let two = one + step;
+            if r == start_rank && occupied & sq_bb(two) == 0 {
+                func_for_sqs(two);
             }
         }
-        {
-            fr = FileRank{f: fr0.f, r: fr0.r};
-            apply_dir_dl(&mut fr);
-            let piece_found = piece_at_sq(pos, filerank_to_sq(&fr));
-            if piece_color(piece_found) == -color {
-                if func_for_captures(filerank_to_sq(&fr), piece_found) {
-                    return;
-                }
-                func_for_sqs(filerank_to_sq(&fr));
-            }
+    }
+    // Captures
+    let mut captures = pawn_attacks(color, sq) & color_bb(pos, -color);
+    while captures != 0 {
+        let cap_sq = bb_pop_lsb(&mut captures);
+        if func_for_captures(cap_sq, piece_at_sq(pos, cap_sq)) {
+            return;
         }
+        func_for_sqs(cap_sq);
     }
 }
 
@@ -577,110 +952,503 @@ fn for_each_legal_sq_from_sq(
     mut func_for_captures: impl FnMut(Sq, Piece) -> bool,
     piece_override: Option<Piece>,
 ) {
-    let fr0 = sq_to_filerank(sq);
-    let piece;
-    match piece_override {
-        Some(p) => { piece = p; },
-        None => { piece = piece_at_sq(pos, sq); },
-    }
+    let piece = match piece_override {
+        Some(p) => p,
+        None => piece_at_sq(pos, sq),
+    };
     let color = piece_color(piece);
     let pb = piece_base(piece);
     if pb == P_BASE {
         for_each_legal_sq_for_pawn(
-            pos, sq, &fr0, color, func_for_sqs, func_for_captures);
+            pos, sq, color, func_for_sqs, func_for_captures);
         return;
     }
-    let apply_dir_funcs = match pb {
-        R_BASE => vec![apply_dir_u, apply_dir_r, apply_dir_d, apply_dir_l],
-        B_BASE => vec![apply_dir_ur, apply_dir_dr, apply_dir_dl, apply_dir_ul],
-        Q_BASE => vec![
-            apply_dir_u, apply_dir_r, apply_dir_d, apply_dir_l,
-            apply_dir_ur, apply_dir_dr, apply_dir_dl, apply_dir_ul
-        ],
-        K_BASE => vec![
-            apply_dir_u, apply_dir_r, apply_dir_d, apply_dir_l,
-            apply_dir_ur, apply_dir_dr, apply_dir_dl, apply_dir_ul
-        ],
-        N_BASE => vec![
-            apply_dir_nur, apply_dir_nru, apply_dir_nrd, apply_dir_ndr,
-            apply_dir_ndl, apply_dir_nld, apply_dir_nlu, apply_dir_nul
-        ],
-        _ => panic!("Unexpected!"),
-    };
-    for apply_dir_func in apply_dir_funcs {
-        let mut fr = FileRank{f: fr0.f, r: fr0.r};
-        loop {
-            if apply_dir_func(&mut fr) {
-                break;
-            }
-            let sq = filerank_to_sq(&fr);
-            let piece_found = piece_at_sq(pos, sq);
-            let piece_found_color = piece_color(piece_found);
-            if piece_found_color == COLOR_EMPTY {
-                func_for_sqs(sq);
-            } else if piece_found_color == color {
-                break;
-            } else {
-                if func_for_captures(sq, piece_found) {
-                    return;
-                }
-                func_for_sqs(sq);
-                break;
-            }
-            match pb {
-                N_BASE => { break; },
-                K_BASE => { break; },
-                _ => {},
-            }
+    let mut targets =
+        piece_attacks(pb, sq, occupied_bb(pos)) & !color_bb(pos, color);
+    while targets != 0 {
+        let sq = bb_pop_lsb(&mut targets);
+        let piece_found = piece_at_sq(pos, sq);
+        if piece_found != EMPTY && func_for_captures(sq, piece_found) {
+            return;
         }
+        func_for_sqs(sq);
     }
 }
 
-fn does_move_lead_to_own_king_in_check(pos: &Position, mov: &Move) -> bool {
+// All pieces of the given color attacking sq, given the occupancy.
+fn attackers_to_sq(
+    pos: &Position, sq: Sq, color: Color, occupied: Bitboard
+) -> Bitboard {
+    let rooks = pieces_bb(pos, R_BASE * color) | pieces_bb(pos, Q_BASE * color);
+    let bishops =
+        pieces_bb(pos, B_BASE * color) | pieces_bb(pos, Q_BASE * color);
+    (pawn_attacks(-color, sq) & pieces_bb(pos, P_BASE * color))
+        | (knight_attacks(sq) & pieces_bb(pos, N_BASE * color))
+        | (king_attacks(sq) & pieces_bb(pos, K_BASE * color))
+        | (rook_attacks(sq, occupied) & rooks)
+        | (bishop_attacks(sq, occupied) & bishops)
+}
+
+fn does_move_lead_to_own_king_in_check(pos: &mut Position, mov: &Move) -> bool {
     //println!("Checking move {} -> {}",
     //            sq_to_algstring(mov.from), sq_to_algstring(mov.to));
-    is_king_in_check(&position_after_move(pos, mov), true)
+    let undo = make_move(pos, mov);
+    let result = is_king_in_check(pos, true);
+    unmake_move(pos, mov, undo);
+    result
 }
 
-fn for_each_legal_move_from_position(pos: &Position, mut func: impl FnMut(Move)) {
-    for sq in 0 .. 64 {
-        let piece_found = piece_at_sq(pos, sq);
-        let piece_found_color = piece_color(piece_found);
-        if piece_found_color == pos.active_color {
-            for_each_legal_sq_from_sq(
-                &pos,
-                sq,
-                |sq_to: Sq| {
+fn is_sq_attacked(pos: &Position, sq: Sq, by_color: Color) -> bool {
+    attackers_to_sq(pos, sq, by_color, occupied_bb(pos)) != 0
+}
+
+fn for_each_castling_move(pos: &Position, mut func: impl FnMut(Move)) {
+    let color = pos.active_color;
+    let (kingside, queenside, base) = if color == COLOR_WHITE {
+        (CASTLING_WHITE_KINGSIDE, CASTLING_WHITE_QUEENSIDE, 0)
+    } else {
+        (CASTLING_BLACK_KINGSIDE, CASTLING_BLACK_QUEENSIDE, 56)
+    };
+    let king = K_BASE * color;
+    let king_sq = base + 4;
+    if piece_at_sq(pos, king_sq) != king {
+        return;
+    }
+    let occupied = occupied_bb(pos);
+    // (right, rook square, squares that must be empty, squares the king
+    // crosses and lands on)
+    let sides = [
+        (kingside, base + 7, sq_bb(base + 5) | sq_bb(base + 6),
+            [base + 5, base + 6]),
+        (queenside, base, sq_bb(base + 1) | sq_bb(base + 2) | sq_bb(base + 3),
+            [base + 3, base + 2]),
+    ];
+    for (right, rook_sq, between, king_path) in sides {
+        if !pos.castling[right] || piece_at_sq(pos, rook_sq) != R_BASE * color {
+            continue;
+        }
+        if occupied & between != 0 {
+            continue;
+        }
+        if is_sq_attacked(pos, king_sq, -color)
+            || king_path.iter().any(|&sq| is_sq_attacked(pos, sq, -color))
+        {
+            continue;
+        }
+        func(Move{
+            piece: king,
+            from: king_sq,
+            to: king_path[1],
+            promotion: EMPTY,
+            leads_to: None,
+        });
+    }
+}
+
+// Calls func for every move of the piece on sq whose destination is in
+// allowed, expanding pawn moves to the last rank into promotions. En
+// passant and castling are left to the callers.
+fn for_each_move_from_sq(
+    pos: &Position, sq: Sq, allowed: Bitboard, func: &mut impl FnMut(Move)
+) {
+    let piece_found = piece_at_sq(pos, sq);
+    let color = piece_color(piece_found);
+    let is_pawn = piece_base(piece_found) == P_BASE;
+    for_each_legal_sq_from_sq(
+        pos,
+        sq,
+        |sq_to: Sq| {
+            if allowed & sq_bb(sq_to) == 0 {
+                return;
+            }
+            let r = sq_to_filerank(sq_to).r;
+            if is_pawn && (r == 0 || r == 7) {
+                for pb in PROMOTION_BASES {
                     func(Move{
                         piece: piece_found,
                         from: sq,
                         to: sq_to,
+                        promotion: pb * color,
                         leads_to: None,
-                    })
-                },
-                |cap_sq, cap_piece| {
-                    return false;
-                },
-                None
-            );
-        }
-    }
+                    });
+                }
+            } else {
+                func(Move{
+                    piece: piece_found,
+                    from: sq,
+                    to: sq_to,
+                    promotion: EMPTY,
+                    leads_to: None,
+                });
+            }
+        },
+        |cap_sq, cap_piece| false,
+        None
+    );
 }
 
-fn set_moves_to_position(pos: &mut Position) {
-    match pos.moves {
+// Generates pseudo-legal moves: they may leave the own king in check.
+fn for_each_pseudo_legal_move_from_position(
+    pos: &Position, mut func: impl FnMut(Move)
+) {
+    let color = pos.active_color;
+    let mut own = color_bb(pos, color);
+    while own != 0 {
+        let sq = bb_pop_lsb(&mut own);
+        for_each_move_from_sq(pos, sq, !0, &mut func);
+    }
+    if let Some(ep) = pos.en_passant {
+        let pawn = P_BASE * color;
+        for_each_sq_in_bb(pawn_attacks(-color, ep) & pieces_bb(pos, pawn), |sq| {
+            func(Move{
+                piece: pawn,
+                from: sq,
+                to: ep,
+                promotion: EMPTY,
+                leads_to: None,
+            });
+        });
+    }
+    for_each_castling_move(pos, func);
+}
+
+// Pieces of the given color that are the only blocker between their own
+// king on king_sq and an enemy slider.
+fn pinned_bb(pos: &Position, king_sq: Sq, color: Color) -> Bitboard {
+    let them = -color;
+    let occupied = occupied_bb(pos);
+    let queens = pieces_bb(pos, Q_BASE * them);
+    let mut snipers =
+        (rook_attacks(king_sq, 0) & (pieces_bb(pos, R_BASE * them) | queens))
+        | (bishop_attacks(king_sq, 0) & (pieces_bb(pos, B_BASE * them) | queens));
+    let mut result = 0;
+    while snipers != 0 {
+        let sniper_sq = bb_pop_lsb(&mut snipers);
+        let blockers = between_bb(king_sq, sniper_sq) & occupied;
+        if bb_popcount(blockers) == 1 {
+            result |= blockers & color_bb(pos, color);
+        }
+    }
+    result
+}
+
+// Generates strictly legal moves. Checkers and pinned pieces are computed
+// once up front: in double check only the king moves, in single check the
+// other pieces must capture the checker or block, and pinned pieces stay
+// on the line through their king.
+fn for_each_legal_move_from_position(pos: &Position, mut func: impl FnMut(Move)) {
+    let color = pos.active_color;
+    let king = K_BASE * color;
+    let kings = pieces_bb(pos, king);
+    if kings == 0 {
+        // For positions without king.
+        for_each_pseudo_legal_move_from_position(pos, func);
+        return;
+    }
+    let king_sq = bb_lsb(kings);
+    let occupied = occupied_bb(pos);
+    let checkers = attackers_to_sq(pos, king_sq, -color, occupied);
+    // The king itself is left out of the occupancy so that it cannot hide
+    // from a slider behind its own square.
+    let mut king_targets = king_attacks(king_sq) & !color_bb(pos, color);
+    while king_targets != 0 {
+        let to = bb_pop_lsb(&mut king_targets);
+        let attackers =
+            attackers_to_sq(pos, to, -color, occupied ^ sq_bb(king_sq));
+        if attackers == 0 {
+            func(Move{
+                piece: king,
+                from: king_sq,
+                to,
+                promotion: EMPTY,
+                leads_to: None,
+            });
+        }
+    }
+    if bb_popcount(checkers) > 1 {
+        return;
+    }
+    let target_mask = if checkers != 0 {
+        between_bb(king_sq, bb_lsb(checkers)) | checkers
+    } else {
+        !0
+    };
+    let pinned = pinned_bb(pos, king_sq, color);
+    let mut own = color_bb(pos, color) & !kings;
+    while own != 0 {
+        let sq = bb_pop_lsb(&mut own);
+        let mut allowed = target_mask;
+        if pinned & sq_bb(sq) != 0 {
+            allowed &= line_bb(king_sq, sq);
+        }
+        for_each_move_from_sq(pos, sq, allowed, &mut func);
+    }
+    if let Some(ep) = pos.en_passant {
+        // En passant removes two pieces from the capturing side's rank, so
+        // it is checked directly against the resulting occupancy.
+        let pawn = P_BASE * color;
+        let cap_sq = ep - 8 * color;
+        let mut pawns = pawn_attacks(-color, ep) & pieces_bb(pos, pawn);
+        while pawns != 0 {
+            let sq = bb_pop_lsb(&mut pawns);
+            let occupied_after =
+                (occupied ^ sq_bb(sq) ^ sq_bb(cap_sq)) | sq_bb(ep);
+            let attackers = attackers_to_sq(pos, king_sq, -color, occupied_after)
+                & !sq_bb(cap_sq);
+            if attackers == 0 {
+                func(Move{
+                    piece: pawn,
+                    from: sq,
+                    to: ep,
+                    promotion: EMPTY,
+                    leads_to: None,
+                });
+            }
+        }
+    }
+    if checkers == 0 {
+        for_each_castling_move(pos, func);
+    }
+}
+
+fn is_move_castling(mov: &Move) -> bool {
+    piece_base(mov.piece) == K_BASE && (mov.to - mov.from).abs() == 2
+}
+
+fn is_move_en_passant(pos: &Position, mov: &Move) -> bool {
+    piece_base(mov.piece) == P_BASE
+        && Some(mov.to) == pos.en_passant
+        && sq_to_filerank(mov.from).f != sq_to_filerank(mov.to).f
+}
+
+// The rook's (from, to) squares for a castling move.
+fn castling_rook_sqs(mov: &Move) -> (Sq, Sq) {
+    if mov.to > mov.from {
+        (mov.from + 3, mov.from + 1)
+    } else {
+        (mov.from - 4, mov.from - 1)
+    }
+}
+
+fn clear_castling_rights_for_sq(castling: &mut [bool; 4], sq: Sq) {
+    match sq {
+        // e1, h1, a1
+        4 => {
+            castling[CASTLING_WHITE_KINGSIDE] = false;
+            castling[CASTLING_WHITE_QUEENSIDE] = false;
+        },
+        7 => { castling[CASTLING_WHITE_KINGSIDE] = false; },
+        0 => { castling[CASTLING_WHITE_QUEENSIDE] = false; },
+        // e8, h8, a8
+        60 => {
+            castling[CASTLING_BLACK_KINGSIDE] = false;
+            castling[CASTLING_BLACK_QUEENSIDE] = false;
+        },
+        63 => { castling[CASTLING_BLACK_KINGSIDE] = false; },
+        56 => { castling[CASTLING_BLACK_QUEENSIDE] = false; },
+        _ => {},
+    }
+}
+
+// Plays mov on pos in place. The returned Undo must be handed back to
+// unmake_move, together with the same move, to get the position back.
+fn make_move(pos: &mut Position, mov: &Move) -> Undo {
+    let color = pos.active_color;
+    let en_passant = is_move_en_passant(pos, mov);
+    let mut undo = Undo{
+        captured: piece_at_sq(pos, mov.to),
+        castling: pos.castling,
+        en_passant: pos.en_passant,
+        halfmoves: pos.halfmoves,
+        fullmoves: pos.fullmoves,
+        hash: pos.hash,
+        evaluation: pos.evaluation.take(),
+        moves: pos.moves.take(),
+        is_king_in_check: pos.is_king_in_check.take(),
+        is_king_in_checkmate: pos.is_king_in_checkmate.take(),
+        is_king_in_stalemate: pos.is_king_in_stalemate.take(),
+    };
+    pos.hash ^= zobrist_castling_key(&pos.castling);
+    pos.hash ^= zobrist_en_passant_key(pos.en_passant);
+    if en_passant {
+        let cap_sq = mov.to - 8 * color;
+        undo.captured = piece_at_sq(pos, cap_sq);
+        set_piece_at_sq(pos, cap_sq, EMPTY);
+    }
+    set_piece_at_sq(pos, mov.from, EMPTY);
+    if mov.promotion != EMPTY {
+        set_piece_at_sq(pos, mov.to, mov.promotion);
+    } else {
+        set_piece_at_sq(pos, mov.to, mov.piece);
+    }
+    if is_move_castling(mov) {
+        let (rook_from, rook_to) = castling_rook_sqs(mov);
+        let rook = piece_at_sq(pos, rook_from);
+        set_piece_at_sq(pos, rook_from, EMPTY);
+        set_piece_at_sq(pos, rook_to, rook);
+    }
+    clear_castling_rights_for_sq(&mut pos.castling, mov.from);
+    clear_castling_rights_for_sq(&mut pos.castling, mov.to);
+    let is_pawn = piece_base(mov.piece) == P_BASE;
+    if is_pawn && (mov.to - mov.from).abs() == 16 {
+        pos.en_passant = Some((mov.from + mov.to) / 2);
+    } else {
+        pos.en_passant = None;
+    }
+    if is_pawn || undo.captured != EMPTY {
+        pos.halfmoves = 0;
+    } else {
+        pos.halfmoves += 1;
+    }
+    if color == COLOR_BLACK {
+        pos.fullmoves += 1;
+    }
+    pos.active_color = -color;
+    pos.hash ^= zobrist_castling_key(&pos.castling);
+    pos.hash ^= zobrist_en_passant_key(pos.en_passant);
+    pos.hash ^= ZOBRIST.black_to_move;
+    undo
+}
+
+fn unmake_move(pos: &mut Position, mov: &Move, undo: Undo) {
+    pos.active_color = -pos.active_color;
+    let color = pos.active_color;
+    if is_move_castling(mov) {
+        let (rook_from, rook_to) = castling_rook_sqs(mov);
+        let rook = piece_at_sq(pos, rook_to);
+        set_piece_at_sq(pos, rook_to, EMPTY);
+        set_piece_at_sq(pos, rook_from, rook);
+    }
+    set_piece_at_sq(pos, mov.from, mov.piece);
+    pos.en_passant = undo.en_passant;
+    if is_move_en_passant(pos, mov) {
+        set_piece_at_sq(pos, mov.to, EMPTY);
+        set_piece_at_sq(pos, mov.to - 8 * color, undo.captured);
+    } else {
+        set_piece_at_sq(pos, mov.to, undo.captured);
+    }
+    pos.castling = undo.castling;
+    pos.halfmoves = undo.halfmoves;
+    pos.fullmoves = undo.fullmoves;
+    pos.hash = undo.hash;
+    pos.evaluation = undo.evaluation;
+    pos.moves = undo.moves;
+    pos.is_king_in_check = undo.is_king_in_check;
+    pos.is_king_in_checkmate = undo.is_king_in_checkmate;
+    pos.is_king_in_stalemate = undo.is_king_in_stalemate;
+}
+
+fn pack_move(pos: &Position, mov: &Move) -> PackedMove {
+    let flags = if mov.promotion != EMPTY {
+        let pb = piece_base(mov.promotion);
+        let i = PROMOTION_BASES.iter().position(|&p| p == pb).unwrap();
+        MOVE_FLAG_PROMOTION + i as u16
+    } else if is_move_castling(mov) {
+        MOVE_FLAG_CASTLING
+    } else if is_move_en_passant(pos, mov) {
+        MOVE_FLAG_EN_PASSANT
+    } else {
+        MOVE_FLAG_NORMAL
+    };
+    (mov.from as u16) | ((mov.to as u16) << 6) | (flags << 12)
+}
+
+fn packed_move_from(pm: PackedMove) -> Sq {
+    (pm & 63) as Sq
+}
+
+fn packed_move_to(pm: PackedMove) -> Sq {
+    ((pm >> 6) & 63) as Sq
+}
+
+fn packed_move_flags(pm: PackedMove) -> u16 {
+    pm >> 12
+}
+
+// The moving piece is taken from pos, so pm must be a move of pos.
+fn unpack_move(pos: &Position, pm: PackedMove) -> Move {
+    let from = packed_move_from(pm);
+    let piece = piece_at_sq(pos, from);
+    let flags = packed_move_flags(pm);
+    let promotion = if flags >= MOVE_FLAG_PROMOTION {
+        PROMOTION_BASES[(flags - MOVE_FLAG_PROMOTION) as usize]
+            * piece_color(piece)
+    } else {
+        EMPTY
+    };
+    Move{
+        piece,
+        from,
+        to: packed_move_to(pm),
+        promotion,
+        leads_to: None,
+    }
+}
+
+fn packed_move_to_uci_string(pm: PackedMove) -> String {
+    let mut result = sq_to_algstring(packed_move_from(pm))
+        + &sq_to_algstring(packed_move_to(pm));
+    let flags = packed_move_flags(pm);
+    if flags >= MOVE_FLAG_PROMOTION {
+        let pb = PROMOTION_BASES[(flags - MOVE_FLAG_PROMOTION) as usize];
+        result.push(piece_to_char(-pb));
+    }
+    result
+}
+
+fn empty_move_list() -> MoveList {
+    MoveList{
+        moves: [NULL_PACKED_MOVE; MAX_MOVES],
+        len: 0,
+    }
+}
+
+fn move_list_push(list: &mut MoveList, pm: PackedMove) {
+    debug_assert!(list.len < MAX_MOVES, "more than MAX_MOVES moves");
+    list.moves[list.len] = pm;
+    list.len += 1;
+}
+
+fn move_list_slice(list: &MoveList) -> &[PackedMove] {
+    &list.moves[.. list.len]
+}
+
+fn generate_legal_moves(pos: &Position) -> MoveList {
+    let mut list = empty_move_list();
+    for_each_legal_move_from_position(pos, |mov| {
+        move_list_push(&mut list, pack_move(pos, &mov));
+    });
+    list
+}
+
+// Counts the leaf nodes of the legal move tree, for checking move
+// generation against known totals.
+fn perft(pos: &mut Position, depth: u32) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    let list = generate_legal_moves(pos);
+    if depth == 1 {
+        return list.len as u64;
+    }
+    let mut nodes = 0;
+    for &pm in move_list_slice(&list) {
+        let mov = unpack_move(pos, pm);
+        debug_assert_eq!(pack_move(pos, &mov), pm);
+        let undo = make_move(pos, &mov);
+        debug_assert_eq!(pos.hash, position_hash(pos));
+        nodes += perft(pos, depth - 1);
+        unmake_move(pos, &mov, undo);
+    }
+    nodes
+}
+
+fn set_moves_to_position(pos: &mut Position) {
+    match pos.moves {
         Some(_) => panic!("Unexpected"),
         None => {
             let mut v = Vec::new();
-            for_each_legal_move_from_position(
-                pos,
-                |mov| {
-                    //println!("{}", does_move_lead_to_own_king_in_check(pos, &mov));
-                    if !does_move_lead_to_own_king_in_check(pos, &mov) {
-                        v.push(mov);
-                    }
-                }
-            );
+            for_each_legal_move_from_position(pos, |mov| v.push(mov));
             pos.moves = Some(v);
         }
     }
@@ -697,10 +1465,7 @@ fn is_king_in_checkmate(pos: &Position) -> bool {
     match &pos.moves {
         None => panic!("Unexpected"),
         Some(moves) => {
-            if moves.is_empty() {
-                return is_king_in_check(pos, false)
-            }
-            return false
+            moves.is_empty() && is_king_in_check(pos, false)
         },
     }
 }
@@ -709,14 +1474,29 @@ fn is_king_in_stalemate(pos: &Position) -> bool {
     match &pos.moves {
         None => panic!("Unexpected"),
         Some(moves) => {
-            if moves.is_empty() {
-                return !is_king_in_check(pos, false)
-            }
-            return false
+            moves.is_empty() && !is_king_in_check(pos, false)
         },
     }
 }
 
+// Neither side can possibly mate: bare kings, a single minor piece, or
+// bishops that all stand on squares of one color.
+fn is_insufficient_material(pos: &Position) -> bool {
+    let heavy_or_pawns = pos.piece_bb[piece_index(P_BASE)]
+        | pos.piece_bb[piece_index(R_BASE)]
+        | pos.piece_bb[piece_index(Q_BASE)];
+    if heavy_or_pawns != 0 {
+        return false;
+    }
+    let knights = pos.piece_bb[piece_index(N_BASE)];
+    let bishops = pos.piece_bb[piece_index(B_BASE)];
+    if bb_popcount(knights | bishops) <= 1 {
+        return true;
+    }
+    let light_squares: Bitboard = 0x55aa55aa55aa55aa;
+    knights == 0 && (bishops & light_squares == 0 || bishops & !light_squares == 0)
+}
+
 fn print_sq(sq: Sq) {
     println!("{}", sq_to_algstring(sq));
 }
@@ -727,75 +1507,60 @@ fn print_move(mov: Move) {
                 sq_to_algstring(mov.to));
 }
 
-fn position_after_move(pos: &Position, mov: &Move) -> Position {
-    let mut new_placement: [Piece; 64] = [0; 64];
-    new_placement.copy_from_slice(&pos.placement);
-    let mut pos = Position{
-        placement: new_placement,
-        active_color: -pos.active_color,
+// Copies everything but the cached expansion.
+fn copy_position(pos: &Position) -> Position {
+    Position{
+        placement: pos.placement,
+        piece_bb: pos.piece_bb,
+        color_bb: pos.color_bb,
+        active_color: pos.active_color,
         castling: pos.castling,
         en_passant: pos.en_passant,
-        halfmoves: pos.halfmoves, //TODO
-        fullmoves: pos.fullmoves, // TODO
+        halfmoves: pos.halfmoves,
+        fullmoves: pos.fullmoves,
+        hash: pos.hash,
         evaluation: None,
         moves: None,
         is_king_in_check: None,
         is_king_in_checkmate: None,
         is_king_in_stalemate: None,
-    };
-    set_piece_at_sq(&mut pos, mov.from, EMPTY);
-    set_piece_at_sq(&mut pos, mov.to, mov.piece);
+    }
+}
+
+fn position_after_move(pos: &Position, mov: &Move) -> Position {
+    let mut pos = copy_position(pos);
+    make_move(&mut pos, mov);
     pos
 }
 
 fn position_static_value(pos: &Position) -> Val {
+    if let Some(score) = endgame_evaluate_white(pos) {
+        return score as Val / 100.0;
+    }
     let mut result = 0.0;
-    for f in 0 .. 8 {
-        for r in 0 .. 8 {
-            let sq = fr_to_sq(f, r);
-            let piece_found = piece_at_sq(pos, sq);
-            result += match piece_found {
-                R_WHITE =>  5.0,
-                R_BLACK => -5.0,
-                N_WHITE =>  3.0,
-                N_BLACK => -3.0,
-                B_WHITE =>  3.0,
-                B_BLACK => -3.0,
-                Q_WHITE =>  9.0,
-                Q_BLACK => -9.0,
-                P_WHITE =>  1.0,
-                P_BLACK => -1.0,
-                K_WHITE =>  9999.0,
-                K_BLACK => -9999.0,
-                EMPTY => 0.0,
-                _ => panic!("Unexpected"),
-            };
-        }
+    for (piece, val) in [
+        (R_WHITE, 5.0),
+        (N_WHITE, 3.0),
+        (B_WHITE, 3.0),
+        (Q_WHITE, 9.0),
+        (P_WHITE, 1.0),
+        (K_WHITE, 9999.0),
+    ] {
+        let n_white = bb_popcount(pieces_bb(pos, piece)) as Val;
+        let n_black = bb_popcount(pieces_bb(pos, -piece)) as Val;
+        result += val * (n_white - n_black);
     }
     result
 }
 
 fn is_king_in_check(pos: &Position, w_toggled_active_color: bool) -> bool {
-    let sign;
-    if w_toggled_active_color { sign = -1; }
-    else { sign = 1; }
-
-    let king;
-    if pos.active_color == COLOR_WHITE { king = K_WHITE * sign; }
-    else { king = K_BLACK * sign; }
-
-    for f in 0 .. 8 {
-        for r in 0 .. 8 {
-            let sq = fr_to_sq(f, r);
-            let piece_found = piece_at_sq(pos, sq);
-            if piece_found == king {
-                //println!("King found at {}", sq_to_algstring(sq));
-                return is_king_in_specific_sq_in_check(
-                                pos, sq, w_toggled_active_color);
-            }
-        }
+    let sign = if w_toggled_active_color { -1 } else { 1 };
+    let king = K_BASE * pos.active_color * sign;
+    let kings = pieces_bb(pos, king);
+    if kings == 0 {
+        return false; // For positions without king.
     }
-    false // For positions without king.
+    is_king_in_specific_sq_in_check(pos, bb_lsb(kings), w_toggled_active_color)
 }
 
 fn set_is_king_in_check(pos: &mut Position) {
@@ -823,30 +1588,137 @@ fn is_king_in_specific_sq_in_check(
         pos: &Position, sq: Sq, w_toggled_active_color: bool,
 ) -> bool {
     //println!("Checking if king on {} is in check...", sq_to_algstring(sq));
-    let sign;
-    if w_toggled_active_color { sign = -1; }
-    else { sign = 1; }
-    let mut result = false;
-    for p in [R_WHITE, N_WHITE, B_WHITE, Q_WHITE, P_WHITE] {
-        for_each_legal_sq_from_sq(
-            pos, sq,
-            |sq| { },
-            |cap_sq, cap_piece| {
-                if cap_piece == (-p * pos.active_color * sign) {
-                    result = true;
-                    // Return true to instruct
-                    // for_each_legal_sq_from_sq to stop.
-                    return true;
-                }
-                return false;
-            },
-            Some(p * pos.active_color * sign),
-        );
-        if result {
-            return result;
+    let sign = if w_toggled_active_color { -1 } else { 1 };
+    let attacker_color = -pos.active_color * sign;
+    attackers_to_sq(pos, sq, attacker_color, occupied_bb(pos)) != 0
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum IllegalReason {
+    // Color and the number of kings found.
+    WrongKingCount(Color, u32),
+    TooManyPawns(Color),
+    TooManyPieces(Color),
+    PawnOnBackRank(Sq),
+    // Index into Position.castling whose king or rook is not at home.
+    CastlingWithoutKingOrRook(usize),
+    ImplausibleEnPassant(Sq),
+    OpponentInCheck,
+    TooManyCheckers(u32),
+    // Two checkers that no single move could have produced.
+    ImpossibleDoubleCheck,
+}
+
+impl std::fmt::Display for IllegalReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let color_name = |c: &Color| if *c == COLOR_WHITE { "white" } else { "black" };
+        match self {
+            IllegalReason::WrongKingCount(c, n) =>
+                write!(f, "{} has {} kings", color_name(c), n),
+            IllegalReason::TooManyPawns(c) =>
+                write!(f, "{} has more than 8 pawns", color_name(c)),
+            IllegalReason::TooManyPieces(c) =>
+                write!(f, "{} has more than 16 pieces", color_name(c)),
+            IllegalReason::PawnOnBackRank(sq) =>
+                write!(f, "pawn on {}", sq_to_algstring(*sq)),
+            IllegalReason::CastlingWithoutKingOrRook(i) =>
+                write!(f, "castling right {} without king and rook at home",
+                    ['K', 'Q', 'k', 'q'][*i]),
+            IllegalReason::ImplausibleEnPassant(sq) =>
+                write!(f, "en passant square {} is implausible",
+                    sq_to_algstring(*sq)),
+            IllegalReason::OpponentInCheck =>
+                write!(f, "side not to move is in check"),
+            IllegalReason::TooManyCheckers(n) =>
+                write!(f, "king is attacked by {} pieces", n),
+            IllegalReason::ImpossibleDoubleCheck =>
+                write!(f, "double check that no move could give"),
         }
     }
-    result
+}
+
+// Checks that pos could arise in a game. Returns every problem found, not
+// just the first one.
+fn validate(pos: &Position) -> Result<(), Vec<IllegalReason>> {
+    let mut reasons = Vec::new();
+    let mut kings_ok = true;
+    for color in [COLOR_WHITE, COLOR_BLACK] {
+        let n_kings = bb_popcount(pieces_bb(pos, K_BASE * color));
+        if n_kings != 1 {
+            reasons.push(IllegalReason::WrongKingCount(color, n_kings));
+            kings_ok = false;
+        }
+        if bb_popcount(pieces_bb(pos, P_BASE * color)) > 8 {
+            reasons.push(IllegalReason::TooManyPawns(color));
+        }
+        if bb_popcount(color_bb(pos, color)) > 16 {
+            reasons.push(IllegalReason::TooManyPieces(color));
+        }
+    }
+    let back_ranks: Bitboard = 0xff000000000000ff;
+    let pawns = pos.piece_bb[piece_index(P_BASE)];
+    for_each_sq_in_bb(pawns & back_ranks, |sq| {
+        reasons.push(IllegalReason::PawnOnBackRank(sq));
+    });
+    // (right, king square, rook square, color)
+    let castling_homes = [
+        (CASTLING_WHITE_KINGSIDE, 4, 7, COLOR_WHITE),
+        (CASTLING_WHITE_QUEENSIDE, 4, 0, COLOR_WHITE),
+        (CASTLING_BLACK_KINGSIDE, 60, 63, COLOR_BLACK),
+        (CASTLING_BLACK_QUEENSIDE, 60, 56, COLOR_BLACK),
+    ];
+    for (right, king_sq, rook_sq, color) in castling_homes {
+        if pos.castling[right]
+            && (piece_at_sq(pos, king_sq) != K_BASE * color
+                || piece_at_sq(pos, rook_sq) != R_BASE * color)
+        {
+            reasons.push(IllegalReason::CastlingWithoutKingOrRook(right));
+        }
+    }
+    if let Some(ep) = pos.en_passant {
+        // The opponent's pawn just went from behind ep to in front of it.
+        let them = -pos.active_color;
+        let expected_rank = if them == COLOR_WHITE { 2 } else { 5 };
+        let plausible = sq_to_filerank(ep).r == expected_rank
+            && piece_at_sq(pos, ep) == EMPTY
+            && piece_at_sq(pos, ep - 8 * them) == EMPTY
+            && piece_at_sq(pos, ep + 8 * them) == P_BASE * them;
+        if !plausible {
+            reasons.push(IllegalReason::ImplausibleEnPassant(ep));
+        }
+    }
+    if kings_ok {
+        let occupied = occupied_bb(pos);
+        let color = pos.active_color;
+        let their_king = bb_lsb(pieces_bb(pos, -K_BASE * color));
+        if attackers_to_sq(pos, their_king, color, occupied) != 0 {
+            reasons.push(IllegalReason::OpponentInCheck);
+        }
+        let king_sq = bb_lsb(pieces_bb(pos, K_BASE * color));
+        let checkers = attackers_to_sq(pos, king_sq, -color, occupied);
+        let n_checkers = bb_popcount(checkers);
+        if n_checkers > 2 {
+            reasons.push(IllegalReason::TooManyCheckers(n_checkers));
+        } else if n_checkers == 2 {
+            // A move gives at most one direct check and uncovers at most
+            // one slider, and the two can never share a line with the king.
+            let sliders = pos.piece_bb[piece_index(R_BASE)]
+                | pos.piece_bb[piece_index(B_BASE)]
+                | pos.piece_bb[piece_index(Q_BASE)];
+            let first = bb_lsb(checkers);
+            let second = bb_lsb(checkers & (checkers - 1));
+            if bb_popcount(checkers & !sliders) > 1
+                || line_bb(first, second) & sq_bb(king_sq) != 0
+            {
+                reasons.push(IllegalReason::ImpossibleDoubleCheck);
+            }
+        }
+    }
+    if reasons.is_empty() {
+        Ok(())
+    } else {
+        Err(reasons)
+    }
 }
 
 fn position_val_at_ply(pos: &Position, ply: Ply) -> Vec<MoveVal> {
@@ -878,9 +1750,39 @@ fn position_val_at_ply(pos: &Position, ply: Ply) -> Vec<MoveVal> {
 // TODO: print moves
 // TODO: sort moves in evaluation
 
+// The file, rank or square of mov.from needed to tell mov apart from the
+// other legal moves of the same piece type to the same square.
+fn san_disambiguation(mov: &Move, pos: &Position) -> String {
+    let mut others_exist = false;
+    let mut file_shared = false;
+    let mut rank_shared = false;
+    let from = sq_to_filerank(mov.from);
+    for_each_legal_move_from_position(pos, |other| {
+        if other.piece != mov.piece || other.to != mov.to || other.from == mov.from {
+            return;
+        }
+        let other_from = sq_to_filerank(other.from);
+        others_exist = true;
+        file_shared |= other_from.f == from.f;
+        rank_shared |= other_from.r == from.r;
+    });
+    if !others_exist {
+        String::from("")
+    } else if !file_shared {
+        f_to_string(from.f)
+    } else if !rank_shared {
+        r_to_string(from.r)
+    } else {
+        sq_to_algstring(mov.from)
+    }
+}
+
+// Standard Algebraic Notation for mov, which must be legal in pos.
 fn move_to_string(mov: &Move, pos: &Position) -> String {
     let piece_moving = piece_at_sq(pos, mov.from);
-    let piece_being_captured = piece_at_sq(pos, mov.to);
+    let is_pawn = piece_base(piece_moving) == P_BASE;
+    let is_capture = piece_at_sq(pos, mov.to) != EMPTY
+        || is_move_en_passant(pos, mov);
     let piece_string = match piece_moving {
         P_WHITE => "",
         R_WHITE => "R",
@@ -896,59 +1798,656 @@ fn move_to_string(mov: &Move, pos: &Position) -> String {
         K_BLACK => "K",
         _ => panic!("Unexpected"),
     };
-    let capturing_pawn_string;
-    let capture_string;
-    if piece_being_captured != EMPTY {
-        if piece_moving == P_WHITE || piece_moving == P_BLACK {
-            capturing_pawn_string =
-                f_to_string(sq_to_filerank(mov.from).f);
+    let from_string;
+    if is_pawn {
+        if is_capture {
+            from_string = f_to_string(sq_to_filerank(mov.from).f);
         } else {
-            capturing_pawn_string = String::from("");
+            from_string = String::from("");
         }
-        capture_string = "x";
     } else {
-        capturing_pawn_string = String::from("");
-        capture_string = "";
+        from_string = san_disambiguation(mov, pos);
     }
+    let capture_string = if is_capture { "x" } else { "" };
+    let promotion_string = if mov.promotion != EMPTY {
+        format!("={}", piece_to_char(piece_base(mov.promotion)))
+    } else {
+        String::from("")
+    };
     let check_or_checkmate_string;
-    let mut new_pos = position_after_move(pos, mov);
-    expand_position(&mut new_pos);
-    if is_king_in_checkmate(&new_pos) {
-        check_or_checkmate_string = "#";
-    } else if is_king_in_check(&new_pos, false) {
-        check_or_checkmate_string = "+";
+    let new_pos = position_after_move(pos, mov);
+    if is_king_in_check(&new_pos, false) {
+        if generate_legal_moves(&new_pos).len == 0 {
+            check_or_checkmate_string = "#";
+        } else {
+            check_or_checkmate_string = "+";
+        }
     } else {
         check_or_checkmate_string = "";
     }
+    if is_move_castling(mov) {
+        let castling_string = if mov.to > mov.from { "O-O" } else { "O-O-O" };
+        return String::from(castling_string) + check_or_checkmate_string;
+    }
     let sq_string = sq_to_algstring(mov.to);
-    let result = vec![
+    let result = [
         piece_string,
-        &capturing_pawn_string,
+        &from_string,
         capture_string,
         &sq_string,
+        &promotion_string,
         check_or_checkmate_string
     ];
-    String::from(result.join(""))
+    result.join("")
 }
 
-fn main() {
-    let starting_fen =
-        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
-    let empty_fen = "8/8/8/8/8/8/8/8 w - - 0 1";
-    let fen_problematic_shows_no_valid_moves =
-            "8/4k3/3N1N2/4Q3/1B6/8/1K6/8 b - - 0 1";
-    let fen = 
-            "8/4k3/3P1P2/4Q3/1B6/8/1K6/8 w - - 0 1";
-    let mut pos = decode_fen(
-                    String::from(fen_problematic_shows_no_valid_moves));
-    expand_position(&mut pos);
-    match pos.moves {
-        Some(ref moves) => {
+fn legal_move_strings(pos: &Position) -> Vec<String> {
+    let mut result = Vec::new();
+    for_each_legal_move_from_position(pos, |mov| {
+        result.push(move_to_string(&mov, pos));
+    });
+    result
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MoveParseError {
+    BadSyntax(String),
+    // Well formed, but no legal move matches.
+    IllegalMove(String),
+    // The input and the SAN of every legal move it matches.
+    AmbiguousMove(String, Vec<String>),
+}
+
+impl std::fmt::Display for MoveParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MoveParseError::BadSyntax(s) => write!(f, "cannot parse move '{}'", s),
+            MoveParseError::IllegalMove(s) => write!(f, "illegal move '{}'", s),
+            MoveParseError::AmbiguousMove(s, candidates) =>
+                write!(f, "ambiguous move '{}': could be {}", s, candidates.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for MoveParseError {}
+
+// Copies a move without whatever position it leads to.
+fn copy_move(mov: &Move) -> Move {
+    Move{
+        piece: mov.piece,
+        from: mov.from,
+        to: mov.to,
+        promotion: mov.promotion,
+        leads_to: None,
+    }
+}
+
+// The legal moves of pos accepted by pred, taken from pos.moves when the
+// position has been expanded.
+fn matching_legal_moves(pos: &Position, mut pred: impl FnMut(&Move) -> bool) -> Vec<Move> {
+    let mut result = Vec::new();
+    match &pos.moves {
+        Some(moves) => {
             for mov in moves.iter() {
-                println!("{}", move_to_string(mov, &pos));
+                if pred(mov) {
+                    result.push(copy_move(mov));
+                }
             }
         },
-        None => {},
+        None => {
+            for_each_legal_move_from_position(pos, |mov| {
+                if pred(&mov) {
+                    result.push(mov);
+                }
+            });
+        },
+    }
+    result
+}
+
+fn single_matching_move(
+    pos: &Position, input: &str, mut candidates: Vec<Move>
+) -> Result<Move, MoveParseError> {
+    match candidates.len() {
+        0 => Err(MoveParseError::IllegalMove(String::from(input))),
+        1 => Ok(candidates.pop().unwrap()),
+        _ => Err(MoveParseError::AmbiguousMove(
+            String::from(input),
+            candidates.iter().map(|mov| move_to_string(mov, pos)).collect())),
+    }
+}
+
+fn char_to_promotion_base(c: char) -> Option<Piece> {
+    match c {
+        'Q' => Some(Q_BASE),
+        'R' => Some(R_BASE),
+        'B' => Some(B_BASE),
+        'N' => Some(N_BASE),
+        _ => None,
+    }
+}
+
+// Parses a move in SAN. Check and annotation marks are optional, castling
+// may be written with zeros, the promotion '=' may be left out (but not
+// the uppercase piece letter), a trailing "e.p." is ignored, and the
+// capture mark is not required to be right.
+fn parse_san(pos: &Position, san: &str) -> Result<Move, MoveParseError> {
+    let bad_syntax = || Err(MoveParseError::BadSyntax(String::from(san)));
+    let mut s = san.trim();
+    if let Some(stripped) = s.strip_suffix("e.p.") {
+        s = stripped.trim_end();
+    }
+    let s = s.trim_end_matches(['+', '#', '!', '?']);
+    let color = pos.active_color;
+    if s == "O-O" || s == "0-0" || s == "O-O-O" || s == "0-0-0" {
+        let kingside = s.len() == 3;
+        let candidates = matching_legal_moves(pos, |mov| {
+            is_move_castling(mov) && (mov.to > mov.from) == kingside
+        });
+        return single_matching_move(pos, san, candidates);
+    }
+    let mut chars: Vec<char> = s.chars().filter(|&c| c != 'x' && c != ':' && c != '-').collect();
+    if chars.is_empty() {
+        return bad_syntax();
+    }
+    let pb = match chars[0] {
+        'K' => K_BASE,
+        'Q' => Q_BASE,
+        'R' => R_BASE,
+        'B' => B_BASE,
+        'N' => N_BASE,
+        _ => P_BASE,
+    };
+    if pb != P_BASE {
+        chars.remove(0);
+    }
+    // A promotion is an uppercase piece letter after '=' or right after a
+    // destination on the last rank; a lowercase letter is always a file.
+    let mut promotion = EMPTY;
+    if let [.., before, last] = chars[..] {
+        let after_destination = before == '=' || before == '1' || before == '8';
+        if let Some(promotion_base) = char_to_promotion_base(last).filter(|_| after_destination) {
+            if pb != P_BASE {
+                return bad_syntax();
+            }
+            promotion = promotion_base * color;
+            chars.pop();
+            if chars.last() == Some(&'=') {
+                chars.pop();
+            }
+        }
+    }
+    if chars.len() < 2 {
+        return bad_syntax();
+    }
+    let to_string: String = chars[chars.len() - 2 ..].iter().collect();
+    let Some(to) = algstring_to_sq(&to_string) else {
+        return bad_syntax();
+    };
+    let mut from_file: Option<File> = None;
+    let mut from_rank: Option<Rank> = None;
+    for &c in &chars[.. chars.len() - 2] {
+        match c {
+            'a' ..= 'h' if from_file.is_none() => {
+                from_file = Some((c as u8 - b'a') as File);
+            },
+            '1' ..= '8' if from_rank.is_none() => {
+                from_rank = Some((c as u8 - b'1') as Rank);
+            },
+            _ => { return bad_syntax(); },
+        }
+    }
+    let candidates = matching_legal_moves(pos, |mov| {
+        let from = sq_to_filerank(mov.from);
+        piece_base(mov.piece) == pb
+            && mov.to == to
+            && mov.promotion == promotion
+            && from_file.is_none_or(|f| f == from.f)
+            && from_rank.is_none_or(|r| r == from.r)
+    });
+    single_matching_move(pos, san, candidates)
+}
+
+// Parses a move in UCI long algebraic notation, e.g. "e2e4" or "e7e8q".
+// Castling is written as the king's move.
+fn parse_uci(pos: &Position, uci: &str) -> Result<Move, MoveParseError> {
+    let bad_syntax = || Err(MoveParseError::BadSyntax(String::from(uci)));
+    let uci = uci.trim();
+    if !uci.is_ascii() || (uci.len() != 4 && uci.len() != 5) {
+        return bad_syntax();
+    }
+    let (Some(from), Some(to)) =
+        (algstring_to_sq(&uci[0 .. 2]), algstring_to_sq(&uci[2 .. 4]))
+    else {
+        return bad_syntax();
+    };
+    let mut promotion = EMPTY;
+    if let Some(c) = uci[4 ..].chars().next() {
+        match char_to_promotion_base(c.to_ascii_uppercase()) {
+            Some(pb) => { promotion = pb * pos.active_color; },
+            None => { return bad_syntax(); },
+        }
+    }
+    let candidates = matching_legal_moves(pos, |mov| {
+        mov.from == from && mov.to == to && mov.promotion == promotion
+    });
+    single_matching_move(pos, uci, candidates)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 2 && args[1] == "perft" {
+        // perft <depth> [fen]
+        let depth: u32 = args[2].parse().unwrap();
+        let fen = if args.len() > 3 {
+            args[3].clone()
+        } else {
+            String::from(
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1")
+        };
+        let mut pos = decode_fen(fen);
+        println!("{}", perft(&mut pos, depth));
+        return;
+    }
+    if args.len() > 2 && args[1] == "pgn" {
+        // pgn <file> [export]: replays every game and reports the ones that
+        // fail. With export, the games that parse are written back out in
+        // export format instead.
+        let export = args.len() > 3 && args[3] == "export";
+        let file = std::fs::File::open(&args[2]).unwrap();
+        let mut n_games = 0;
+        let mut n_errors = 0;
+        for_each_pgn_game(std::io::BufReader::new(file), |game| {
+            n_games += 1;
+            match game {
+                Ok(game) if export => {
+                    print!("{}", write_pgn(&game));
+                },
+                Ok(game) => {
+                    println!("Game {}: {} - {} {} ({} plies)",
+                        n_games,
+                        pgn_tag(&game, "White").unwrap_or("?"),
+                        pgn_tag(&game, "Black").unwrap_or("?"),
+                        game.result,
+                        game.line.moves.len());
+                },
+                Err(e) => {
+                    n_errors += 1;
+                    eprintln!("Game {}: {}", n_games, e);
+                },
+            }
+        });
+        eprintln!("{} games, {} errors", n_games, n_errors);
+        return;
+    }
+    if args.len() > 2 && args[1] == "book" {
+        // book <file.bin> [fen]: lists the book moves of a position, the
+        // starting position by default.
+        let book = read_polyglot_book(&args[2]).unwrap();
+        let fen = args.get(3).cloned().unwrap_or(String::from(STARTING_FEN));
+        let pos = decode_fen(fen);
+        let moves = polyglot_book_moves(&book, &pos);
+        let total: u64 = moves.iter().map(|(_, w)| *w as u64).sum();
+        println!("key {:016x}", polyglot_key(&pos));
+        for (mov, weight) in moves {
+            println!("{:8} {:6} {:5.1}%", move_to_string(&mov, &pos), weight,
+                100.0 * weight as f64 / total.max(1) as f64);
+        }
+        return;
+    }
+    if args.len() > 3 && args[1] == "makebook" {
+        // makebook <in.pgn> <out.bin> [min-elo <n>] [max-ply <n>]
+        // [min-games <n>] [results <r>,<r>...]: builds a Polyglot book.
+        let mut options = default_book_build_options();
+        for pair in args[4 ..].chunks(2) {
+            match (pair[0].as_str(), pair.get(1)) {
+                ("min-elo", Some(n)) => { options.min_elo = Some(n.parse().unwrap()); },
+                ("max-ply", Some(n)) => { options.max_ply = n.parse().unwrap(); },
+                ("min-games", Some(n)) => { options.min_games = n.parse().unwrap(); },
+                ("results", Some(r)) => { options.results = r.split(',').map(String::from).collect(); },
+                _ => panic!("Unexpected! usage: makebook <in.pgn> <out.bin> [min-elo <n>] \
+                    [max-ply <n>] [min-games <n>] [results <r>,<r>...]"),
+            }
+        }
+        let input = std::io::BufReader::new(std::fs::File::open(&args[2]).unwrap());
+        let mut output = std::fs::File::create(&args[3]).unwrap();
+        let summary = build_polyglot_book(input, &mut output, &options, &std::env::temp_dir()).unwrap();
+        eprintln!("{} games, {} used, {} errors, {} entries from {} runs",
+            summary.games, summary.games_used, summary.errors, summary.entries, summary.runs);
+        return;
+    }
+    if args.len() > 2 && args[1] == "tbgen" {
+        // tbgen <signature> [dir]: computes the table of a signature such
+        // as KRvK and those it leads to, and writes them all to dir.
+        let dir = std::path::PathBuf::from(args.get(3).map(|s| s.as_str()).unwrap_or("."));
+        let mut tbs = new_tablebases();
+        generate_tablebase(&mut tbs, &args[2]).unwrap();
+        for signature in tablebase_signatures(&tbs) {
+            let tb = tablebase(&tbs, &signature).unwrap();
+            write_tablebase(tb, &dir).unwrap();
+            let stats = tablebase_stats(tb);
+            println!("{:8} {:10} positions {:10} wins {:10} draws {:10} losses, mate in {} from {}",
+                signature, stats.positions, stats.wins, stats.draws, stats.losses,
+                stats.max_dtm, stats.max_dtm_fen);
+        }
+        return;
+    }
+    if args.len() > 4 && args[1] == "solve" {
+        // solve <mate | helpmate | selfmate | reflexmate> <n> <fen>: every
+        // key that solves in n moves of the side to move, with its tree.
+        let Some(stipulation) = stipulation_from_string(&args[2]) else {
+            panic!("Unexpected! usage: solve <mate | helpmate | selfmate | reflexmate> <n> <fen>");
+        };
+        let n: u32 = args[3].parse().unwrap();
+        let pos = decode_fen(args[4 ..].join(" "));
+        print!("{}", problem_solution_to_string(&solve_problem(&pos, stipulation, n)));
+        return;
+    }
+    if args.len() > 2 && args[1] == "testsuite" {
+        // testsuite <file> [depth <n> | time <ms>]: searches every position
+        // of an EPD suite, one second each unless told otherwise.
+        let mut limits = default_search_limits();
+        match (args.get(3).map(|s| s.as_str()), args.get(4)) {
+            (Some("depth"), Some(n)) => { limits.depth = Some(n.parse().unwrap()); },
+            (Some("time"), Some(ms)) => {
+                limits.movetime = Some(std::time::Duration::from_millis(ms.parse().unwrap()));
+            },
+            (None, _) => {
+                limits.movetime = Some(std::time::Duration::from_millis(1000));
+            },
+            _ => panic!("Unexpected! usage: testsuite <file> [depth <n> | time <ms>]"),
+        }
+        let file = std::fs::File::open(&args[2]).unwrap();
+        run_test_suite(std::io::BufReader::new(file), &limits, 64);
+        return;
+    }
+    // Without a subcommand, talk to a GUI on stdin and stdout, in XBoard
+    // protocol if its first word says so and UCI otherwise.
+    let mut stdin = std::io::stdin().lock();
+    let mut first = String::new();
+    stdin.read_line(&mut first).unwrap();
+    let is_xboard = first.trim() == "xboard";
+    let input = std::io::Read::chain(std::io::Cursor::new(first), stdin);
+    if is_xboard {
+        run_xboard(input);
+    } else {
+        run_uci(input);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A few malformed FENs and the errors they must produce.
+    #[test]
+    fn fen_errors() {
+        use FenErrorKind::*;
+        let cases = [
+            ("rnbqkbnr/ppppxppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                BadPieceLetter('x'), FenField::Placement, 13),
+            ("rnbqkbnr/pppppppp/4p4/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                RankOverflow, FenField::Placement, 20),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNRR w KQkq - 0 1",
+                RankOverflow, FenField::Placement, 43),
+            ("rnbqkbnr/pppppppp/7/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                RankUnderflow, FenField::Placement, 19),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR/8 w KQkq - 0 1",
+                TooManyRanks, FenField::Placement, 43),
+            ("rnbqkbnr/pppppppp/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                TooFewRanks, FenField::Placement, 41),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR x KQkq - 0 1",
+                BadActiveColor, FenField::ActiveColor, 44),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkx - 0 1",
+                BadCastling('x'), FenField::Castling, 49),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KKkq - 0 1",
+                BadCastling('K'), FenField::Castling, 47),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq e4 0 1",
+                BadEnPassant, FenField::EnPassant, 51),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - x 1",
+                NonNumericClock, FenField::Halfmoves, 53),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 -1",
+                NonNumericClock, FenField::Fullmoves, 55),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -",
+                MissingField, FenField::Halfmoves, 52),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w",
+                MissingField, FenField::Castling, 45),
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 x",
+                TooManyFields, FenField::Fullmoves, 57),
+            // Queens all round the rim and no king: 288 moves.
+            ("QQQQQQQQ/Q6Q/Q6Q/Q6Q/Q6Q/Q6Q/Q6Q/QQQQQQQQ w - - 0 1",
+                TooManyMoves, FenField::Placement, 0),
+        ];
+        for (fen, kind, field, offset) in cases {
+            assert_eq!(parse_fen(fen).err(), Some(fen_error(kind, field, offset)), "{}", fen);
+        }
+        let p = parse_fen_with_options(
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -", true).unwrap();
+        assert_eq!(encode_fen(&p),
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+    }
+
+    // Calls func on random legal positions: every position reached in random
+    // games from the perft suite positions, one seed per game.
+    fn for_each_random_position(mut func: impl FnMut(&mut Position)) {
+        for (fen, _, _) in PERFT_SUITE {
+            for seed in 1 ..= 50 {
+                let mut rng = seed;
+                let mut pos = decode_fen(String::from(fen));
+                for _ in 0 .. 200 {
+                    func(&mut pos);
+                    let list = generate_legal_moves(&pos);
+                    if list.len == 0 {
+                        break;
+                    }
+                    let pm = list.moves[random_next(&mut rng) as usize % list.len];
+                    let mov = unpack_move(&pos, pm);
+                    make_move(&mut pos, &mov);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fen_round_trips() {
+        for_each_random_position(|pos| {
+            let encoded = encode_fen(pos);
+            let decoded = decode_fen(encoded.clone());
+            assert_eq!(encode_fen(&decoded), encoded);
+            assert_eq!(decoded.placement, pos.placement, "{}", encoded);
+            assert_eq!(decoded.hash, pos.hash, "{}", encoded);
+        });
+    }
+
+    // Every legal move made and unmade leaves the position as it was, and
+    // the hash kept up by make_move is the one computed from scratch.
+    #[test]
+    fn make_unmake_round_trips() {
+        for_each_random_position(|pos| {
+            let fen = encode_fen(pos);
+            let (placement, piece_bb, color_bb, hash) =
+                (pos.placement, pos.piece_bb, pos.color_bb, pos.hash);
+            assert_eq!(hash, position_hash(pos), "{}", fen);
+            let list = generate_legal_moves(pos);
+            for &pm in move_list_slice(&list) {
+                let mov = unpack_move(pos, pm);
+                let undo = make_move(pos, &mov);
+                assert_eq!(pos.hash, position_hash(pos), "{} {}", fen, packed_move_to_uci_string(pm));
+                unmake_move(pos, &mov, undo);
+                assert_eq!(encode_fen(pos), fen);
+                assert_eq!(pos.placement, placement, "{}", fen);
+                assert_eq!((pos.piece_bb, pos.color_bb, pos.hash), (piece_bb, color_bb, hash), "{}", fen);
+            }
+        });
+    }
+
+    // Same as perft, but establishes legality by playing every pseudo-legal
+    // move and looking for a check. Slow, but independent of the pin and
+    // check logic in for_each_legal_move_from_position.
+    fn perft_slow(pos: &mut Position, depth: u32) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        let mut moves = Vec::new();
+        for_each_pseudo_legal_move_from_position(pos, |mov| moves.push(mov));
+        let mut nodes = 0;
+        for mov in moves.iter() {
+            let undo = make_move(pos, mov);
+            if !is_king_in_check(pos, true) {
+                nodes += perft_slow(pos, depth - 1);
+            }
+            unmake_move(pos, mov, undo);
+        }
+        nodes
+    }
+
+    // Positions with known perft totals, picked to exercise castling, en
+    // passant (including discovered checks along the rank), promotions and
+    // pins.
+    const PERFT_SUITE: [(&str, u32, u64); 6] = [
+        ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 4, 197281),
+        ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            3, 97862),
+        ("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 5, 674624),
+        ("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            4, 422333),
+        ("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8", 3, 62379),
+        ("r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            3, 89890),
+    ];
+
+    // Perft against the slow generator and the known totals.
+    #[test]
+    fn move_generation() {
+        for (fen, depth, expected) in PERFT_SUITE {
+            let mut pos = decode_fen(String::from(fen));
+            for d in 1 ..= depth {
+                let fast = perft(&mut pos, d);
+                let slow = perft_slow(&mut pos, d);
+                assert_eq!(fast, slow, "perft {} of {}", d, fen);
+            }
+            assert_eq!(perft(&mut pos, depth), expected, "perft {} of {}", depth, fen);
+        }
+    }
+
+    // Known illegal positions and the reasons validate must give.
+    #[test]
+    fn validation() {
+        use IllegalReason::*;
+        let cases = [
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", vec![]),
+            ("8/8/8/8/8/8/8/8 w - - 0 1", vec![
+                WrongKingCount(COLOR_WHITE, 0), WrongKingCount(COLOR_BLACK, 0)]),
+            ("k7/8/8/8/8/8/8/K1K5 w - - 0 1",
+                vec![WrongKingCount(COLOR_WHITE, 2)]),
+            ("k6P/8/8/8/8/8/8/K7 w - - 0 1", vec![PawnOnBackRank(63)]),
+            ("4k3/8/8/8/8/8/8/4K3 w KQ - 0 1", vec![
+                CastlingWithoutKingOrRook(CASTLING_WHITE_KINGSIDE),
+                CastlingWithoutKingOrRook(CASTLING_WHITE_QUEENSIDE)]),
+            ("4k3/8/8/8/8/8/8/4K3 w - e6 0 1", vec![ImplausibleEnPassant(44)]),
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", vec![]),
+            ("4k2R/8/8/8/8/8/8/4K3 b - - 0 1", vec![]),
+            ("4k2R/8/8/8/8/8/8/4K3 w - - 0 1", vec![OpponentInCheck]),
+            ("4k3/8/8/8/1b6/8/8/r3K2q w - - 0 1", vec![TooManyCheckers(3)]),
+            ("4k3/8/8/8/8/3n1n2/8/4K3 w - - 0 1", vec![ImpossibleDoubleCheck]),
+            ("4k3/8/8/8/4r3/8/8/4K2q w - - 0 1", vec![]),
+        ];
+        for (fen, expected) in cases {
+            let result = validate(&decode_fen(String::from(fen)));
+            match result {
+                Ok(()) => assert!(expected.is_empty(), "{}", fen),
+                Err(reasons) => assert_eq!(reasons, expected, "{}", fen),
+            }
+        }
+    }
+
+    // Variants parse_san and parse_uci must accept, and inputs they must
+    // reject.
+    #[test]
+    fn move_parsing() {
+        let accepted = [
+            ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "0-0", "e1g1"),
+            ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "O-O-O", "e1c1"),
+            ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "Rxh8", "h1h8"),
+            ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "Rh1xh8+!", "h1h8"),
+            ("8/4P3/8/8/8/8/k7/4K3 w - - 0 1", "e8Q", "e7e8q"),
+            ("8/4P3/8/8/8/8/k7/4K3 w - - 0 1", "e8=N", "e7e8n"),
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "exd6 e.p.", "e5d6"),
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "ed6", "e5d6"),
+            ("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "Nbd2", "b1d2"),
+            ("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "Nf1-e3", "f1e3"),
+        ];
+        for (fen, san, uci) in accepted {
+            let pos = decode_fen(String::from(fen));
+            let from_san = parse_san(&pos, san).unwrap();
+            let from_uci = parse_uci(&pos, uci).unwrap();
+            assert_eq!(pack_move(&pos, &from_san), pack_move(&pos, &from_uci), "{}", san);
+        }
+        let pos = decode_fen(String::from("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1"));
+        assert!(matches!(parse_san(&pos, "Nd2"), Err(MoveParseError::AmbiguousMove(..))));
+        assert!(matches!(parse_san(&pos, "Nd3"), Err(MoveParseError::IllegalMove(..))));
+        assert!(matches!(parse_san(&pos, "Zd2"), Err(MoveParseError::BadSyntax(..))));
+        assert!(matches!(parse_san(&pos, ""), Err(MoveParseError::BadSyntax(..))));
+        assert!(matches!(parse_uci(&pos, "b1d3"), Err(MoveParseError::IllegalMove(..))));
+        assert!(matches!(parse_uci(&pos, "b1d2x"), Err(MoveParseError::BadSyntax(..))));
+        assert!(matches!(parse_uci(&pos, "i1d2"), Err(MoveParseError::BadSyntax(..))));
+        let pos = decode_fen(String::from("8/4P3/8/8/8/8/k7/4K3 w - - 0 1"));
+        assert!(matches!(parse_san(&pos, "e8"), Err(MoveParseError::IllegalMove(..))));
+        assert!(matches!(parse_san(&pos, "e8q"), Err(MoveParseError::BadSyntax(..))));
+        assert!(matches!(parse_san(&pos, "e8=n"), Err(MoveParseError::BadSyntax(..))));
+    }
+
+    // Replays games given as SAN, requiring every move to be printed exactly
+    // once among the legal moves, then checks hand-picked positions for
+    // disambiguation, castling, promotion and en passant.
+    #[test]
+    fn san() {
+        let games = [
+            // Morphy - Duke Karl / Count Isouard, Paris 1858.
+            "e4 e5 Nf3 d6 d4 Bg4 dxe5 Bxf3 Qxf3 dxe5 Bc4 Nf6 Qb3 Qe7 Nc3 c6 Bg5 \
+             b5 Nxb5 cxb5 Bxb5+ Nbd7 O-O-O Rd8 Rxd7 Rxd7 Rd1 Qe6 Bxd7+ Nxd7 Qb8+ \
+             Nxb8 Rd8#",
+            // Levitsky - Marshall, Breslau 1912.
+            "d4 e6 e4 d5 Nc3 c5 Nf3 Nc6 exd5 exd5 Be2 Nf6 O-O Be7 Bg5 O-O dxc5 \
+             Be6 Nd4 Bxc5 Nxe6 fxe6 Bg4 Qd6 Bh3 Rae8 Qd2 Bb4 Bxf6 Rxf6 Rad1 Qc5 \
+             Qe2 Bxc3 bxc3 Qxc3 Rxd5 Nd4 Qh5 Ref8 Re5 Rh6 Qg5 Rxh3 Rc5 Qg3",
+            // Fischer - Myagmarsuren, Sousse 1967.
+            "e4 e6 d3 d5 Nd2 Nf6 g3 c5 Bg2 Nc6 Ngf3 Be7 O-O O-O e5 Nd7 Re1 b5 \
+             Nf1 b4 h4 a5 Bf4 a4 a3 bxa3 bxa3 Na5 Ne3 Ba6 Bh3 d4 Nf1 Nb6 Ng5 Nd5 \
+             Bd2 Bxg5 Bxg5 Qd7 Qh5 Rfc8 Nd2 Nc3 Bf6 Qe8 Ne4 g6 Qg5 Nxe4 Rxe4 c4 \
+             h5 cxd3 Rh4 Ra7 Bg2 dxc2 Qh6 Qf8 Qxh7+",
+        ];
+        for game in games {
+            let mut pos = decode_fen(String::from(
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"));
+            for san in game.split_whitespace() {
+                let mut found = Vec::new();
+                for_each_legal_move_from_position(&pos, |mov| {
+                    if move_to_string(&mov, &pos) == san {
+                        found.push(mov);
+                    }
+                });
+                assert_eq!(found.len(), 1, "{} in {}", san, encode_fen(&pos));
+                make_move(&mut pos, &found[0]);
+            }
+        }
+        let cases = [
+            ("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", vec!["Nbd2", "Nfd2", "Ne3"]),
+            ("4k3/8/8/R7/8/8/8/R3K3 w - - 0 1", vec!["R1a3", "R5a3", "Rb1"]),
+            ("4k3/8/8/8/8/Q7/8/Q1Q1K3 w - - 0 1", vec!["Qa1b2", "Qab1", "Q3a2"]),
+            ("8/4P3/8/8/8/8/k7/4K3 w - - 0 1",
+                vec!["e8=Q", "e8=R", "e8=B", "e8=N"]),
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", vec!["exd6", "e6"]),
+            ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1",
+                vec!["O-O", "O-O-O", "Rxa8+", "Rxh8+"]),
+            ("rnbqkbnr/pppp1ppp/8/4p3/6P1/5P2/PPPPP2P/RNBQKBNR b KQkq - 0 2",
+                vec!["Qh4#"]),
+        ];
+        for (fen, expected) in cases {
+            let strings = legal_move_strings(&decode_fen(String::from(fen)));
+            for san in expected {
+                assert!(strings.iter().any(|s| s == san), "{} in {}", san, fen);
+            }
+        }
     }
-    println!("Done");
 }
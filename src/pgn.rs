@@ -0,0 +1,763 @@
+// Streaming PGN reader. Games are parsed one at a time from any BufRead,
+// so memory use is bounded by the largest single game, and a game that
+// fails to parse is reported and skipped without affecting the rest.
+
+use std::io::BufRead;
+
+use crate::*;
+
+pub const STARTING_FEN: &str =
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+// One move of the movetext and everything annotating it.
+#[derive(Debug, PartialEq)]
+pub struct PgnNode {
+    // As written in the file.
+    pub san: String,
+    pub mov: PackedMove,
+    pub nags: Vec<u8>,
+    // Comments following the move.
+    pub comments: Vec<String>,
+    // Alternatives to this move, each starting from the position before
+    // it.
+    pub variations: Vec<PgnLine>,
+}
+
+// A sequence of moves: the main line or a variation.
+#[derive(Debug, PartialEq)]
+pub struct PgnLine {
+    // Comments before the first move.
+    pub comments: Vec<String>,
+    pub moves: Vec<PgnNode>,
+}
+
+pub struct PgnGame {
+    // In file order.
+    pub tags: Vec<(String, String)>,
+    pub start_fen: String,
+    pub line: PgnLine,
+    // "1-0", "0-1", "1/2-1/2" or "*".
+    pub result: String,
+    // The start position followed by the position after each main line
+    // move.
+    pub positions: Vec<Position>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PgnErrorKind {
+    Syntax(String),
+    BadFen(FenError),
+    BadMove(MoveParseError),
+    Io(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgnError {
+    pub kind: PgnErrorKind,
+    // 1-based line in the input where the problem was found.
+    pub line: usize,
+}
+
+impl std::fmt::Display for PgnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.kind {
+            PgnErrorKind::Syntax(s) => write!(f, "line {}: {}", self.line, s),
+            PgnErrorKind::BadFen(e) => write!(f, "line {}: bad FEN tag: {}", self.line, e),
+            PgnErrorKind::BadMove(e) => write!(f, "line {}: {}", self.line, e),
+            PgnErrorKind::Io(e) => write!(f, "line {}: read error: {}", self.line, e),
+        }
+    }
+}
+
+impl std::error::Error for PgnError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PgnToken {
+    TagOpen,
+    TagClose,
+    // Tag names, SAN moves and move numbers.
+    Symbol(String),
+    Str(String),
+    Comment(String),
+    Nag(u8),
+    VariationOpen,
+    VariationClose,
+    GameResult(String),
+}
+
+struct PgnLexer<R: BufRead> {
+    reader: R,
+    chars: Vec<char>,
+    i: usize,
+    line: usize,
+    peeked: Option<PgnToken>,
+    io_error: Option<String>,
+    // Whether a blank line came before the last token read.
+    blank_line_before: bool,
+}
+
+fn pgn_error(kind: PgnErrorKind, line: usize) -> PgnError {
+    PgnError{kind, line}
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_+#=:-/.".contains(c)
+}
+
+// Makes the next line current. Returns false at the end of the input.
+fn lexer_next_line<R: BufRead>(lexer: &mut PgnLexer<R>) -> bool {
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        match lexer.reader.read_line(&mut buf) {
+            Ok(0) => { return false; },
+            Ok(_) => {},
+            Err(e) => {
+                lexer.io_error = Some(e.to_string());
+                return false;
+            },
+        }
+        lexer.line += 1;
+        // Lines starting with '%' are escaped and ignored.
+        if !buf.starts_with('%') {
+            break;
+        }
+    }
+    lexer.chars = buf.chars().collect();
+    lexer.i = 0;
+    true
+}
+
+fn annotation_to_nag(s: &str) -> Option<u8> {
+    match s {
+        "!" => Some(1),
+        "?" => Some(2),
+        "!!" => Some(3),
+        "??" => Some(4),
+        "!?" => Some(5),
+        "?!" => Some(6),
+        _ => None,
+    }
+}
+
+fn lexer_read_token<R: BufRead>(lexer: &mut PgnLexer<R>) -> Option<PgnToken> {
+    lexer.blank_line_before = false;
+    loop {
+        while lexer.i < lexer.chars.len() && lexer.chars[lexer.i].is_whitespace() {
+            lexer.i += 1;
+        }
+        if lexer.i < lexer.chars.len() {
+            break;
+        }
+        if !lexer_next_line(lexer) {
+            return None;
+        }
+        if lexer.chars.iter().all(|c| c.is_whitespace()) {
+            lexer.blank_line_before = true;
+        }
+    }
+    let c = lexer.chars[lexer.i];
+    lexer.i += 1;
+    match c {
+        '[' => Some(PgnToken::TagOpen),
+        ']' => Some(PgnToken::TagClose),
+        '(' => Some(PgnToken::VariationOpen),
+        ')' => Some(PgnToken::VariationClose),
+        '*' => Some(PgnToken::GameResult(String::from("*"))),
+        ';' => {
+            let text: String = lexer.chars[lexer.i ..].iter().collect();
+            lexer.i = lexer.chars.len();
+            Some(PgnToken::Comment(String::from(text.trim())))
+        },
+        '{' => {
+            let mut text = String::new();
+            loop {
+                if lexer.i >= lexer.chars.len() {
+                    if !lexer_next_line(lexer) {
+                        break;
+                    }
+                    continue;
+                }
+                let c = lexer.chars[lexer.i];
+                lexer.i += 1;
+                if c == '}' {
+                    break;
+                }
+                text.push(c);
+            }
+            // Line breaks and runs of spaces are not significant.
+            let words: Vec<&str> = text.split_whitespace().collect();
+            Some(PgnToken::Comment(words.join(" ")))
+        },
+        '"' => {
+            let mut text = String::new();
+            while lexer.i < lexer.chars.len() {
+                let c = lexer.chars[lexer.i];
+                lexer.i += 1;
+                if c == '\\' && lexer.i < lexer.chars.len() {
+                    text.push(lexer.chars[lexer.i]);
+                    lexer.i += 1;
+                } else if c == '"' {
+                    break;
+                } else {
+                    text.push(c);
+                }
+            }
+            Some(PgnToken::Str(text))
+        },
+        '$' => {
+            let start = lexer.i;
+            while lexer.i < lexer.chars.len() && lexer.chars[lexer.i].is_ascii_digit() {
+                lexer.i += 1;
+            }
+            let digits: String = lexer.chars[start .. lexer.i].iter().collect();
+            Some(PgnToken::Nag(digits.parse().unwrap_or(0)))
+        },
+        '!' | '?' => {
+            let start = lexer.i - 1;
+            while lexer.i < lexer.chars.len() && "!?".contains(lexer.chars[lexer.i]) {
+                lexer.i += 1;
+            }
+            let s: String = lexer.chars[start .. lexer.i].iter().collect();
+            Some(PgnToken::Nag(annotation_to_nag(&s).unwrap_or(0)))
+        },
+        _ if is_symbol_char(c) => {
+            let start = lexer.i - 1;
+            while lexer.i < lexer.chars.len() && is_symbol_char(lexer.chars[lexer.i]) {
+                lexer.i += 1;
+            }
+            let s: String = lexer.chars[start .. lexer.i].iter().collect();
+            if s == "1-0" || s == "0-1" || s == "1/2-1/2" {
+                Some(PgnToken::GameResult(s))
+            } else {
+                Some(PgnToken::Symbol(s))
+            }
+        },
+        _ => Some(PgnToken::Symbol(String::from(c))),
+    }
+}
+
+fn lexer_next<R: BufRead>(lexer: &mut PgnLexer<R>) -> Option<PgnToken> {
+    match lexer.peeked.take() {
+        Some(token) => Some(token),
+        None => lexer_read_token(lexer),
+    }
+}
+
+fn lexer_peek<R: BufRead>(lexer: &mut PgnLexer<R>) -> Option<&PgnToken> {
+    if lexer.peeked.is_none() {
+        lexer.peeked = lexer_read_token(lexer);
+    }
+    lexer.peeked.as_ref()
+}
+
+// Strips a leading move number ("12.", "12...") from a symbol. Returns an
+// empty string for a bare move number and for "e.p." markers.
+fn strip_move_number(symbol: &str) -> &str {
+    if symbol == "e.p." {
+        return "";
+    }
+    let digits_end = symbol.find(|c: char| !c.is_ascii_digit()).unwrap_or(symbol.len());
+    let rest = &symbol[digits_end ..];
+    if digits_end > 0 && (rest.is_empty() || rest.starts_with('.')) {
+        rest.trim_start_matches('.')
+    } else {
+        symbol
+    }
+}
+
+// Parses moves, starting from pos, up to the end of the variation (depth
+// > 0) or the game result (depth 0). positions collects the position after
+// every move, if given.
+fn parse_pgn_line<R: BufRead>(
+    lexer: &mut PgnLexer<R>, pos: &Position, depth: u32,
+    mut positions: Option<&mut Vec<Position>>, result: &mut String,
+) -> Result<PgnLine, PgnError> {
+    let mut line = PgnLine{comments: Vec::new(), moves: Vec::new()};
+    let mut cur = copy_position(pos);
+    let mut before_last = copy_position(pos);
+    loop {
+        if depth == 0 && lexer_peek(lexer) == Some(&PgnToken::TagOpen) {
+            // The next game starts without this one having a result.
+            *result = String::from("*");
+            return Ok(line);
+        }
+        let Some(token) = lexer_next(lexer) else {
+            if depth > 0 {
+                return Err(pgn_error(
+                    PgnErrorKind::Syntax(String::from("unterminated variation")),
+                    lexer.line));
+            }
+            *result = String::from("*");
+            return Ok(line);
+        };
+        match token {
+            PgnToken::Symbol(symbol) => {
+                let san = strip_move_number(&symbol);
+                if san.is_empty() {
+                    continue;
+                }
+                let mov = parse_san(&cur, san)
+                    .map_err(|e| pgn_error(PgnErrorKind::BadMove(e), lexer.line))?;
+                line.moves.push(PgnNode{
+                    san: String::from(san),
+                    mov: pack_move(&cur, &mov),
+                    nags: Vec::new(),
+                    comments: Vec::new(),
+                    variations: Vec::new(),
+                });
+                before_last = copy_position(&cur);
+                make_move(&mut cur, &mov);
+                if let Some(positions) = positions.as_deref_mut() {
+                    positions.push(copy_position(&cur));
+                }
+            },
+            PgnToken::Nag(nag) => {
+                if let Some(node) = line.moves.last_mut() {
+                    node.nags.push(nag);
+                }
+            },
+            PgnToken::Comment(text) => {
+                match line.moves.last_mut() {
+                    Some(node) => { node.comments.push(text); },
+                    None => { line.comments.push(text); },
+                }
+            },
+            PgnToken::VariationOpen => {
+                if line.moves.is_empty() {
+                    return Err(pgn_error(
+                        PgnErrorKind::Syntax(String::from("variation before any move")),
+                        lexer.line));
+                }
+                let variation =
+                    parse_pgn_line(lexer, &before_last, depth + 1, None, result)?;
+                line.moves.last_mut().unwrap().variations.push(variation);
+            },
+            PgnToken::VariationClose => {
+                if depth == 0 {
+                    return Err(pgn_error(
+                        PgnErrorKind::Syntax(String::from("unmatched ')'")),
+                        lexer.line));
+                }
+                return Ok(line);
+            },
+            PgnToken::GameResult(r) => {
+                if depth > 0 {
+                    return Err(pgn_error(
+                        PgnErrorKind::Syntax(String::from("result inside variation")),
+                        lexer.line));
+                }
+                *result = r;
+                return Ok(line);
+            },
+            token => {
+                return Err(pgn_error(
+                    PgnErrorKind::Syntax(format!("unexpected {:?} in movetext", token)),
+                    lexer.line));
+            },
+        }
+    }
+}
+
+// Consumes tokens up to the end of the current game, so that the next
+// game can be read after an error: past its result, or up to the tag that
+// starts the next game. After an error in the tags, inside the tag section
+// only a tag after a blank line starts the next game.
+fn skip_to_game_end<R: BufRead>(lexer: &mut PgnLexer<R>, in_tags: bool) {
+    let mut depth: u32 = 0;
+    let mut in_tags = in_tags;
+    let mut in_tag = in_tags;
+    loop {
+        if lexer_peek(lexer) == Some(&PgnToken::TagOpen) && depth == 0 && (!in_tags || lexer.blank_line_before) {
+            return;
+        }
+        match lexer_next(lexer) {
+            None => { return; },
+            Some(PgnToken::TagOpen) => { in_tag = true; },
+            Some(PgnToken::TagClose) => { in_tag = false; },
+            Some(PgnToken::VariationOpen) => {
+                depth += 1;
+                in_tags = false;
+            },
+            // An error inside a variation leaves its close unmatched.
+            Some(PgnToken::VariationClose) => { depth = depth.saturating_sub(1); },
+            Some(PgnToken::GameResult(_)) if depth == 0 => { return; },
+            Some(_) => {
+                if !in_tag {
+                    in_tags = false;
+                }
+            },
+        }
+    }
+}
+
+fn parse_pgn_tag<R: BufRead>(
+    lexer: &mut PgnLexer<R>
+) -> Result<(String, String), PgnError> {
+    let syntax = |line| pgn_error(
+        PgnErrorKind::Syntax(String::from("malformed tag pair")), line);
+    lexer_next(lexer);
+    let Some(PgnToken::Symbol(name)) = lexer_next(lexer) else {
+        return Err(syntax(lexer.line));
+    };
+    let Some(PgnToken::Str(value)) = lexer_next(lexer) else {
+        return Err(syntax(lexer.line));
+    };
+    if lexer_next(lexer) != Some(PgnToken::TagClose) {
+        return Err(syntax(lexer.line));
+    }
+    Ok((name, value))
+}
+
+// Reads one game. Returns None at the end of the input.
+fn parse_pgn_game<R: BufRead>(
+    lexer: &mut PgnLexer<R>
+) -> Option<Result<PgnGame, PgnError>> {
+    let mut tags = Vec::new();
+    while lexer_peek(lexer) == Some(&PgnToken::TagOpen) {
+        match parse_pgn_tag(lexer) {
+            Ok(tag) => { tags.push(tag); },
+            Err(e) => {
+                skip_to_game_end(lexer, true);
+                return Some(Err(e));
+            },
+        }
+    }
+    if tags.is_empty() && lexer_peek(lexer).is_none() {
+        if let Some(e) = lexer.io_error.take() {
+            return Some(Err(pgn_error(PgnErrorKind::Io(e), lexer.line)));
+        }
+        return None;
+    }
+    let start_fen = match tags.iter().find(|(name, _)| name == "FEN") {
+        Some((_, fen)) => fen.clone(),
+        None => String::from(STARTING_FEN),
+    };
+    let start = match parse_fen_with_options(&start_fen, true) {
+        Ok(p) => p,
+        Err(e) => {
+            let line = lexer.line;
+            skip_to_game_end(lexer, false);
+            return Some(Err(pgn_error(PgnErrorKind::BadFen(e), line)));
+        },
+    };
+    let mut positions = vec![copy_position(&start)];
+    let mut result = String::new();
+    match parse_pgn_line(lexer, &start, 0, Some(&mut positions), &mut result) {
+        Ok(line) => Some(Ok(PgnGame{tags, start_fen, line, result, positions})),
+        Err(e) => {
+            skip_to_game_end(lexer, false);
+            Some(Err(e))
+        },
+    }
+}
+
+// Calls func with every game in reader, in order. A game that cannot be
+// parsed is passed as an Err and reading continues with the next one; a
+// read error is passed as an Err and ends reading.
+pub fn for_each_pgn_game<R: BufRead>(
+    reader: R, mut func: impl FnMut(Result<PgnGame, PgnError>)
+) {
+    let mut lexer = PgnLexer{
+        reader,
+        chars: Vec::new(),
+        i: 0,
+        line: 0,
+        peeked: None,
+        io_error: None,
+        blank_line_before: false,
+    };
+    while let Some(game) = parse_pgn_game(&mut lexer) {
+        let stop = matches!(&game, Err(PgnError{kind: PgnErrorKind::Io(_), ..}));
+        func(game);
+        if stop {
+            break;
+        }
+    }
+}
+
+pub fn pgn_tag<'a>(game: &'a PgnGame, name: &str) -> Option<&'a str> {
+    game.tags.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+const SEVEN_TAG_ROSTER: [(&str, &str); 7] = [
+    ("Event", "?"),
+    ("Site", "?"),
+    ("Date", "????.??.??"),
+    ("Round", "?"),
+    ("White", "?"),
+    ("Black", "?"),
+    ("Result", "*"),
+];
+
+// Export format limits movetext lines to 79 characters.
+const PGN_LINE_WIDTH: usize = 79;
+
+fn escape_tag_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Movetext is built as a list of words that are later wrapped into lines.
+// Parentheses stick to the word next to them.
+struct MovetextWords {
+    words: Vec<String>,
+    pending_prefix: String,
+}
+
+fn push_word(mw: &mut MovetextWords, word: String) {
+    let prefix = std::mem::take(&mut mw.pending_prefix);
+    mw.words.push(prefix + &word);
+}
+
+fn push_comment_words(mw: &mut MovetextWords, comment: &str) {
+    // '}' would end the comment early.
+    let comment = comment.replace('}', ")");
+    let words: Vec<&str> = comment.split_whitespace().collect();
+    if words.is_empty() {
+        push_word(mw, String::from("{}"));
+        return;
+    }
+    for (i, word) in words.iter().enumerate() {
+        let mut w = String::from(*word);
+        if i == 0 {
+            w = String::from("{") + &w;
+        }
+        if i == words.len() - 1 {
+            w.push('}');
+        }
+        push_word(mw, w);
+    }
+}
+
+fn push_pgn_line_words(mw: &mut MovetextWords, line: &PgnLine, pos: &Position) {
+    let mut cur = copy_position(pos);
+    // Black moves get a number at the start of a line and after anything
+    // that interrupts the move sequence.
+    let mut need_number = true;
+    for comment in line.comments.iter() {
+        push_comment_words(mw, comment);
+    }
+    for node in line.moves.iter() {
+        let mov = unpack_move(&cur, node.mov);
+        if cur.active_color == COLOR_WHITE {
+            push_word(mw, format!("{}.", cur.fullmoves));
+        } else if need_number {
+            push_word(mw, format!("{}...", cur.fullmoves));
+        }
+        push_word(mw, move_to_string(&mov, &cur));
+        need_number = false;
+        for nag in node.nags.iter() {
+            push_word(mw, format!("${}", nag));
+        }
+        for comment in node.comments.iter() {
+            push_comment_words(mw, comment);
+            need_number = true;
+        }
+        for variation in node.variations.iter() {
+            mw.pending_prefix.push('(');
+            push_pgn_line_words(mw, variation, &cur);
+            if mw.pending_prefix.is_empty() {
+                mw.words.last_mut().unwrap().push(')');
+            } else {
+                // Nothing was written inside the parentheses.
+                mw.pending_prefix.push(')');
+            }
+            need_number = true;
+        }
+        make_move(&mut cur, &mov);
+    }
+}
+
+// Writes game in PGN export format: the seven tag roster followed by the
+// other tags sorted by name, then the movetext wrapped at
+// PGN_LINE_WIDTH, with SAN regenerated by move_to_string.
+pub fn write_pgn(game: &PgnGame) -> String {
+    let mut result = String::new();
+    for (name, default) in SEVEN_TAG_ROSTER {
+        let value = if name == "Result" {
+            game.result.as_str()
+        } else {
+            pgn_tag(game, name).unwrap_or(default)
+        };
+        result += &format!("[{} \"{}\"]\n", name, escape_tag_value(value));
+    }
+    let mut other_tags: Vec<(String, String)> = game.tags.iter()
+        .filter(|(name, _)| SEVEN_TAG_ROSTER.iter().all(|(n, _)| n != name))
+        .filter(|(name, _)| name != "SetUp" && name != "FEN")
+        .cloned()
+        .collect();
+    if game.start_fen != STARTING_FEN {
+        other_tags.push((String::from("FEN"), game.start_fen.clone()));
+        other_tags.push((String::from("SetUp"), String::from("1")));
+    }
+    other_tags.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, value) in other_tags {
+        result += &format!("[{} \"{}\"]\n", name, escape_tag_value(&value));
+    }
+    result.push('\n');
+    let mut mw = MovetextWords{words: Vec::new(), pending_prefix: String::new()};
+    let start = parse_fen_with_options(&game.start_fen, true).unwrap();
+    push_pgn_line_words(&mut mw, &game.line, &start);
+    push_word(&mut mw, game.result.clone());
+    let mut line_len = 0;
+    for word in mw.words {
+        if line_len > 0 && line_len + 1 + word.len() > PGN_LINE_WIDTH {
+            result.push('\n');
+            line_len = 0;
+        }
+        if line_len > 0 {
+            result.push(' ');
+            line_len += 1;
+        }
+        line_len += word.len();
+        result += &word;
+    }
+    result + "\n\n"
+}
+
+// Equality of the move trees, ignoring how the SAN was spelled.
+fn same_pgn_line(a: &PgnLine, b: &PgnLine) -> bool {
+    a.comments == b.comments
+        && a.moves.len() == b.moves.len()
+        && a.moves.iter().zip(b.moves.iter()).all(|(x, y)| {
+            x.mov == y.mov
+                && x.nags == y.nags
+                && x.comments == y.comments
+                && x.variations.len() == y.variations.len()
+                && x.variations.iter().zip(y.variations.iter())
+                    .all(|(v, w)| same_pgn_line(v, w))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small database exercising the syntax, with a broken game in the
+    // middle, read as expected.
+    #[test]
+    fn pgn_reading() {
+        let text = r#"
+[Event "Casual"]
+[White "Morphy, Paul"]
+[Black "Duke Karl / Count Isouard"]
+[Result "1-0"]
+
+{Opening comment} 1. e4 e5 2. Nf3 d6 3. d4 Bg4 $2 {Weak.} (3... exd4 4. Qxd4
+(4. Nxd4) 4... Nc6) 4. dxe5 Bxf3 5. Qxf3 dxe5 6. Bc4 Nf6 7. Qb3 Qe7 8. Nc3 c6
+9. Bg5 b5?! 10. Nxb5 cxb5 11. Bxb5+ Nbd7 12. O-O-O Rd8 13. Rxd7 Rxd7 14. Rd1
+Qe6 15. Bxd7+ Nxd7 16. Qb8+ ; the queen sacrifice
+Nxb8 17. Rd8# 1-0
+
+[Event "Broken"]
+[Result "*"]
+
+1. e4 e5 2. Ke3 (2. Nf3 {inside (parens) 1-0}) Nc6 *
+
+% escaped line
+[Event "From FEN"]
+[SetUp "1"]
+[FEN "4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1"]
+[Result "1/2-1/2"]
+
+1. exd6 e.p. Kd7 2.Ke2 Kxd6 1/2-1/2
+"#;
+        let mut games = Vec::new();
+        for_each_pgn_game(text.as_bytes(), |game| games.push(game));
+        assert_eq!(games.len(), 3);
+        let morphy = games[0].as_ref().unwrap();
+        assert_eq!(pgn_tag(morphy, "White"), Some("Morphy, Paul"));
+        assert_eq!(morphy.result, "1-0");
+        assert_eq!(morphy.line.comments, vec!["Opening comment"]);
+        assert_eq!(morphy.line.moves.len(), 33);
+        assert_eq!(morphy.positions.len(), 34);
+        assert_eq!(morphy.line.moves[5].nags, vec![2]);
+        assert_eq!(morphy.line.moves[5].comments, vec!["Weak."]);
+        assert_eq!(morphy.line.moves[5].variations[0].moves.len(), 3);
+        assert_eq!(morphy.line.moves[5].variations[0].moves[1].variations.len(), 1);
+        assert_eq!(morphy.line.moves[17].nags, vec![6]);
+        assert_eq!(morphy.line.moves[30].comments, vec!["the queen sacrifice"]);
+        let last = morphy.positions.last().unwrap();
+        assert!(is_king_in_check(last, false) && generate_legal_moves(last).len == 0);
+        assert!(matches!(games[1],
+            Err(PgnError{kind: PgnErrorKind::BadMove(_), line: 16})));
+        let from_fen = games[2].as_ref().unwrap();
+        assert_eq!(from_fen.result, "1/2-1/2");
+        assert_eq!(encode_fen(from_fen.positions.last().unwrap()),
+            "8/8/3k4/8/8/8/4K3/8 w - - 0 3");
+
+        // A malformed tag skips the rest of its game, and an error inside a
+        // variation of a game with no result leaves the next game alone.
+        let text = r#"
+[Event Casual]
+[White "Morphy, Paul"]
+[Result "1-0"]
+
+1. e4 e5 1-0
+
+[Event "Second"]
+[Result "*"]
+
+1. d4 *
+
+[Event "Third"]
+
+1. e4 (1. d4 Zz9) e5
+
+[Event "Fourth"]
+[Result "*"]
+
+1. c4 *
+"#;
+        let mut games = Vec::new();
+        for_each_pgn_game(text.as_bytes(), |game| games.push(game));
+        assert_eq!(games.len(), 4);
+        assert!(matches!(games[0], Err(PgnError{kind: PgnErrorKind::Syntax(_), line: 2})));
+        assert_eq!(pgn_tag(games[1].as_ref().unwrap(), "Event"), Some("Second"));
+        assert!(matches!(games[2], Err(PgnError{kind: PgnErrorKind::BadMove(_), ..})));
+        assert_eq!(pgn_tag(games[3].as_ref().unwrap(), "Event"), Some("Fourth"));
+    }
+
+    // Writes the games of pgn_reading's database plus one with a long
+    // comment, and checks that the output re-parses to the same games and is
+    // a fixed point of reading and writing.
+    #[test]
+    fn pgn_writing() {
+        let text = r#"
+[White "Morphy, Paul"]
+[Black "Duke Karl / Count Isouard"]
+[Annotator "Nobody"]
+[Result "1-0"]
+
+{Opening comment} 1. e4 e5 2. Nf3 d6 3. d4 Bg4 $2 {Weak.} (3... exd4 4. Qxd4
+(4. Nxd4) 4... Nc6) 4. dxe5 Bxf3 5. Qxf3 dxe5 6. Bc4 Nf6 7. Qb3 Qe7 8. Nc3 c6
+9. Bg5 b5?! 10. Nxb5 cxb5 11. Bxb5+ Nbd7 12. O-O-O Rd8 13. Rxd7 Rxd7 14. Rd1
+Qe6 15. Bxd7+ Nxd7 16. Qb8+ {The queen sacrifice that the whole game has been
+building up to, and which forces mate in one whatever Black does next, since
+the knight is the only piece that can capture.} Nxb8 17. Rd8# 1-0
+
+[FEN "4k3/8/8/3pP3/8/8/8/4K3 b - - 0 1"]
+[SetUp "1"]
+
+1... Kd7 2. e6+ (2. Kd2 Kc6) 2... Kxe6 1/2-1/2
+"#;
+        let mut originals = Vec::new();
+        for_each_pgn_game(text.as_bytes(), |game| originals.push(game.unwrap()));
+        assert_eq!(originals.len(), 2);
+        for original in originals.iter() {
+            let written = write_pgn(original);
+            assert!(written.lines().all(|l| l.len() <= PGN_LINE_WIDTH), "{}", written);
+            let mut reread = Vec::new();
+            for_each_pgn_game(written.as_bytes(), |game| reread.push(game.unwrap()));
+            assert_eq!(reread.len(), 1);
+            assert!(same_pgn_line(&original.line, &reread[0].line), "{}", written);
+            assert_eq!(reread[0].result, original.result);
+            assert_eq!(reread[0].start_fen, original.start_fen);
+            assert_eq!(write_pgn(&reread[0]), written);
+        }
+        let written = write_pgn(&originals[1]);
+        assert!(written.starts_with("[Event \"?\"]\n[Site \"?\"]\n"), "{}", written);
+        assert!(written.ends_with(
+            "\n1... Kd7 2. e6+ (2. Kd2 Kc6) 2... Kxe6 1/2-1/2\n\n"), "{}", written);
+    }
+}
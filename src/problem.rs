@@ -0,0 +1,435 @@
+// Solving of chess problems in the orthodox stipulations, all counted in
+// moves of the side to move, the first mover:
+//
+// - mate in N: it forces mate in at most N moves against every defence;
+// - helpmate in N: both sides cooperate so that the first mover is mated
+//   by the other side's Nth move, exactly;
+// - selfmate in N: it forces the other side, which tries to avoid it, to
+//   mate it in at most N moves;
+// - reflexmate in N: a selfmate where either side must mate whenever it
+//   can.
+//
+// The search is exhaustive, with no pruning but the one that is sound
+// (only checks can mate in one), and finds every key move, so that a
+// problem with more than one solution is shown cooked. Along with the
+// keys it gives the threat of each and the full tree of defences and
+// continuations, marking duals where the first mover has more than one
+// way on. Helpmates are listed as their solution lines instead.
+
+use std::collections::HashMap;
+
+use crate::*;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stipulation {
+    Mate,
+    Helpmate,
+    Selfmate,
+    Reflexmate,
+}
+
+pub struct ProblemNode {
+    pub mov: PackedMove,
+    pub san: String,
+    // For a move of the first mover, every reply that keeps to the
+    // stipulation: all defences, or in a helpmate the cooperating moves.
+    // For a reply, every continuation that reaches it soonest.
+    pub children: Vec<ProblemNode>,
+}
+
+pub struct ProblemSolution {
+    pub stipulation: Stipulation,
+    pub moves: u32,
+    // The first moves that solve the problem, each with its tree.
+    pub keys: Vec<ProblemNode>,
+    // The continuations that would solve in moves - 1 if the other side
+    // could pass after the key, in the order of the keys.
+    pub threats: Vec<Vec<String>>,
+    // The fewest moves that solve, if fewer than asked for.
+    pub short_mate: Option<u32>,
+    pub duals: usize,
+}
+
+struct Solver {
+    stipulation: Stipulation,
+    // Whether the first mover solves in n from a position, by hash and n.
+    proven: HashMap<(u64, u32), bool>,
+}
+
+pub fn stipulation_from_string(s: &str) -> Option<Stipulation> {
+    match s {
+        "mate" => Some(Stipulation::Mate),
+        "helpmate" => Some(Stipulation::Helpmate),
+        "selfmate" => Some(Stipulation::Selfmate),
+        "reflexmate" => Some(Stipulation::Reflexmate),
+        _ => None,
+    }
+}
+
+pub fn stipulation_to_string(stipulation: Stipulation) -> &'static str {
+    match stipulation {
+        Stipulation::Mate => "mate",
+        Stipulation::Helpmate => "helpmate",
+        Stipulation::Selfmate => "selfmate",
+        Stipulation::Reflexmate => "reflexmate",
+    }
+}
+
+fn is_checkmate_move(pos: &mut Position, mov: &Move) -> bool {
+    let undo = make_move(pos, mov);
+    let mate = is_king_in_check(pos, false) && generate_legal_moves(pos).len == 0;
+    unmake_move(pos, mov, undo);
+    mate
+}
+
+fn has_checkmate_move(pos: &mut Position) -> bool {
+    let list = generate_legal_moves(pos);
+    move_list_slice(&list).iter().any(|&pm| {
+        let mov = unpack_move(pos, pm);
+        is_checkmate_move(pos, &mov)
+    })
+}
+
+// Whether the first mover, to move in pos, solves in n moves.
+fn solves_in(solver: &mut Solver, pos: &mut Position, n: u32) -> bool {
+    if n == 0 {
+        return false;
+    }
+    if let Some(&known) = solver.proven.get(&(pos.hash, n)) {
+        return known;
+    }
+    // In a reflexmate a mate that can be given must be, and ends it wrong.
+    if solver.stipulation == Stipulation::Reflexmate && has_checkmate_move(pos) {
+        return false;
+    }
+    let list = generate_legal_moves(pos);
+    let mut found = false;
+    for &pm in move_list_slice(&list) {
+        let mov = unpack_move(pos, pm);
+        let undo = make_move(pos, &mov);
+        // Only a check can mate at once.
+        found = (n > 1 || solver.stipulation != Stipulation::Mate || is_king_in_check(pos, false))
+            && is_reply_lost(solver, pos, n);
+        unmake_move(pos, &mov, undo);
+        if found {
+            break;
+        }
+    }
+    solver.proven.insert((pos.hash, n), found);
+    found
+}
+
+// Whether the other side of a helpmate, to move in pos with n moves of
+// the first mover used up to here, can mate it with its nth move.
+fn can_help_mate(solver: &mut Solver, pos: &mut Position, n: u32) -> bool {
+    let list = generate_legal_moves(pos);
+    move_list_slice(&list).iter().any(|&pm| {
+        let mov = unpack_move(pos, pm);
+        if n == 1 {
+            return is_checkmate_move(pos, &mov);
+        }
+        let undo = make_move(pos, &mov);
+        let helped = solves_in(solver, pos, n - 1);
+        unmake_move(pos, &mov, undo);
+        helped
+    })
+}
+
+// Whether the other side of a selfmate, to move in pos with n moves of
+// the first mover used up to here, must mate it by its nth move whatever
+// it does.
+fn is_forced_to_mate(solver: &mut Solver, pos: &mut Position, n: u32) -> bool {
+    let list = generate_legal_moves(pos);
+    if list.len == 0 {
+        return false;
+    }
+    if solver.stipulation == Stipulation::Reflexmate && has_checkmate_move(pos) {
+        return true;
+    }
+    move_list_slice(&list).iter().all(|&pm| {
+        let mov = unpack_move(pos, pm);
+        if is_checkmate_move(pos, &mov) {
+            return true;
+        }
+        let undo = make_move(pos, &mov);
+        let forced = solves_in(solver, pos, n - 1);
+        unmake_move(pos, &mov, undo);
+        forced
+    })
+}
+
+// Whether the other side, to move in pos with n moves of the first mover
+// used up to here, ends up as the stipulation wants: mated now or after
+// every defence in a mate, helping or forced to mate in the others.
+fn is_reply_lost(solver: &mut Solver, pos: &mut Position, n: u32) -> bool {
+    // A helpmate in 0 asks this with no moves left.
+    if n == 0 {
+        return false;
+    }
+    match solver.stipulation {
+        Stipulation::Mate => {},
+        Stipulation::Helpmate => return can_help_mate(solver, pos, n),
+        Stipulation::Selfmate | Stipulation::Reflexmate => return is_forced_to_mate(solver, pos, n),
+    }
+    let list = generate_legal_moves(pos);
+    if list.len == 0 {
+        return is_king_in_check(pos, false);
+    }
+    if n <= 1 {
+        return false;
+    }
+    for &pm in move_list_slice(&list) {
+        let mov = unpack_move(pos, pm);
+        let undo = make_move(pos, &mov);
+        let lost = solves_in(solver, pos, n - 1);
+        unmake_move(pos, &mov, undo);
+        if !lost {
+            return false;
+        }
+    }
+    true
+}
+
+// The fewest moves, up to n, in which the first mover, who just moved,
+// solves against the side to move of pos. Helpmates take exactly n.
+fn reply_lost_in(solver: &mut Solver, pos: &mut Position, n: u32) -> Option<u32> {
+    if solver.stipulation == Stipulation::Helpmate {
+        return Some(n).filter(|&n| is_reply_lost(solver, pos, n));
+    }
+    (1 ..= n).find(|&k| is_reply_lost(solver, pos, k))
+}
+
+fn problem_node(pos: &Position, mov: &Move, children: Vec<ProblemNode>) -> ProblemNode {
+    ProblemNode{mov: pack_move(pos, mov), san: move_to_string(mov, pos), children}
+}
+
+// The moves of the first mover that solve in at most n, each with the
+// replies to it. With soonest_only, as for a continuation, only those
+// that solve soonest.
+fn attack_nodes(solver: &mut Solver, pos: &mut Position, n: u32, soonest_only: bool) -> Vec<ProblemNode> {
+    if solver.stipulation == Stipulation::Reflexmate && has_checkmate_move(pos) {
+        return Vec::new();
+    }
+    expand_position(pos);
+    let moves = pos.moves.take().unwrap();
+    let mut solving = Vec::new();
+    for mov in moves.iter() {
+        let mut after = position_after_move(pos, mov);
+        if let Some(k) = reply_lost_in(solver, &mut after, n) {
+            solving.push((k, mov, after));
+        }
+    }
+    let soonest = solving.iter().map(|(k, _, _)| *k).min().unwrap_or(0);
+    let mut nodes = Vec::new();
+    for (k, mov, mut after) in solving {
+        if soonest_only && k > soonest {
+            continue;
+        }
+        let children = reply_nodes(solver, &mut after, k);
+        nodes.push(problem_node(pos, mov, children));
+    }
+    pos.moves = Some(moves);
+    nodes
+}
+
+// The replies of the side to move that keep to a solution in n, with the
+// continuations that follow. None after the last move.
+fn reply_nodes(solver: &mut Solver, pos: &mut Position, n: u32) -> Vec<ProblemNode> {
+    expand_position(pos);
+    if is_king_in_checkmate(pos) {
+        return Vec::new();
+    }
+    let moves = pos.moves.take().unwrap();
+    let stipulation = solver.stipulation;
+    let must_mate = stipulation == Stipulation::Reflexmate && has_checkmate_move(pos);
+    let mut nodes = Vec::new();
+    for mov in moves.iter() {
+        let mates = is_checkmate_move(pos, mov);
+        let mut after = position_after_move(pos, mov);
+        let children = match stipulation {
+            Stipulation::Mate => attack_nodes(solver, &mut after, n - 1, true),
+            Stipulation::Helpmate if n == 1 && mates => Vec::new(),
+            Stipulation::Helpmate if n == 1 || !solves_in(solver, &mut after, n - 1) => continue,
+            Stipulation::Helpmate => attack_nodes(solver, &mut after, n - 1, false),
+            _ if must_mate && !mates => continue,
+            _ if mates => Vec::new(),
+            _ => attack_nodes(solver, &mut after, n - 1, true),
+        };
+        nodes.push(problem_node(pos, mov, children));
+    }
+    pos.moves = Some(moves);
+    nodes
+}
+
+// Replies after which the first mover has more than one way on.
+fn count_duals(nodes: &[ProblemNode]) -> usize {
+    nodes.iter()
+        .map(|attack| attack.children.iter()
+            .map(|reply| (reply.children.len() > 1) as usize + count_duals(&reply.children))
+            .sum::<usize>())
+        .sum()
+}
+
+// Solves the problem of pos in n moves of the side to move, with every
+// key and the tree behind it.
+pub fn solve_problem(pos: &Position, stipulation: Stipulation, n: u32) -> ProblemSolution {
+    let mut solver = Solver{stipulation, proven: HashMap::new()};
+    let mut pos = copy_position(pos);
+    let keys = attack_nodes(&mut solver, &mut pos, n, false);
+    let short_mate = (1 .. n).find(|&k| solves_in(&mut solver, &mut pos, k));
+    let mut threats = Vec::new();
+    for key in keys.iter() {
+        let mut after = copy_position(&pos);
+        make_move(&mut after, &unpack_move(&pos, key.mov));
+        let mut threat = Vec::new();
+        if n > 1 && stipulation != Stipulation::Helpmate && !is_king_in_check(&after, false) {
+            make_null_move(&mut after);
+            for node in attack_nodes(&mut solver, &mut after, n - 1, true) {
+                threat.push(node.san);
+            }
+        }
+        threats.push(threat);
+    }
+    let duals = if stipulation == Stipulation::Helpmate { 0 } else { count_duals(&keys) };
+    ProblemSolution{stipulation, moves: n, keys, threats, short_mate, duals}
+}
+
+// Whether the side to move mates in n of its moves against every
+// defence, with the keys that do and the trees behind them.
+pub fn solve_mate(pos: &Position, n: u32) -> ProblemSolution {
+    solve_problem(pos, Stipulation::Mate, n)
+}
+
+fn write_replies(out: &mut String, replies: &[ProblemNode], number: u32, indent: usize) {
+    for reply in replies {
+        out.push_str(&format!("{:indent$}{}...{}", "", number, reply.san, indent = indent));
+        let dual = if reply.children.len() > 1 { " (dual)" } else { "" };
+        if reply.children.is_empty() {
+            out.push('\n');
+            continue;
+        }
+        if reply.children.iter().all(|attack| attack.children.is_empty()) {
+            let mates: Vec<String> = reply.children.iter().map(|a| format!("{}.{}", number + 1, a.san)).collect();
+            out.push_str(&format!(" {}{}\n", mates.join(", "), dual));
+            continue;
+        }
+        out.push_str(&format!("{}\n", dual));
+        for attack in reply.children.iter() {
+            out.push_str(&format!("{:indent$}{}.{}\n", "", number + 1, attack.san, indent = indent + 2));
+            write_replies(out, &attack.children, number + 1, indent + 4);
+        }
+    }
+}
+
+// Each line of a helpmate from node on, after the moves in line.
+fn write_help_lines(out: &mut String, nodes: &[ProblemNode], number: u32, line: &str) {
+    for attack in nodes {
+        for reply in attack.children.iter() {
+            let line = format!("{}{}{}.{} {}", line, if line.is_empty() { "" } else { " " }, number, attack.san, reply.san);
+            if reply.children.is_empty() {
+                out.push_str(&format!("{}\n", line));
+            } else {
+                write_help_lines(out, &reply.children, number + 1, &line);
+            }
+        }
+    }
+}
+
+// The solution as composers write it: each key with a "!", its threat,
+// and the replies indented below, one to a line. Helpmates have one line
+// per solution instead.
+pub fn problem_solution_to_string(solution: &ProblemSolution) -> String {
+    let mut out = String::new();
+    if solution.keys.is_empty() {
+        out.push_str(&format!("No {} in {}\n", stipulation_to_string(solution.stipulation), solution.moves));
+        return out;
+    }
+    if solution.stipulation == Stipulation::Helpmate {
+        write_help_lines(&mut out, &solution.keys, 1, "");
+        let lines = out.lines().count();
+        if lines > 1 {
+            out.push_str(&format!("{} solutions\n", lines));
+        }
+    } else {
+        for (key, threat) in solution.keys.iter().zip(solution.threats.iter()) {
+            out.push_str(&format!("1.{}!", key.san));
+            if !threat.is_empty() {
+                let threat: Vec<String> = threat.iter().map(|san| format!("2.{}", san)).collect();
+                out.push_str(&format!(" threat: {}", threat.join(", ")));
+            }
+            out.push('\n');
+            write_replies(&mut out, &key.children, 1, 4);
+        }
+        if solution.keys.len() > 1 {
+            out.push_str(&format!("Cooked: {} keys\n", solution.keys.len()));
+        }
+    }
+    if let Some(k) = solution.short_mate {
+        out.push_str(&format!("Short solution: {} in {}\n", stipulation_to_string(solution.stipulation), k));
+    }
+    if solution.duals > 0 {
+        out.push_str(&format!("Duals: {}\n", solution.duals));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the solver on problems with known solutions.
+    #[test]
+    fn problems() {
+        let solve = |fen: &str, n: u32| solve_mate(&decode_fen(String::from(fen)), n);
+        let keys = |solution: &ProblemSolution| -> Vec<String> { solution.keys.iter().map(|k| k.san.clone()).collect() };
+        // Only Qh8 mates; Qb8+ is taken and Qa2+ leaves b8.
+        let solution = solve("k7/8/1K6/8/8/8/7Q/8 w - - 0 1", 1);
+        assert_eq!(keys(&solution), ["Qh8#"]);
+        assert_eq!((solution.short_mate, solution.duals), (None, 0));
+        // Two rooks mate on the back rank either way: a cook.
+        let solution = solve("6k1/5ppp/8/8/8/8/8/RR4K1 w - - 0 1", 1);
+        assert_eq!(keys(&solution), ["Ra8#", "Rb8#"]);
+        assert!(problem_solution_to_string(&solution).contains("Cooked: 2 keys"));
+        // A mate in one solves a mate in two short.
+        let solution = solve("k7/8/1K6/8/8/8/7Q/8 w - - 0 1", 2);
+        assert_eq!(solution.short_mate, Some(1));
+        assert!(keys(&solution).contains(&String::from("Qh8#")) && solution.keys.len() > 1);
+        // Bare kings never mate, and a stalemate is no mate.
+        assert!(solve("k7/8/1K6/8/8/8/8/8 w - - 0 1", 3).keys.is_empty());
+        assert!(solve("7k/8/6K1/8/8/8/8/5Q2 w - - 0 1", 1).keys.iter().all(|k| k.san.ends_with('#')));
+        // The waiting move Kc7 leaves the king one square, and threatens the
+        // same mate.
+        let solution = solve("k7/8/2K5/8/8/8/8/1R6 w - - 0 1", 2);
+        assert_eq!(keys(&solution), ["Kc7"]);
+        assert_eq!(solution.threats, [["Ra1#"]]);
+        assert_eq!(problem_solution_to_string(&solution), "1.Kc7! threat: 2.Ra1#\n    1...Ka7 2.Ra1#\n");
+        // After 1.Rab2 Ka7 either rook mates.
+        let solution = solve("k7/8/2K5/8/8/8/R7/1R6 w - - 0 1", 2);
+        let tree = problem_solution_to_string(&solution);
+        assert!(tree.contains("1.Rab2! threat: 2.Ra1#, 2.Ra2#\n    1...Ka7 2.Ra1#, 2.Ra2# (dual)\n"), "{}", tree);
+        assert!(solution.duals > 0 && solution.short_mate == Some(1));
+
+        let solve_as = |stipulation: Stipulation, fen: &str, n: u32| {
+            problem_solution_to_string(&solve_problem(&decode_fen(String::from(fen)), stipulation, n))
+        };
+        // Kg8 is the only move, and the rook mates on the back rank.
+        assert_eq!(solve_as(Stipulation::Helpmate, "7k/8/6K1/8/8/8/8/R7 b - - 0 1", 1), "1.Kg8 Ra8#\n");
+        let tree = solve_as(Stipulation::Helpmate, "7k/8/6K1/8/8/8/8/R7 b - - 0 1", 2);
+        assert!(tree.contains("1.Kg8 Ra7 2.Kh8 Ra8#\n") && tree.contains("Short solution: helpmate in 1"), "{}", tree);
+        assert_eq!(solve_as(Stipulation::Helpmate, "k7/8/1K6/8/8/8/8/8 b - - 0 1", 2), "No helpmate in 2\n");
+        // Qc2+ leaves only Kxc2, which uncovers the rook on the boxed king.
+        // In a reflexmate Qa2+ works too, since Black must take it and mate
+        // rather than play Kc1.
+        let fen = "6R1/8/8/8/Q7/8/6PP/rk5K w - - 0 1";
+        assert_eq!(solve_as(Stipulation::Selfmate, fen, 1), "1.Qc2+!\n    1...Kxc2#\n");
+        assert_eq!(solve_as(Stipulation::Reflexmate, fen, 1), "1.Qa2+!\n    1...Kxa2#\n1.Qc2+!\n    1...Kxc2#\nCooked: 2 keys\n");
+        // With a mate of its own, White must give it in a reflexmate.
+        assert_eq!(solve_as(Stipulation::Reflexmate, "k7/8/1K6/8/8/8/7Q/8 w - - 0 1", 1), "No reflexmate in 1\n");
+        assert_eq!(solve_as(Stipulation::Selfmate, "k7/8/1K6/8/8/8/8/8 w - - 0 1", 2), "No selfmate in 2\n");
+        // Nothing solves in no moves.
+        for stipulation in [Stipulation::Mate, Stipulation::Helpmate, Stipulation::Selfmate, Stipulation::Reflexmate] {
+            let solution = solve_problem(&decode_fen(String::from("7k/8/6K1/8/8/8/8/R7 b - - 0 1")), stipulation, 0);
+            assert!(solution.keys.is_empty() && solution.short_mate.is_none());
+        }
+    }
+}
@@ -0,0 +1,871 @@
+// Iterative deepening alpha-beta search (principal variation search) with
+// a transposition table, null move pruning, late move reductions and a
+// capture-only quiescence search. Scores are in centipawns from the point
+// of view of the side to move; mates are MATE_SCORE minus the distance to
+// the mate in plies.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+pub const MATE_SCORE: Score = 32000;
+pub const INFINITE_SCORE: Score = 32001;
+pub const MAX_PLY: usize = 128;
+// Scores at least this far from zero are mates.
+pub const MATE_BOUND: Score = MATE_SCORE - MAX_PLY as Score;
+
+const BOUND_EXACT: u8 = 0;
+const BOUND_LOWER: u8 = 1;
+const BOUND_UPPER: u8 = 2;
+
+// How many nodes pass between checks of the clock and the stop flag.
+const CHECK_INTERVAL: u64 = 1024;
+
+#[derive(Clone, Copy)]
+pub struct TtEntry {
+    pub key: u64,
+    pub mov: PackedMove,
+    pub score: Score,
+    pub depth: i16,
+    pub bound: u8,
+    pub generation: u8,
+}
+
+pub struct TranspositionTable {
+    // Length is a power of two; an entry lives at key & (len - 1).
+    entries: Vec<TtEntry>,
+    // Bumped at every search so that old entries give way to new ones.
+    generation: u8,
+}
+
+#[derive(Clone)]
+pub struct SearchLimits {
+    pub depth: Option<u32>,
+    pub nodes: Option<u64>,
+    pub movetime: Option<Duration>,
+    // Stop as soon as a mate in this many moves is found.
+    pub mate: Option<u32>,
+    // Only these root moves are searched, all of them if empty.
+    pub searchmoves: Vec<PackedMove>,
+    // The clock of the side to move.
+    pub time: Option<Duration>,
+    // The clock of the other side, if known.
+    pub opponent_time: Option<Duration>,
+    pub increment: Duration,
+    pub moves_to_go: Option<u32>,
+    // Search until stopped.
+    pub infinite: bool,
+}
+
+// Reported after every completed iteration.
+pub struct SearchInfo {
+    pub depth: u32,
+    pub seldepth: u32,
+    pub score: Score,
+    pub nodes: u64,
+    pub time: Duration,
+    // Permille of the transposition table in use.
+    pub hashfull: u32,
+    pub pv: Vec<PackedMove>,
+}
+
+pub struct SearchResult {
+    // NULL_PACKED_MOVE when there are no legal moves.
+    pub best_move: PackedMove,
+    // The reply expected to best_move, NULL_PACKED_MOVE if unknown.
+    pub ponder_move: PackedMove,
+    pub score: Score,
+    pub depth: u32,
+    pub nodes: u64,
+    pub pv: Vec<PackedMove>,
+}
+
+pub struct Searcher {
+    pub tt: TranspositionTable,
+    // Set from another thread to end the search early.
+    pub stop: Arc<AtomicBool>,
+    // While set, the search ignores its time limits. Cleared from another
+    // thread when the opponent plays the expected move.
+    pub pondering: Arc<AtomicBool>,
+    // The clock when the expected move was played, if it changed since
+    // the search started. Taken at the ponderhit, or by the next search
+    // when it came too late for this one.
+    pub ponderhit_limits: Arc<Mutex<Option<SearchLimits>>>,
+    // Probed at every node with few enough men.
+    pub tablebases: Tablebases,
+    pub syzygy: Syzygy,
+    // Syzygy WDL tables are probed in the search with this many men or
+    // fewer; none when the root was ranked by DTZ or is not won.
+    tb_cardinality: usize,
+    limits: SearchLimits,
+    start: Instant,
+    // How long this search may take, from limits.movetime or the clock,
+    // and after how long it starts no new iteration. Both count from
+    // timer_start: the start, or the ponderhit when it began as a ponder
+    // search.
+    deadline: Option<Duration>,
+    soft_limit: Option<Duration>,
+    timer_start: Instant,
+    was_pondering: bool,
+    nodes: u64,
+    seldepth: usize,
+    root_depth: i32,
+    stopped: bool,
+    // Hashes of the positions before the current node, back to the
+    // start of the game.
+    hashes: Vec<u64>,
+    killers: [[PackedMove; 2]; MAX_PLY],
+    // Indexed by 64 * from + to.
+    history: Vec<i32>,
+    pv: Vec<[PackedMove; MAX_PLY]>,
+    pv_len: [usize; MAX_PLY],
+}
+
+pub fn default_search_limits() -> SearchLimits {
+    SearchLimits{
+        depth: None,
+        nodes: None,
+        movetime: None,
+        mate: None,
+        searchmoves: Vec::new(),
+        time: None,
+        opponent_time: None,
+        increment: Duration::ZERO,
+        moves_to_go: None,
+        infinite: false,
+    }
+}
+
+fn empty_tt_entry() -> TtEntry {
+    TtEntry{key: 0, mov: NULL_PACKED_MOVE, score: 0, depth: 0, bound: BOUND_EXACT, generation: 0}
+}
+
+pub fn new_transposition_table(megabytes: usize) -> TranspositionTable {
+    let bytes = megabytes.max(1) * 1024 * 1024;
+    let mut len = 1;
+    while 2 * len * std::mem::size_of::<TtEntry>() <= bytes {
+        len *= 2;
+    }
+    TranspositionTable{
+        entries: vec![empty_tt_entry(); len],
+        generation: 0,
+    }
+}
+
+pub fn tt_clear(tt: &mut TranspositionTable) {
+    tt.entries.fill(empty_tt_entry());
+    tt.generation = 0;
+}
+
+pub fn tt_probe(tt: &TranspositionTable, key: u64) -> Option<TtEntry> {
+    let entry = tt.entries[(key as usize) & (tt.entries.len() - 1)];
+    if entry.key == key {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+fn tt_store(
+    tt: &mut TranspositionTable, key: u64, mov: PackedMove, score: Score, depth: i32, bound: u8
+) {
+    let generation = tt.generation;
+    let i = (key as usize) & (tt.entries.len() - 1);
+    let entry = &mut tt.entries[i];
+    let replace = entry.key != key
+        || entry.generation != generation
+        || bound == BOUND_EXACT
+        || depth + 2 >= entry.depth as i32;
+    if !replace {
+        return;
+    }
+    // Keep the old move if this search found none.
+    let mov = if mov == NULL_PACKED_MOVE && entry.key == key { entry.mov } else { mov };
+    *entry = TtEntry{key, mov, score, depth: depth as i16, bound, generation};
+}
+
+// Permille of a sample of entries written during the current search.
+pub fn tt_hashfull(tt: &TranspositionTable) -> u32 {
+    let sample = tt.entries.len().min(1000);
+    let used = tt.entries[.. sample].iter()
+        .filter(|e| e.key != 0 && e.generation == tt.generation)
+        .count();
+    (used * 1000 / sample) as u32
+}
+
+// Mate scores are stored relative to the node rather than the root, so
+// that they stay right when the entry is found at another ply.
+fn score_to_tt(score: Score, ply: usize) -> Score {
+    if score >= MATE_BOUND {
+        score + ply as Score
+    } else if score <= -MATE_BOUND {
+        score - ply as Score
+    } else {
+        score
+    }
+}
+
+fn score_from_tt(score: Score, ply: usize) -> Score {
+    if score >= MATE_BOUND {
+        score - ply as Score
+    } else if score <= -MATE_BOUND {
+        score + ply as Score
+    } else {
+        score
+    }
+}
+
+pub fn is_mate_score(score: Score) -> bool {
+    score.abs() >= MATE_BOUND
+}
+
+// Full moves to mate: positive when the side to move mates, negative when
+// it gets mated.
+pub fn mate_in_moves(score: Score) -> i32 {
+    if score > 0 {
+        (MATE_SCORE - score + 1) / 2
+    } else {
+        -(MATE_SCORE + score) / 2
+    }
+}
+
+// "cp 35" or "mate -2", the way UCI info lines write scores.
+pub fn score_to_uci_string(score: Score) -> String {
+    if is_mate_score(score) {
+        format!("mate {}", mate_in_moves(score))
+    } else {
+        format!("cp {}", score)
+    }
+}
+
+pub fn new_searcher(hash_megabytes: usize) -> Searcher {
+    Searcher{
+        tt: new_transposition_table(hash_megabytes),
+        stop: Arc::new(AtomicBool::new(false)),
+        pondering: Arc::new(AtomicBool::new(false)),
+        ponderhit_limits: Arc::new(Mutex::new(None)),
+        tablebases: new_tablebases(),
+        syzygy: new_syzygy(),
+        tb_cardinality: 0,
+        limits: default_search_limits(),
+        start: Instant::now(),
+        deadline: None,
+        soft_limit: None,
+        timer_start: Instant::now(),
+        was_pondering: false,
+        nodes: 0,
+        seldepth: 0,
+        root_depth: 0,
+        stopped: false,
+        hashes: Vec::new(),
+        killers: [[NULL_PACKED_MOVE; 2]; MAX_PLY],
+        history: vec![0; 64 * 64],
+        pv: vec![[NULL_PACKED_MOVE; MAX_PLY]; MAX_PLY],
+        pv_len: [0; MAX_PLY],
+    }
+}
+
+// Forgets everything learnt from earlier searches, for a new game.
+pub fn searcher_clear(s: &mut Searcher) {
+    tt_clear(&mut s.tt);
+    s.killers = [[NULL_PACKED_MOVE; 2]; MAX_PLY];
+    s.history.fill(0);
+}
+
+fn set_time_limits(s: &mut Searcher, limits: &SearchLimits) {
+    let allocation = allocate_time(limits).filter(|_| !limits.infinite);
+    s.deadline = match (limits.movetime, allocation) {
+        _ if limits.infinite => None,
+        (Some(t), Some(a)) => Some(t.min(a.hard)),
+        (t, a) => t.or(a.map(|a| a.hard)),
+    };
+    s.soft_limit = allocation.map(|a| a.soft);
+}
+
+// Ponderhit: the search goes on as a normal one, on the clock as it is
+// now, and its time starts now. Returns whether it is still pondering.
+fn check_ponderhit(s: &mut Searcher) -> bool {
+    let pondering = s.pondering.load(Ordering::Relaxed);
+    if s.was_pondering && !pondering {
+        s.was_pondering = false;
+        s.timer_start = Instant::now();
+        let limits = s.ponderhit_limits.lock().unwrap().take();
+        if let Some(limits) = limits {
+            set_time_limits(s, &limits);
+        }
+    }
+    pondering
+}
+
+fn check_limits(s: &mut Searcher) {
+    let pondering = check_ponderhit(s);
+    // The first iteration always completes, so there is a move to play.
+    if s.root_depth <= 1 {
+        return;
+    }
+    let out_of_nodes = matches!(s.limits.nodes, Some(n) if s.nodes >= n);
+    let out_of_time = matches!(s.deadline, Some(t) if s.timer_start.elapsed() >= t)
+        && !pondering;
+    if out_of_nodes || out_of_time || s.stop.load(Ordering::Relaxed) {
+        s.stopped = true;
+    }
+}
+
+// Whether the position at the current node occurred before, since the
+// last capture or pawn move.
+fn is_repetition(s: &Searcher, pos: &Position) -> bool {
+    let n = s.hashes.len();
+    let window = (pos.halfmoves as usize).min(n);
+    let hash = repetition_hash(pos);
+    let mut back = 4;
+    while back <= window {
+        if s.hashes[n - back] == hash {
+            return true;
+        }
+        back += 2;
+    }
+    false
+}
+
+fn has_non_pawn_material(pos: &Position, color: Color) -> bool {
+    let pawns_and_kings = pos.piece_bb[piece_index(P_BASE)] | pos.piece_bb[piece_index(K_BASE)];
+    color_bb(pos, color) & !pawns_and_kings != 0
+}
+
+// Passes the move to the opponent. Returns what unmake_null_move needs.
+pub fn make_null_move(pos: &mut Position) -> (Option<Sq>, i32, u64) {
+    let saved = (pos.en_passant, pos.halfmoves, pos.hash);
+    pos.hash ^= zobrist_en_passant_key(pos.en_passant) ^ ZOBRIST.black_to_move;
+    pos.en_passant = None;
+    // Repetitions cannot reach across a null move.
+    pos.halfmoves = 0;
+    pos.active_color = -pos.active_color;
+    saved
+}
+
+pub fn unmake_null_move(pos: &mut Position, saved: (Option<Sq>, i32, u64)) {
+    pos.active_color = -pos.active_color;
+    (pos.en_passant, pos.halfmoves, pos.hash) = saved;
+}
+
+fn captured_piece(pos: &Position, pm: PackedMove) -> Piece {
+    if packed_move_flags(pm) == MOVE_FLAG_EN_PASSANT {
+        P_BASE
+    } else {
+        piece_at_sq(pos, packed_move_to(pm))
+    }
+}
+
+fn is_quiet_move(pos: &Position, pm: PackedMove) -> bool {
+    captured_piece(pos, pm) == EMPTY && packed_move_flags(pm) < MOVE_FLAG_PROMOTION
+}
+
+// Ordering keys: the hash move, then captures by most valuable victim and
+// least valuable attacker, then killers, then quiet moves by history.
+fn score_moves(
+    s: &Searcher, pos: &Position, moves: &[PackedMove], tt_move: PackedMove, ply: usize,
+    keys: &mut [i32],
+) {
+    for (i, &pm) in moves.iter().enumerate() {
+        let captured = captured_piece(pos, pm);
+        let flags = packed_move_flags(pm);
+        keys[i] = if pm == tt_move {
+            1_000_000
+        } else if captured != EMPTY || flags >= MOVE_FLAG_PROMOTION {
+            let mut key = 100_000;
+            if captured != EMPTY {
+                let attacker = piece_at_sq(pos, packed_move_from(pm));
+                key += 10 * PIECE_VALUES[piece_index(captured)]
+                    - PIECE_VALUES[piece_index(attacker)] / 100;
+            }
+            if flags >= MOVE_FLAG_PROMOTION {
+                let pb = PROMOTION_BASES[(flags - MOVE_FLAG_PROMOTION) as usize];
+                key += 10 * PIECE_VALUES[piece_index(pb)] - 10 * PIECE_VALUES[0];
+            }
+            key
+        } else if pm == s.killers[ply][0] {
+            90_000
+        } else if pm == s.killers[ply][1] {
+            80_000
+        } else {
+            s.history[pm as usize & 4095]
+        };
+    }
+}
+
+// Brings the best remaining move to position i.
+fn pick_move(moves: &mut [PackedMove], keys: &mut [i32], i: usize) {
+    let mut best = i;
+    for j in i + 1 .. moves.len() {
+        if keys[j] > keys[best] {
+            best = j;
+        }
+    }
+    moves.swap(i, best);
+    keys.swap(i, best);
+}
+
+fn update_pv(s: &mut Searcher, ply: usize, pm: PackedMove) {
+    let child_len = if ply + 1 < MAX_PLY { s.pv_len[ply + 1] } else { 0 };
+    s.pv[ply][0] = pm;
+    for i in 0 .. child_len {
+        s.pv[ply][i + 1] = s.pv[ply + 1][i];
+    }
+    s.pv_len[ply] = child_len + 1;
+}
+
+fn quiesce(s: &mut Searcher, pos: &mut Position, mut alpha: Score, beta: Score, ply: usize) -> Score {
+    s.pv_len[ply] = 0;
+    if s.nodes.is_multiple_of(CHECK_INTERVAL) {
+        check_limits(s);
+    }
+    if s.stopped {
+        return 0;
+    }
+    s.nodes += 1;
+    s.seldepth = s.seldepth.max(ply);
+    if ply >= MAX_PLY - 1 {
+        return evaluate(pos);
+    }
+    let in_check = is_king_in_check(pos, false);
+    let mut best_score = -INFINITE_SCORE;
+    let mut stand_pat = -INFINITE_SCORE;
+    if !in_check {
+        stand_pat = evaluate(pos);
+        if stand_pat >= beta {
+            return stand_pat;
+        }
+        alpha = alpha.max(stand_pat);
+        best_score = stand_pat;
+    }
+    let list = generate_legal_moves(pos);
+    if in_check && list.len == 0 {
+        return -MATE_SCORE + ply as Score;
+    }
+    let mut moves = list.moves;
+    let moves = &mut moves[.. list.len];
+    let mut keys = [0; MAX_MOVES];
+    score_moves(s, pos, moves, NULL_PACKED_MOVE, ply, &mut keys);
+    for i in 0 .. moves.len() {
+        pick_move(moves, &mut keys[.. list.len], i);
+        let pm = moves[i];
+        if !in_check {
+            if is_quiet_move(pos, pm) {
+                // Quiet moves sort last.
+                break;
+            }
+            // Skip captures that cannot bring the score near alpha.
+            let captured = captured_piece(pos, pm);
+            let is_promotion = packed_move_flags(pm) >= MOVE_FLAG_PROMOTION;
+            if !is_promotion && stand_pat + PIECE_VALUES[piece_index(captured)] + 200 < alpha {
+                continue;
+            }
+        }
+        let mov = unpack_move(pos, pm);
+        let undo = make_move(pos, &mov);
+        let score = -quiesce(s, pos, -beta, -alpha, ply + 1);
+        unmake_move(pos, &mov, undo);
+        if s.stopped {
+            return 0;
+        }
+        if score > best_score {
+            best_score = score;
+            if score > alpha {
+                alpha = score;
+                if alpha >= beta {
+                    break;
+                }
+            }
+        }
+    }
+    best_score
+}
+
+fn negamax(
+    s: &mut Searcher, pos: &mut Position, mut depth: i32,
+    mut alpha: Score, mut beta: Score, ply: usize, allow_null: bool,
+) -> Score {
+    s.pv_len[ply] = 0;
+    if s.nodes.is_multiple_of(CHECK_INTERVAL) {
+        check_limits(s);
+    }
+    if s.stopped {
+        return 0;
+    }
+    let root = ply == 0;
+    let pv_node = beta - alpha > 1;
+    if !root {
+        if pos.halfmoves >= 100 || is_repetition(s, pos) || is_insufficient_material(pos) {
+            return 0;
+        }
+        // No mate found from here can be shorter than one already known.
+        alpha = alpha.max(-MATE_SCORE + ply as Score);
+        beta = beta.min(MATE_SCORE - ply as Score - 1);
+        if alpha >= beta {
+            return alpha;
+        }
+        if ply >= MAX_PLY - 1 {
+            return evaluate(pos);
+        }
+        if let Some(outcome) = tb_probe(&s.tablebases, pos) {
+            return tb_outcome_score(outcome, ply);
+        }
+    }
+    let in_check = is_king_in_check(pos, false);
+    if in_check {
+        depth += 1;
+    }
+    if depth <= 0 {
+        return quiesce(s, pos, alpha, beta, ply);
+    }
+    s.nodes += 1;
+    s.seldepth = s.seldepth.max(ply);
+
+    let mut tt_move = NULL_PACKED_MOVE;
+    if let Some(entry) = tt_probe(&s.tt, pos.hash) {
+        tt_move = entry.mov;
+        if !pv_node && entry.depth as i32 >= depth {
+            let score = score_from_tt(entry.score, ply);
+            let cutoff = match entry.bound {
+                BOUND_EXACT => true,
+                BOUND_LOWER => score >= beta,
+                _ => score <= alpha,
+            };
+            if cutoff {
+                return score;
+            }
+        }
+    }
+
+    // A win or loss counts only as a bound: the tables know the result
+    // but not the distance to mate.
+    let mut max_score = INFINITE_SCORE;
+    let mut tb_floor = -INFINITE_SCORE;
+    let men = bb_popcount(occupied_bb(pos)) as usize;
+    if !root && men <= s.tb_cardinality && (men < s.tb_cardinality || depth >= s.syzygy.probe_depth)
+        && pos.halfmoves == 0 && !pos.castling.iter().any(|&c| c) {
+        if let Some(wdl) = syzygy_probe_wdl(&s.syzygy, pos) {
+            let draw_score = s.syzygy.rule50 as Score;
+            let (score, bound) = if wdl < -draw_score {
+                (-MATE_BOUND + ply as Score + 1, BOUND_UPPER)
+            } else if wdl > draw_score {
+                (MATE_BOUND - ply as Score - 1, BOUND_LOWER)
+            } else {
+                (2 * wdl * draw_score, BOUND_EXACT)
+            };
+            let cutoff = match bound {
+                BOUND_EXACT => true,
+                BOUND_LOWER => score >= beta,
+                _ => score <= alpha,
+            };
+            if cutoff {
+                let tb_depth = (depth + 6).min(MAX_PLY as i32 - 1);
+                tt_store(&mut s.tt, pos.hash, NULL_PACKED_MOVE, score_to_tt(score, ply), tb_depth, bound);
+                return score;
+            }
+            if pv_node {
+                if bound == BOUND_LOWER {
+                    tb_floor = score;
+                    alpha = alpha.max(score);
+                } else {
+                    max_score = score;
+                }
+            }
+        }
+    }
+
+    // If passing still fails high, a real move will too.
+    if !pv_node && !in_check && allow_null && depth >= 3
+        && has_non_pawn_material(pos, pos.active_color) && evaluate(pos) >= beta {
+        let reduction = 2 + depth / 6;
+        s.hashes.push(repetition_hash(pos));
+        let saved = make_null_move(pos);
+        let score = -negamax(s, pos, depth - 1 - reduction, -beta, -beta + 1, ply + 1, false);
+        unmake_null_move(pos, saved);
+        s.hashes.pop();
+        if s.stopped {
+            return 0;
+        }
+        if score >= beta {
+            return if is_mate_score(score) { beta } else { score };
+        }
+    }
+
+    let list = generate_legal_moves(pos);
+    let mut moves = list.moves;
+    let mut n = list.len;
+    if root && !s.limits.searchmoves.is_empty() {
+        let mut kept = 0;
+        for i in 0 .. n {
+            if s.limits.searchmoves.contains(&moves[i]) {
+                moves[kept] = moves[i];
+                kept += 1;
+            }
+        }
+        n = kept;
+    }
+    if n == 0 {
+        return if in_check { -MATE_SCORE + ply as Score } else { 0 };
+    }
+    let moves = &mut moves[.. n];
+    let mut keys = [0; MAX_MOVES];
+    score_moves(s, pos, moves, tt_move, ply, &mut keys);
+
+    let original_alpha = alpha;
+    let mut best_score = tb_floor;
+    let mut best_move = NULL_PACKED_MOVE;
+    for i in 0 .. n {
+        pick_move(moves, &mut keys[.. n], i);
+        let pm = moves[i];
+        let quiet = is_quiet_move(pos, pm);
+        let mov = unpack_move(pos, pm);
+        s.hashes.push(repetition_hash(pos));
+        let undo = make_move(pos, &mov);
+        let gives_check = is_king_in_check(pos, false);
+        let mut score;
+        if i == 0 {
+            score = -negamax(s, pos, depth - 1, -beta, -alpha, ply + 1, true);
+        } else {
+            let reduction = if depth >= 3 && i >= 3 && quiet && !in_check && !gives_check {
+                if i >= 8 { 2 } else { 1 }
+            } else {
+                0
+            };
+            score = -negamax(s, pos, depth - 1 - reduction, -alpha - 1, -alpha, ply + 1, true);
+            if score > alpha && reduction > 0 {
+                score = -negamax(s, pos, depth - 1, -alpha - 1, -alpha, ply + 1, true);
+            }
+            if score > alpha && score < beta {
+                score = -negamax(s, pos, depth - 1, -beta, -alpha, ply + 1, true);
+            }
+        }
+        unmake_move(pos, &mov, undo);
+        s.hashes.pop();
+        if s.stopped {
+            return 0;
+        }
+        if score > best_score {
+            best_score = score;
+            best_move = pm;
+            if score > alpha {
+                alpha = score;
+                update_pv(s, ply, pm);
+                if alpha >= beta {
+                    if quiet {
+                        if s.killers[ply][0] != pm {
+                            s.killers[ply][1] = s.killers[ply][0];
+                            s.killers[ply][0] = pm;
+                        }
+                        let h = &mut s.history[pm as usize & 4095];
+                        *h = (*h + depth * depth).min(50_000);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    best_score = best_score.min(max_score);
+    let bound = if best_score >= beta {
+        BOUND_LOWER
+    } else if alpha > original_alpha {
+        BOUND_EXACT
+    } else {
+        BOUND_UPPER
+    };
+    tt_store(&mut s.tt, pos.hash, best_move, score_to_tt(best_score, ply), depth, bound);
+    best_score
+}
+
+// Searches pos within limits and returns the best move found. history
+// holds the repetition hashes of the earlier positions of the game, oldest
+// first, so that repetitions are recognised. report is called after every
+// completed iteration.
+pub fn search(
+    s: &mut Searcher, pos: &Position, history: &[u64], limits: &SearchLimits,
+    report: &mut dyn FnMut(&SearchInfo),
+) -> SearchResult {
+    s.limits = limits.clone();
+    s.start = Instant::now();
+    s.timer_start = s.start;
+    s.was_pondering = s.pondering.load(Ordering::Relaxed);
+    let ponderhit_limits = s.ponderhit_limits.lock().unwrap().take();
+    match ponderhit_limits {
+        Some(hit) if !s.was_pondering => set_time_limits(s, &hit),
+        _ => set_time_limits(s, limits),
+    }
+    // Counted in pos.moves when the position has been expanded.
+    let mut root_moves = if !limits.searchmoves.is_empty() {
+        limits.searchmoves.len()
+    } else {
+        pos.moves.as_ref().map_or_else(|| generate_legal_moves(pos).len, |moves| moves.len())
+    };
+    s.nodes = 0;
+    s.stopped = false;
+    s.hashes = history.to_vec();
+    s.tt.generation = s.tt.generation.wrapping_add(1);
+    for h in s.history.iter_mut() {
+        *h /= 2;
+    }
+    let mut pos = copy_position(pos);
+    let legal = generate_legal_moves(&pos);
+    if limits.searchmoves.iter().all(|pm| !move_list_slice(&legal).contains(pm)) {
+        s.limits.searchmoves.clear();
+    }
+    // With the root in the tables only the moves that keep its result are
+    // searched, and the search probes only when that is a win it cannot
+    // lose by going astray.
+    let mut tb_score = None;
+    s.tb_cardinality = s.syzygy.max_men.min(s.syzygy.probe_limit);
+    if let Some((ranked, by_dtz)) = syzygy_rank_root_moves(&s.syzygy, &pos, is_repetition(s, &pos)) {
+        let ranked: Vec<_> = ranked.into_iter()
+            .filter(|(pm, _, _)| s.limits.searchmoves.is_empty() || s.limits.searchmoves.contains(pm))
+            .collect();
+        if let Some(&(_, best_rank, score)) = ranked.iter().max_by_key(|(_, rank, _)| *rank) {
+            s.limits.searchmoves = ranked.iter().filter(|(_, rank, _)| *rank == best_rank).map(|(pm, _, _)| *pm).collect();
+            root_moves = s.limits.searchmoves.len();
+            tb_score = Some(score);
+            if by_dtz || score <= 0 {
+                s.tb_cardinality = 0;
+            }
+        }
+    }
+    let mut result = SearchResult{
+        best_move: NULL_PACKED_MOVE,
+        ponder_move: NULL_PACKED_MOVE,
+        score: 0,
+        depth: 0,
+        nodes: 0,
+        pv: Vec::new(),
+    };
+    if legal.len == 0 {
+        result.score = if is_king_in_check(&pos, false) { -MATE_SCORE } else { 0 };
+        return result;
+    }
+    let max_depth = limits.depth.unwrap_or(MAX_PLY as u32 - 1).min(MAX_PLY as u32 - 1);
+    let mut best_move_changes = 0;
+    for depth in 1 ..= max_depth {
+        let previous = (result.best_move, result.score);
+        s.root_depth = depth as i32;
+        s.seldepth = 0;
+        let score = negamax(s, &mut pos, depth as i32, -INFINITE_SCORE, INFINITE_SCORE, 0, true);
+        // An interrupted iteration still counts if it got as far as
+        // improving on its first move.
+        if s.pv_len[0] == 0 {
+            break;
+        }
+        if !s.stopped {
+            result.depth = depth;
+            result.score = match tb_score {
+                Some(tb_score) if !is_mate_score(score) => tb_score,
+                _ => score,
+            };
+        }
+        result.pv = s.pv[0][.. s.pv_len[0]].to_vec();
+        result.best_move = result.pv[0];
+        report(&SearchInfo{
+            depth,
+            seldepth: s.seldepth as u32,
+            score: result.score,
+            nodes: s.nodes,
+            time: s.start.elapsed(),
+            hashfull: tt_hashfull(&s.tt),
+            pv: result.pv.clone(),
+        });
+        if s.stopped {
+            break;
+        }
+        if let Some(mate) = limits.mate {
+            if score >= MATE_BOUND && mate_in_moves(score) <= mate as i32 {
+                break;
+            }
+        }
+        // Searching on finds no shorter mate than one already seen to
+        // twice its length.
+        if is_mate_score(score) && depth as Score >= 2 * (MATE_SCORE - score.abs()) + 2 {
+            break;
+        }
+        if let Some(soft) = s.soft_limit {
+            // Nothing is decided while pondering.
+            let pondering = check_ponderhit(s);
+            // A single legal move needs no thought.
+            if root_moves == 1 && !pondering {
+                break;
+            }
+            let changed = depth > 1 && result.best_move != previous.0;
+            best_move_changes = update_best_move_changes(best_move_changes, changed);
+            let score_drop = if depth > 1 { previous.1 - score } else { 0 };
+            let stretch = time_stretch(best_move_changes, score_drop);
+            if !pondering && s.timer_start.elapsed() >= soft * stretch / 100 {
+                break;
+            }
+        }
+    }
+    if result.best_move == NULL_PACKED_MOVE && legal.len > 0 {
+        result.best_move = legal.moves[0];
+    }
+    result.ponder_move = result.pv.get(1).copied().unwrap_or(NULL_PACKED_MOVE);
+    result.nodes = s.nodes;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A few positions with a known best move or score.
+    #[test]
+    fn known_positions() {
+        let mut s = new_searcher(16);
+        let mut limits = default_search_limits();
+        let mut run = |fen: &str, depth: u32| {
+            limits.depth = Some(depth);
+            let pos = decode_fen(String::from(fen));
+            let result = search(&mut s, &pos, &[], &limits, &mut |_| {});
+            let best = if result.best_move == NULL_PACKED_MOVE {
+                String::from("-")
+            } else {
+                move_to_string(&unpack_move(&pos, result.best_move), &pos)
+            };
+            (best, result.score)
+        };
+        // Back rank mate.
+        assert_eq!(run("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1", 2), (String::from("Rd8#"), MATE_SCORE - 1));
+        // Mate in two starting with a knight sacrifice.
+        let (best, score) = run("r2qkb1r/pp2nppp/3p4/2pNN1B1/2BnP3/3P4/PPP2PPP/R2bK2R w KQkq - 1 1", 4);
+        assert_eq!((best.as_str(), mate_in_moves(score)), ("Nf6+", 2));
+        // Checkmated and stalemated.
+        assert_eq!(run("7k/6Q1/6K1/8/8/8/8/8 b - - 0 1", 3), (String::from("-"), -MATE_SCORE));
+        assert_eq!(run("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", 3), (String::from("-"), 0));
+        // Wins a rook with a fork.
+        assert_eq!(run("r3k3/8/8/1N6/8/8/4P3/4K3 w - - 0 1", 4).0, "Nc7+");
+        // White's only move repeats a position of the game, which saves it a
+        // queen down.
+        let pos = decode_fen(String::from("7k/8/8/8/8/8/7q/1K6 b - - 10 20"));
+        let mut game = new_game(&pos);
+        for uci in ["h8g8", "b1a1", "g8h8"] {
+            let mov = parse_uci(&game.position, uci).unwrap();
+            game_push(&mut game, &mov);
+        }
+        let history = game_history_hashes(&game);
+        limits.depth = Some(3);
+        let mut s = new_searcher(1);
+        let result = search(&mut s, &game.position, &history, &limits, &mut |_| {});
+        assert_eq!(result.score, 0);
+        let result = search(&mut s, &game.position, &[], &limits, &mut |_| {});
+        assert!(result.score < -500);
+        // On a clock, a single legal move is played after one iteration.
+        limits.depth = None;
+        limits.time = Some(Duration::from_secs(60));
+        let mut pos = decode_fen(String::from("k7/8/8/8/8/8/6r1/K7 w - - 0 1"));
+        expand_position(&mut pos);
+        let result = search(&mut s, &pos, &[], &limits, &mut |_| {});
+        assert_eq!((packed_move_to_uci_string(result.best_move).as_str(), result.depth), ("a1b1", 1));
+        // Unless pondering, when it goes on until told otherwise.
+        limits.depth = Some(4);
+        s.pondering.store(true, Ordering::Relaxed);
+        let result = search(&mut s, &pos, &[], &limits, &mut |_| {});
+        assert_eq!(result.depth, 4);
+    }
+}
@@ -0,0 +1,1323 @@
+// Probing of Syzygy endgame tablebases: WDL files (.rtbw) tell whether
+// the side to move wins, draws or loses under the fifty move rule, and
+// DTZ files (.rtbz) how many plies it is to the next capture or pawn
+// move on the way. The tables are found by name in the directories of
+// the path, opened at their first probe and read block by block as
+// needed, never whole. Their layout and indexing follow the generator by
+// Ronald de Man; positions with castling rights are never probed.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, OnceLock};
+
+use crate::*;
+
+pub const SYZYGY_MAX_MEN: usize = 7;
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+// More than the header of any table, which holds everything before the
+// sparse indices.
+const MAX_HEADER_SIZE: u64 = 1 << 20;
+
+// The flags of a table per side and file.
+const FLAG_STM: u8 = 1;
+const FLAG_MAPPED: u8 = 2;
+const FLAG_WIN_PLIES: u8 = 4;
+const FLAG_LOSS_PLIES: u8 = 8;
+const FLAG_WIDE: u8 = 16;
+const FLAG_SINGLE_VALUE: u8 = 128;
+
+// For the side to move: a loss, a loss saved by the fifty move rule, a
+// draw, a win spoiled by it and a win.
+pub const WDL_LOSS: i32 = -2;
+pub const WDL_BLESSED_LOSS: i32 = -1;
+pub const WDL_DRAW: i32 = 0;
+pub const WDL_CURSED_WIN: i32 = 1;
+pub const WDL_WIN: i32 = 2;
+
+// Tables that map squares and groups of squares to parts of an index.
+struct SyzygyIndexTables {
+    // A square below the a1-h8 diagonal to 0 ..= 27.
+    map_b1h1h7: [u64; 64],
+    // A square of the a1-d1-d4 triangle to 0 ..= 9, the diagonal last.
+    map_a1d1d4: [u64; 64],
+    // The 462 placements of two kings, the first in the triangle.
+    map_kk: [[u64; 64]; 10],
+    // binomial[k][n]: the ways to choose k of n.
+    binomial: [[u64; 64]; 6],
+    // A pawn square to 0 ..= 47, higher toward the edge and the second rank.
+    map_pawns: [u64; 64],
+    lead_pawn_idx: [[u64; 64]; 6],
+    // By number of leading pawns and file.
+    lead_pawns_size: [[u64; 4]; 6],
+}
+
+static SYZYGY_INDEX: LazyLock<SyzygyIndexTables> = LazyLock::new(new_syzygy_index_tables);
+
+#[derive(Default)]
+struct PairsData {
+    flags: u8,
+    single_value: u8,
+    block_size: u64,
+    // There is a sparse index entry for every span values.
+    span: u64,
+    num_blocks: u64,
+    min_sym_len: u32,
+    // Of the canonical Huffman code: the lowest symbol of each length, and
+    // the same padded to 64 bits.
+    lowest_sym: Vec<u16>,
+    base64: Vec<u64>,
+    // How many values, less one, a symbol stands for, and the pair of
+    // symbols it expands to.
+    symlen: Vec<u32>,
+    btree: Vec<[u16; 2]>,
+    // Offsets in the file.
+    sparse_index: u64,
+    sparse_index_size: u64,
+    block_length: u64,
+    block_length_size: u64,
+    data: u64,
+    // In the order of the index, in the codes of the files.
+    pieces: [u8; SYZYGY_MAX_MEN],
+    group_idx: [u64; SYZYGY_MAX_MEN + 1],
+    group_len: [usize; SYZYGY_MAX_MEN + 1],
+    // Into the DTZ value map, for a win, loss, cursed win and blessed loss.
+    map_idx: [usize; 4],
+}
+
+struct SyzygyTable {
+    file: Mutex<File>,
+    // By side to move, then by file of the leading pawn.
+    items: [[PairsData; 4]; 2],
+    dtz_map: Vec<u8>,
+}
+
+// What the name of a table says about it.
+struct SyzygyMaterial {
+    piece_count: usize,
+    has_pawns: bool,
+    // Whether some side has exactly one piece of a kind besides its king.
+    has_unique_pieces: bool,
+    // Of the side with the leading pawns, then of the other.
+    pawn_count: [usize; 2],
+    // The same men on both sides, like KRvKR.
+    symmetric: bool,
+}
+
+struct SyzygyEntry {
+    material: SyzygyMaterial,
+    wdl_path: PathBuf,
+    dtz_path: PathBuf,
+    wdl: OnceLock<Option<SyzygyTable>>,
+    dtz: OnceLock<Option<SyzygyTable>>,
+}
+
+pub struct Syzygy {
+    // By name, white's men first as in "KRPvKR".
+    entries: HashMap<String, SyzygyEntry>,
+    // The most men of any table found.
+    pub max_men: usize,
+    // From the SyzygyProbeDepth, SyzygyProbeLimit and Syzygy50MoveRule
+    // options: the search probes at this depth or more when the men are
+    // at the limit, and may count wins past fifty moves.
+    pub probe_depth: i32,
+    pub probe_limit: usize,
+    pub rule50: bool,
+}
+
+// Legal moves with their rank and the score to show for them.
+pub type RankedMoves = Vec<(PackedMove, i32, Score)>;
+
+enum TableProbe {
+    Value(i32),
+    // DTZ tables hold one side to move; the position has the other.
+    ChangeStm,
+}
+
+pub fn new_syzygy() -> Syzygy {
+    Syzygy{entries: HashMap::new(), max_men: 0, probe_depth: 1, probe_limit: SYZYGY_MAX_MEN, rule50: true}
+}
+
+fn off_a1h8(sq: Sq) -> i32 {
+    (sq >> 3) as i32 - (sq & 7) as i32
+}
+
+fn flip_file(sq: Sq) -> Sq {
+    sq ^ 7
+}
+
+fn flip_rank(sq: Sq) -> Sq {
+    sq ^ 56
+}
+
+fn new_syzygy_index_tables() -> SyzygyIndexTables {
+    let mut t = SyzygyIndexTables{
+        map_b1h1h7: [0; 64],
+        map_a1d1d4: [0; 64],
+        map_kk: [[0; 64]; 10],
+        binomial: [[0; 64]; 6],
+        map_pawns: [0; 64],
+        lead_pawn_idx: [[0; 64]; 6],
+        lead_pawns_size: [[0; 4]; 6],
+    };
+    let mut code = 0;
+    for sq in 0 .. 64 {
+        if off_a1h8(sq) < 0 {
+            t.map_b1h1h7[sq as usize] = code;
+            code += 1;
+        }
+    }
+    let mut diagonal = Vec::new();
+    code = 0;
+    for sq in 0 ..= 27 {
+        if off_a1h8(sq) < 0 && sq & 7 <= 3 {
+            t.map_a1d1d4[sq as usize] = code;
+            code += 1;
+        } else if off_a1h8(sq) == 0 && sq & 7 <= 3 {
+            diagonal.push(sq);
+        }
+    }
+    for sq in diagonal {
+        t.map_a1d1d4[sq as usize] = code;
+        code += 1;
+    }
+    // With the first king on the diagonal the second is not above it, and
+    // both on it come last.
+    let mut both_on_diagonal = Vec::new();
+    code = 0;
+    for idx in 0 .. 10 {
+        for s1 in 0 ..= 27 {
+            // b1 is the only square of the triangle mapped to 0.
+            if t.map_a1d1d4[s1 as usize] != idx || (idx == 0 && s1 != 1) {
+                continue;
+            }
+            for s2 in 0 .. 64 {
+                if (king_attacks(s1) | sq_bb(s1)) & sq_bb(s2) != 0 {
+                    continue;
+                }
+                if off_a1h8(s1) == 0 && off_a1h8(s2) > 0 {
+                    continue;
+                }
+                if off_a1h8(s1) == 0 && off_a1h8(s2) == 0 {
+                    both_on_diagonal.push((idx, s2));
+                } else {
+                    t.map_kk[idx as usize][s2 as usize] = code;
+                    code += 1;
+                }
+            }
+        }
+    }
+    for (idx, s2) in both_on_diagonal {
+        t.map_kk[idx as usize][s2 as usize] = code;
+        code += 1;
+    }
+    t.binomial[0][0] = 1;
+    for n in 1 .. 64 {
+        for k in 0 .. 6.min(n + 1) {
+            t.binomial[k][n] = if k > 0 { t.binomial[k - 1][n - 1] } else { 0 }
+                + if k < n { t.binomial[k][n - 1] } else { 0 };
+        }
+    }
+    // The leading pawn is the one with the highest map_pawns; the others
+    // can only stand on squares mapped lower.
+    let mut available = 47;
+    for lead_count in 1 ..= 5 {
+        for file in 0 .. 4 {
+            let mut idx = 0;
+            for rank in 1 ..= 6 {
+                let sq = 8 * rank + file;
+                if lead_count == 1 {
+                    t.map_pawns[sq] = available;
+                    t.map_pawns[flip_file(sq as Sq) as usize] = available - 1;
+                    available = available.wrapping_sub(2);
+                }
+                t.lead_pawn_idx[lead_count][sq] = idx;
+                idx += t.binomial[lead_count - 1][t.map_pawns[sq] as usize];
+            }
+            t.lead_pawns_size[lead_count][file] = idx;
+        }
+    }
+    t
+}
+
+// The material of a table name such as "KRPvKR", or None if it is not
+// one.
+fn syzygy_material(name: &str) -> Option<SyzygyMaterial> {
+    let (white, black) = name.split_once('v')?;
+    for side in [white, black] {
+        if !side.starts_with('K') || side[1 ..].contains('K') || side.chars().any(|c| !"KQRBNP".contains(c)) {
+            return None;
+        }
+    }
+    let count = |side: &str, c: char| side.chars().filter(|&x| x == c).count();
+    let piece_count = white.len() + black.len();
+    if piece_count > SYZYGY_MAX_MEN {
+        return None;
+    }
+    let (white_pawns, black_pawns) = (count(white, 'P'), count(black, 'P'));
+    // The side with fewer pawns leads, as that compresses better.
+    let white_leads = black_pawns == 0 || (white_pawns > 0 && black_pawns >= white_pawns);
+    Some(SyzygyMaterial{
+        piece_count,
+        has_pawns: white_pawns + black_pawns > 0,
+        has_unique_pieces: [white, black].iter().any(|side| "QRBNP".chars().any(|c| count(side, c) == 1)),
+        pawn_count: if white_leads { [white_pawns, black_pawns] } else { [black_pawns, white_pawns] },
+        symmetric: white == black,
+    })
+}
+
+// Finds the WDL files in the directories of paths, separated as in PATH,
+// forgetting those found before. Returns how many there are.
+pub fn syzygy_init(sz: &mut Syzygy, paths: &str) -> usize {
+    sz.entries.clear();
+    sz.max_men = 0;
+    for dir in std::env::split_paths(paths) {
+        let Ok(files) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            if path.extension().is_none_or(|e| e != "rtbw") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()).map(String::from) else {
+                continue;
+            };
+            let Some(material) = syzygy_material(&name) else {
+                continue;
+            };
+            sz.max_men = sz.max_men.max(material.piece_count);
+            sz.entries.insert(name.clone(), SyzygyEntry{
+                material,
+                dtz_path: path.with_extension("rtbz"),
+                wdl_path: path,
+                wdl: OnceLock::new(),
+                dtz: OnceLock::new(),
+            });
+        }
+    }
+    sz.entries.len()
+}
+
+fn u16_le(bytes: &[u8], p: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(p .. p + 2)?.try_into().unwrap()))
+}
+
+fn u32_le(bytes: &[u8], p: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(p .. p + 4)?.try_into().unwrap()))
+}
+
+// Reads buf.len() bytes at offset, or as many as there are with the rest
+// left zero.
+fn syzygy_read(file: &Mutex<File>, offset: u64, buf: &mut [u8]) -> Option<()> {
+    let mut file = file.lock().unwrap();
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read ..]).ok()? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Some(())
+}
+
+// How the men are split into groups that are indexed together, and the
+// factor of each group in the index. The leading group is indexed at
+// position order[0] and the other side's pawns at order[1].
+fn syzygy_set_groups(d: &mut PairsData, m: &SyzygyMaterial, order: [u8; 2], file: usize) {
+    let t = &*SYZYGY_INDEX;
+    let mut n = 0;
+    let mut first_len: i32 = if m.has_pawns { 0 } else if m.has_unique_pieces { 3 } else { 2 };
+    d.group_len[0] = 1;
+    for i in 1 .. m.piece_count {
+        first_len -= 1;
+        if first_len > 0 || d.pieces[i] == d.pieces[i - 1] {
+            d.group_len[n] += 1;
+        } else {
+            n += 1;
+            d.group_len[n] = 1;
+        }
+    }
+    n += 1;
+    d.group_len[n] = 0;
+    let pp = m.has_pawns && m.pawn_count[1] > 0;
+    let mut next = if pp { 2 } else { 1 };
+    let mut free_squares = 64 - d.group_len[0] - if pp { d.group_len[1] } else { 0 };
+    let mut idx = 1;
+    let mut k = 0;
+    while next < n || k == order[0] as usize || k == order[1] as usize {
+        if k == order[0] as usize {
+            d.group_idx[0] = idx;
+            idx *= if m.has_pawns {
+                t.lead_pawns_size[d.group_len[0]][file]
+            } else if m.has_unique_pieces {
+                31332
+            } else {
+                462
+            };
+        } else if k == order[1] as usize {
+            d.group_idx[1] = idx;
+            idx *= t.binomial[d.group_len[1]][48 - d.group_len[0]];
+        } else {
+            d.group_idx[next] = idx;
+            idx *= t.binomial[d.group_len[next]][free_squares];
+            free_squares -= d.group_len[next];
+            next += 1;
+        }
+        k += 1;
+    }
+    d.group_idx[n] = idx;
+}
+
+fn syzygy_symlen(d: &mut PairsData, sym: usize, visited: &mut [bool]) -> Option<u32> {
+    visited[sym] = true;
+    let [left, right] = d.btree[sym];
+    if right == 0xFFF {
+        return Some(0);
+    }
+    let (left, right) = (left as usize, right as usize);
+    for s in [left, right] {
+        if *visited.get(s)? {
+            continue;
+        }
+        d.symlen[s] = syzygy_symlen(d, s, visited)?;
+    }
+    Some(d.symlen[left] + d.symlen[right] + 1)
+}
+
+// Reads the block sizes and the Huffman code at p. Returns where they end.
+fn syzygy_set_sizes(d: &mut PairsData, b: &[u8], mut p: usize) -> Option<usize> {
+    d.flags = *b.get(p)?;
+    p += 1;
+    if d.flags & FLAG_SINGLE_VALUE != 0 {
+        d.single_value = *b.get(p)?;
+        return Some(p + 1);
+    }
+    let tb_size = d.group_idx[d.group_len.iter().position(|&len| len == 0)?];
+    d.block_size = 1 << *b.get(p)?;
+    d.span = 1 << *b.get(p + 1)?;
+    d.sparse_index_size = tb_size.div_ceil(d.span);
+    let padding = *b.get(p + 2)? as u64;
+    d.num_blocks = u32_le(b, p + 3)? as u64;
+    d.block_length_size = d.num_blocks + padding;
+    let max_sym_len = *b.get(p + 7)? as u32;
+    d.min_sym_len = *b.get(p + 8)? as u32;
+    p += 9;
+    if d.min_sym_len == 0 || max_sym_len < d.min_sym_len || max_sym_len > 32 {
+        return None;
+    }
+    let lengths = (max_sym_len - d.min_sym_len + 1) as usize;
+    d.lowest_sym = (0 .. lengths).map(|i| u16_le(b, p + 2 * i)).collect::<Option<_>>()?;
+    // Longer codes have lower values, so that base64 falls as lengths
+    // grow and a code padded to 64 bits lies between base64[l] and
+    // base64[l - 1] for its length l.
+    d.base64 = vec![0; lengths];
+    for i in (0 .. lengths - 1).rev() {
+        d.base64[i] = d.base64[i + 1]
+            .wrapping_add(d.lowest_sym[i] as u64)
+            .wrapping_sub(d.lowest_sym[i + 1] as u64) / 2;
+    }
+    for (i, base) in d.base64.iter_mut().enumerate() {
+        *base = base.checked_shl(64 - i as u32 - d.min_sym_len).unwrap_or(0);
+    }
+    p += 2 * lengths;
+    let symbols = u16_le(b, p)? as usize;
+    p += 2;
+    d.btree = (0 .. symbols).map(|i| {
+        let lr = b.get(p + 3 * i .. p + 3 * i + 3)?;
+        Some([((lr[1] as u16 & 0xF) << 8) | lr[0] as u16, ((lr[2] as u16) << 4) | (lr[1] as u16 >> 4)])
+    }).collect::<Option<_>>()?;
+    d.symlen = vec![0; symbols];
+    let mut visited = vec![false; symbols];
+    for sym in 0 .. symbols {
+        if !visited[sym] {
+            d.symlen[sym] = syzygy_symlen(d, sym, &mut visited)?;
+        }
+    }
+    Some(p + 3 * symbols + (symbols & 1))
+}
+
+// Reads the header of a table, which holds everything but its indices
+// and compressed data, and works out where those are.
+fn syzygy_parse_header(t: &mut SyzygyTable, b: &[u8], m: &SyzygyMaterial, dtz: bool) -> Option<()> {
+    if b.get(0 .. 4)? != if dtz { DTZ_MAGIC } else { WDL_MAGIC } {
+        return None;
+    }
+    let flags = *b.get(4)?;
+    if (flags & 2 != 0) != m.has_pawns || (flags & 1 != 0) == m.symmetric {
+        return None;
+    }
+    let sides = if !dtz && !m.symmetric { 2 } else { 1 };
+    let files = if m.has_pawns { 4 } else { 1 };
+    let pp = m.has_pawns && m.pawn_count[1] > 0;
+    let mut p = 5;
+    for f in 0 .. files {
+        let first = *b.get(p)?;
+        let second = if pp { *b.get(p + 1)? } else { 0xFF };
+        let order = [[first & 0xF, second & 0xF], [first >> 4, second >> 4]];
+        p += 1 + pp as usize;
+        for k in 0 .. m.piece_count {
+            let byte = *b.get(p)?;
+            t.items[0][f].pieces[k] = byte & 0xF;
+            t.items[1][f].pieces[k] = byte >> 4;
+            p += 1;
+        }
+        for (side, order) in t.items.iter_mut().zip(order).take(sides) {
+            syzygy_set_groups(&mut side[f], m, order, f);
+        }
+    }
+    p += p & 1;
+    for f in 0 .. files {
+        for i in 0 .. sides {
+            p = syzygy_set_sizes(&mut t.items[i][f], b, p)?;
+        }
+    }
+    if dtz {
+        let map_start = p;
+        for f in 0 .. files {
+            let d = &mut t.items[0][f];
+            if d.flags & FLAG_MAPPED == 0 {
+                continue;
+            }
+            for i in 0 .. 4 {
+                if d.flags & FLAG_WIDE != 0 {
+                    p += p & 1;
+                    d.map_idx[i] = (p - map_start) / 2 + 1;
+                    p += 2 * u16_le(b, p)? as usize + 2;
+                } else {
+                    d.map_idx[i] = p - map_start + 1;
+                    p += *b.get(p)? as usize + 1;
+                }
+            }
+        }
+        p += p & 1;
+        t.dtz_map = b.get(map_start .. p)?.to_vec();
+    }
+    let mut p = p as u64;
+    for f in 0 .. files {
+        for i in 0 .. sides {
+            t.items[i][f].sparse_index = p;
+            p += 6 * t.items[i][f].sparse_index_size;
+        }
+    }
+    for f in 0 .. files {
+        for i in 0 .. sides {
+            t.items[i][f].block_length = p;
+            p += 2 * t.items[i][f].block_length_size;
+        }
+    }
+    for f in 0 .. files {
+        for i in 0 .. sides {
+            p = (p + 0x3F) & !0x3F;
+            t.items[i][f].data = p;
+            p += t.items[i][f].num_blocks * t.items[i][f].block_size;
+        }
+    }
+    Some(())
+}
+
+fn syzygy_open(path: &Path, m: &SyzygyMaterial, dtz: bool) -> Option<SyzygyTable> {
+    let mut file = File::open(path).ok()?;
+    let mut header = Vec::new();
+    (&mut file).take(MAX_HEADER_SIZE).read_to_end(&mut header).ok()?;
+    let mut table = SyzygyTable{file: Mutex::new(file), items: Default::default(), dtz_map: Vec::new()};
+    syzygy_parse_header(&mut table, &header, m, dtz)?;
+    Some(table)
+}
+
+// The value at idx: found through the sparse index and the block
+// lengths, then decoded from its block.
+fn syzygy_decompress(t: &SyzygyTable, d: &PairsData, idx: u64) -> Option<u32> {
+    if d.flags & FLAG_SINGLE_VALUE != 0 {
+        return Some(d.single_value as u32);
+    }
+    // Sparse entry k points at value k * span + span / 2.
+    let k = idx / d.span;
+    let mut entry = [0; 6];
+    syzygy_read(&t.file, d.sparse_index + 6 * k, &mut entry)?;
+    let mut block = u32_le(&entry, 0)? as u64;
+    let mut offset = u16_le(&entry, 4)? as i64 + (idx % d.span) as i64 - (d.span / 2) as i64;
+    let block_length = |block: u64| -> Option<i64> {
+        let mut bytes = [0; 2];
+        syzygy_read(&t.file, d.block_length + 2 * block, &mut bytes)?;
+        Some(u16::from_le_bytes(bytes) as i64)
+    };
+    while offset < 0 {
+        block = block.checked_sub(1)?;
+        offset += block_length(block)? + 1;
+    }
+    loop {
+        let length = block_length(block)?;
+        if offset <= length {
+            break;
+        }
+        offset -= length + 1;
+        block += 1;
+    }
+    let mut bytes = vec![0; d.block_size as usize + 8];
+    syzygy_read(&t.file, d.data + block * d.block_size, &mut bytes[.. d.block_size as usize])?;
+    let word = |i: usize| bytes.get(4 * i .. 4 * i + 4).map_or(0, |w| u32::from_be_bytes(w.try_into().unwrap()));
+    let mut buf = ((word(0) as u64) << 32) | word(1) as u64;
+    let mut buf_size = 64;
+    let mut next_word = 2;
+    let mut sym;
+    loop {
+        let mut len = 0;
+        while buf < *d.base64.get(len)? {
+            len += 1;
+        }
+        sym = ((buf - d.base64[len]) >> (64 - len as u32 - d.min_sym_len)) as usize + d.lowest_sym[len] as usize;
+        let count = *d.symlen.get(sym)? as i64 + 1;
+        if offset < count {
+            break;
+        }
+        offset -= count;
+        let bits = len as u32 + d.min_sym_len;
+        buf <<= bits;
+        buf_size -= bits;
+        if buf_size <= 32 {
+            buf_size += 32;
+            buf |= (word(next_word) as u64) << (64 - buf_size);
+            next_word += 1;
+        }
+    }
+    // Down the pairs the symbol expands to, to the one holding offset.
+    while d.symlen[sym] != 0 {
+        let left = d.btree[sym][0] as usize;
+        let count = *d.symlen.get(left)? as i64 + 1;
+        if offset < count {
+            sym = left;
+        } else {
+            offset -= count;
+            sym = d.btree[sym][1] as usize;
+        }
+    }
+    Some(d.btree[sym][0] as u32)
+}
+
+// 1 to 6 for white pawn, knight, bishop, rook, queen and king, plus 8
+// for black, as the files have them.
+fn syzygy_piece_code(piece: Piece) -> u8 {
+    let code = match piece_base(piece) {
+        P_BASE => 1,
+        N_BASE => 2,
+        B_BASE => 3,
+        R_BASE => 4,
+        Q_BASE => 5,
+        _ => 6,
+    };
+    if piece > 0 { code } else { code + 8 }
+}
+
+// The entry for the men of pos, and whether black has the men that the
+// table gives white.
+fn syzygy_entry<'a>(sz: &'a Syzygy, pos: &Position) -> Option<(&'a SyzygyEntry, bool)> {
+    let mut pieces = Vec::new();
+    for_each_sq_in_bb(occupied_bb(pos), |sq| pieces.push(piece_at_sq(pos, sq)));
+    let name = tb_signature(&pieces);
+    if let Some(entry) = sz.entries.get(&name) {
+        return Some((entry, false));
+    }
+    let (white, black) = name.split_once('v')?;
+    sz.entries.get(&format!("{}v{}", black, white)).map(|entry| (entry, true))
+}
+
+// The side to move, file and index of pos in table t, or None if the
+// table has the other side to move. The board is turned so that the
+// leading man stands where the index has it, as in the generator.
+fn syzygy_index(
+    t: &SyzygyTable, m: &SyzygyMaterial, pos: &Position, black_stronger: bool, dtz: bool,
+) -> Option<(usize, usize, u64)> {
+    let x = &*SYZYGY_INDEX;
+    let black_to_move = pos.active_color == COLOR_BLACK;
+    // Tables have white as the stronger side, and symmetric ones white to
+    // move only.
+    let flip = (m.symmetric && black_to_move) || black_stronger;
+    let flip_color = if flip { 8 } else { 0 };
+    let flip_squares = if flip { 56 } else { 0 };
+    let stm = (flip != black_to_move) as usize;
+    let mut squares = [0; SYZYGY_MAX_MEN];
+    let mut pieces = [0; SYZYGY_MAX_MEN];
+    let mut size = 0;
+    let mut lead_count = 0;
+    let mut lead_pawns = 0;
+    let mut file = 0;
+    if m.has_pawns {
+        // The leading pawns come first in every file's table.
+        let code = t.items[0][0].pieces[0] ^ flip_color;
+        let color = if code & 8 == 0 { COLOR_WHITE } else { COLOR_BLACK };
+        lead_pawns = pieces_bb(pos, P_BASE * color);
+        for_each_sq_in_bb(lead_pawns, |sq| {
+            squares[size] = sq ^ flip_squares;
+            size += 1;
+        });
+        lead_count = size;
+        let lead = (0 .. lead_count).max_by_key(|&i| x.map_pawns[squares[i] as usize])?;
+        squares.swap(0, lead);
+        file = ((squares[0] & 7).min(7 - (squares[0] & 7))) as usize;
+    }
+    let d = &t.items[if dtz { 0 } else { stm }][file];
+    if dtz && (d.flags & FLAG_STM) as usize != stm && (m.has_pawns || !m.symmetric) {
+        return None;
+    }
+    for_each_sq_in_bb(occupied_bb(pos) & !lead_pawns, |sq| {
+        if size < SYZYGY_MAX_MEN {
+            squares[size] = sq ^ flip_squares;
+            pieces[size] = syzygy_piece_code(piece_at_sq(pos, sq)) ^ flip_color;
+        }
+        size += 1;
+    });
+    if size != m.piece_count {
+        return None;
+    }
+    // Into the order of the table.
+    for i in lead_count .. size.saturating_sub(1) {
+        for j in i + 1 .. size {
+            if d.pieces[i] == pieces[j] {
+                pieces.swap(i, j);
+                squares.swap(i, j);
+                break;
+            }
+        }
+    }
+    if squares[0] & 7 > 3 {
+        for sq in squares[.. size].iter_mut() {
+            *sq = flip_file(*sq);
+        }
+    }
+    let mut idx;
+    if m.has_pawns {
+        idx = x.lead_pawn_idx[lead_count][squares[0] as usize];
+        squares[1 .. lead_count].sort_by_key(|&sq| x.map_pawns[sq as usize]);
+        for (i, &sq) in squares.iter().enumerate().take(lead_count).skip(1) {
+            idx += x.binomial[i][x.map_pawns[sq as usize] as usize];
+        }
+    } else {
+        if squares[0] >> 3 > 3 {
+            for sq in squares[.. size].iter_mut() {
+                *sq = flip_rank(*sq);
+            }
+        }
+        // The first man of the leading group off the diagonal goes below it.
+        for i in 0 .. d.group_len[0] {
+            if off_a1h8(squares[i]) == 0 {
+                continue;
+            }
+            if off_a1h8(squares[i]) > 0 {
+                for sq in squares[i .. size].iter_mut() {
+                    *sq = ((*sq >> 3) | (*sq << 3)) & 63;
+                }
+            }
+            break;
+        }
+        if m.has_unique_pieces {
+            let [s0, s1, s2] = [squares[0], squares[1], squares[2]];
+            let adjust1 = (s1 > s0) as u64;
+            let adjust2 = (s2 > s0) as u64 + (s2 > s1) as u64;
+            let rank = |sq: Sq| (sq >> 3) as u64;
+            let (s1u, s2u) = (s1 as u64, s2 as u64);
+            idx = if off_a1h8(s0) != 0 {
+                (x.map_a1d1d4[s0 as usize] * 63 + s1u - adjust1) * 62 + s2u - adjust2
+            } else if off_a1h8(s1) != 0 {
+                (6 * 63 + rank(s0) * 28 + x.map_b1h1h7[s1 as usize]) * 62 + s2u - adjust2
+            } else if off_a1h8(s2) != 0 {
+                6 * 63 * 62 + 4 * 28 * 62 + rank(s0) * 7 * 28 + (rank(s1) - adjust1) * 28
+                    + x.map_b1h1h7[s2 as usize]
+            } else {
+                6 * 63 * 62 + 4 * 28 * 62 + 4 * 7 * 28 + rank(s0) * 7 * 6 + (rank(s1) - adjust1) * 6
+                    + rank(s2) - adjust2
+            };
+        } else {
+            idx = x.map_kk[x.map_a1d1d4[squares[0] as usize] as usize][squares[1] as usize];
+        }
+    }
+    // The other groups, each as a combination of the squares left over.
+    idx *= d.group_idx[0];
+    let mut start = d.group_len[0];
+    let mut remaining_pawns = m.has_pawns && m.pawn_count[1] > 0;
+    let mut next = 1;
+    while d.group_len[next] != 0 {
+        let len = d.group_len[next];
+        squares[start .. start + len].sort_unstable();
+        let mut n = 0;
+        for i in 0 .. len {
+            let sq = squares[start + i];
+            let adjust = squares[.. start].iter().filter(|&&s| sq > s).count();
+            n += x.binomial[i + 1][sq as usize - adjust - 8 * remaining_pawns as usize];
+        }
+        remaining_pawns = false;
+        idx += n * d.group_idx[next];
+        start += len;
+        next += 1;
+    }
+    Some((stm, file, idx))
+}
+
+// The DTZ in plies of a stored value, for a position with result wdl.
+fn syzygy_dtz_value(t: &SyzygyTable, d: &PairsData, value: u32, wdl: i32) -> Option<i32> {
+    const WDL_MAP: [usize; 5] = [1, 3, 0, 2, 0];
+    let mut value = value as i32;
+    if d.flags & FLAG_MAPPED != 0 {
+        let i = d.map_idx[WDL_MAP[(wdl + 2) as usize]] + value as usize;
+        value = if d.flags & FLAG_WIDE != 0 {
+            u16_le(&t.dtz_map, 2 * i)? as i32
+        } else {
+            *t.dtz_map.get(i)? as i32
+        };
+    }
+    if (wdl == WDL_WIN && d.flags & FLAG_WIN_PLIES == 0)
+        || (wdl == WDL_LOSS && d.flags & FLAG_LOSS_PLIES == 0)
+        || wdl == WDL_CURSED_WIN || wdl == WDL_BLESSED_LOSS {
+        value *= 2;
+    }
+    Some(value + 1)
+}
+
+// The stored value of pos: its WDL, or its DTZ given its WDL. None if
+// there is no table or it cannot be read.
+fn syzygy_probe_table(sz: &Syzygy, pos: &Position, dtz: bool, wdl: i32) -> Option<TableProbe> {
+    if bb_popcount(occupied_bb(pos)) == 2 {
+        return Some(TableProbe::Value(WDL_DRAW));
+    }
+    let (entry, black_stronger) = syzygy_entry(sz, pos)?;
+    let m = &entry.material;
+    let table = if dtz {
+        entry.dtz.get_or_init(|| syzygy_open(&entry.dtz_path, m, true))
+    } else {
+        entry.wdl.get_or_init(|| syzygy_open(&entry.wdl_path, m, false))
+    }.as_ref()?;
+    if dtz {
+        // Checked apart from the index, which would fail the same way for a
+        // bad position.
+        let mut flipped = copy_position(pos);
+        flipped.active_color = -flipped.active_color;
+        if syzygy_index(table, m, pos, black_stronger, true).is_none()
+            && syzygy_index(table, m, &flipped, black_stronger, true).is_some() {
+            return Some(TableProbe::ChangeStm);
+        }
+    }
+    let (stm, file, idx) = syzygy_index(table, m, pos, black_stronger, dtz)?;
+    let d = &table.items[if dtz { 0 } else { stm }][file];
+    let value = syzygy_decompress(table, d, idx)?;
+    Some(TableProbe::Value(if dtz { syzygy_dtz_value(table, d, value, wdl)? } else { value as i32 - 2 }))
+}
+
+fn is_capture(pos: &Position, mov: &Move) -> bool {
+    piece_at_sq(pos, mov.to) != EMPTY || is_move_en_passant(pos, mov)
+}
+
+// The WDL of pos, searching captures (and pawn moves, when zeroing) as
+// the tables do not know en passant and store anything where a capture
+// wins. Also returns whether such a move is the best one.
+fn syzygy_search(sz: &Syzygy, pos: &Position, zeroing: bool) -> Option<(i32, bool)> {
+    let list = generate_legal_moves(pos);
+    let mut best = WDL_LOSS;
+    let mut searched = 0;
+    for &pm in move_list_slice(&list) {
+        let mov = unpack_move(pos, pm);
+        if !is_capture(pos, &mov) && (!zeroing || piece_base(mov.piece) != P_BASE) {
+            continue;
+        }
+        searched += 1;
+        let mut child = copy_position(pos);
+        make_move(&mut child, &mov);
+        let value = -syzygy_search(sz, &child, false)?.0;
+        if value > best {
+            best = value;
+            if value >= WDL_WIN {
+                return Some((value, true));
+            }
+        }
+    }
+    let all_searched = searched > 0 && searched == list.len;
+    let value = if all_searched {
+        best
+    } else {
+        match syzygy_probe_table(sz, pos, false, 0)? {
+            TableProbe::Value(value) => value,
+            TableProbe::ChangeStm => return None,
+        }
+    };
+    if best >= value {
+        return Some((best, best > WDL_DRAW || all_searched));
+    }
+    Some((value, false))
+}
+
+// WDL_LOSS ..= WDL_WIN for the side to move, or None if the tables
+// cannot tell.
+pub fn syzygy_probe_wdl(sz: &Syzygy, pos: &Position) -> Option<i32> {
+    syzygy_search(sz, pos, false).map(|(wdl, _)| wdl)
+}
+
+// The DTZ of a position whose best move zeroes the counter.
+fn dtz_before_zeroing(wdl: i32) -> i32 {
+    match wdl {
+        WDL_WIN => 1,
+        WDL_CURSED_WIN => 101,
+        WDL_BLESSED_LOSS => -101,
+        WDL_LOSS => -1,
+        _ => 0,
+    }
+}
+
+// Plies to the next capture or pawn move with best play, positive when
+// the side to move wins and past 100 for results the fifty move rule
+// spoils; 0 for a draw, -1 when mated. None if the tables cannot tell.
+pub fn syzygy_probe_dtz(sz: &Syzygy, pos: &Position) -> Option<i32> {
+    let (wdl, zeroing_best) = syzygy_search(sz, pos, true)?;
+    if wdl == WDL_DRAW {
+        return Some(0);
+    }
+    if zeroing_best {
+        return Some(dtz_before_zeroing(wdl));
+    }
+    if let TableProbe::Value(dtz) = syzygy_probe_table(sz, pos, true, wdl)? {
+        let cursed = wdl == WDL_BLESSED_LOSS || wdl == WDL_CURSED_WIN;
+        return Some((dtz + if cursed { 100 } else { 0 }) * wdl.signum());
+    }
+    // The table has the other side to move: the best reply decides.
+    let mut min_dtz = 0xFFFF;
+    let list = generate_legal_moves(pos);
+    for &pm in move_list_slice(&list) {
+        let mov = unpack_move(pos, pm);
+        let zeroing = is_capture(pos, &mov) || piece_base(mov.piece) == P_BASE;
+        let mut child = copy_position(pos);
+        make_move(&mut child, &mov);
+        let mut dtz = if zeroing {
+            -dtz_before_zeroing(syzygy_search(sz, &child, false)?.0)
+        } else {
+            -syzygy_probe_dtz(sz, &child)?
+        };
+        if dtz == 1 && is_king_in_check(&child, false) && generate_legal_moves(&child).len == 0 {
+            min_dtz = 1;
+        }
+        if !zeroing {
+            dtz += dtz.signum();
+        }
+        if dtz < min_dtz && dtz.signum() == wdl.signum() {
+            min_dtz = dtz;
+        }
+    }
+    Some(if min_dtz == 0xFFFF { -1 } else { min_dtz })
+}
+
+fn syzygy_rank_score(rank: i32, bound: i32) -> Score {
+    if rank >= bound {
+        MATE_BOUND - 1
+    } else if rank > 0 {
+        rank.saturating_sub(800).max(3) * PIECE_VALUES[0] / 200
+    } else if rank == 0 {
+        0
+    } else if rank > -bound {
+        (rank + 800).min(-3) * PIECE_VALUES[0] / 200
+    } else {
+        -MATE_BOUND + 1
+    }
+}
+
+// Ranks the legal moves of pos by DTZ: every win that stays within the
+// fifty moves ranks 1000, later ones less, draws 0 and losses below. The
+// score to show for each goes with it. repeated tells whether a position
+// has repeated since the last capture or pawn move, which makes the
+// quickest win the only safe one.
+fn syzygy_rank_by_dtz(sz: &Syzygy, pos: &Position, repeated: bool) -> Option<RankedMoves> {
+    let halfmoves = pos.halfmoves;
+    let bound = if sz.rule50 { 900 } else { 1 };
+    let mut ranked = Vec::new();
+    for &pm in move_list_slice(&generate_legal_moves(pos)) {
+        let mut child = copy_position(pos);
+        make_move(&mut child, &unpack_move(pos, pm));
+        let mut dtz = if child.halfmoves == 0 {
+            dtz_before_zeroing(-syzygy_probe_wdl(sz, &child)?)
+        } else {
+            let dtz = -syzygy_probe_dtz(sz, &child)?;
+            dtz + dtz.signum()
+        };
+        if dtz == 2 && is_king_in_check(&child, false) && generate_legal_moves(&child).len == 0 {
+            dtz = 1;
+        }
+        let rank = if dtz > 0 {
+            if dtz + halfmoves <= 99 && !repeated { 1000 } else { 1000 - (dtz + halfmoves) }
+        } else if dtz < 0 {
+            if -dtz * 2 + halfmoves < 100 { -1000 } else { -1000 + (-dtz + halfmoves) }
+        } else {
+            0
+        };
+        ranked.push((pm, rank, syzygy_rank_score(rank, bound)));
+    }
+    Some(ranked)
+}
+
+// As syzygy_rank_by_dtz with only WDL files.
+fn syzygy_rank_by_wdl(sz: &Syzygy, pos: &Position) -> Option<RankedMoves> {
+    const WDL_TO_RANK: [i32; 5] = [-1000, -899, 0, 899, 1000];
+    const WDL_TO_SCORE: [Score; 5] = [-MATE_BOUND + 1, -2, 0, 2, MATE_BOUND - 1];
+    let mut ranked = Vec::new();
+    for &pm in move_list_slice(&generate_legal_moves(pos)) {
+        let mut child = copy_position(pos);
+        make_move(&mut child, &unpack_move(pos, pm));
+        let mut wdl = -syzygy_probe_wdl(sz, &child)?;
+        let rank = WDL_TO_RANK[(wdl + 2) as usize];
+        if !sz.rule50 {
+            wdl = 2 * wdl.signum();
+        }
+        ranked.push((pm, rank, WDL_TO_SCORE[(wdl + 2) as usize]));
+    }
+    Some(ranked)
+}
+
+// Ranks the legal moves at the root, by DTZ if its files are there and
+// by WDL if not, with the score to show for each. Also returns whether
+// DTZ was used. None when pos has castling rights, too many men or no
+// table.
+pub fn syzygy_rank_root_moves(
+    sz: &Syzygy, pos: &Position, repeated: bool,
+) -> Option<(RankedMoves, bool)> {
+    let men = bb_popcount(occupied_bb(pos)) as usize;
+    if men > sz.max_men.min(sz.probe_limit) || pos.castling.iter().any(|&c| c) {
+        return None;
+    }
+    if let Some(ranked) = syzygy_rank_by_dtz(sz, pos, repeated) {
+        return Some((ranked, true));
+    }
+    syzygy_rank_by_wdl(sz, pos).map(|ranked| (ranked, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    // The side to move, file and index of a position in a table.
+    type TestIndexFn<'a> = dyn Fn(&Position) -> Option<(usize, usize, u64)> + 'a;
+
+    // Three men on different squares, the kings apart and no pawn on the
+    // first or last rank.
+    fn is_test_placement(pos: &Position) -> bool {
+        let white_king = pieces_bb(pos, K_BASE);
+        let pawns = pieces_bb(pos, P_BASE) | pieces_bb(pos, -P_BASE);
+        bb_popcount(occupied_bb(pos)) == 3 && white_king != 0
+            && king_attacks(white_king.trailing_zeros() as Sq) & pieces_bb(pos, -K_BASE) == 0
+            && pawns & 0xFF000000000000FF == 0
+    }
+
+    // The file of a table of three different men as the generator would
+    // write it, with values[file][side][index] as fixed length codes of
+    // bits bits and no pairs. Pawn tables have one table per file of the
+    // pawn, a to d.
+    fn test_table_bytes(pieces: [u8; 3], dtz: bool, flags: u8, bits: u8, values: &[Vec<Vec<u8>>]) -> Vec<u8> {
+        const BLOCK_BITS: u8 = 5;
+        const SPAN_BITS: u8 = 10;
+        let has_pawns = pieces[0] & 7 == 1;
+        let size = if has_pawns { 6 * 63 * 62 } else { 31332 };
+        let per_block = (8 << BLOCK_BITS) / bits as u64;
+        let num_blocks = (size as u64).div_ceil(per_block);
+        let padding = ((1 << SPAN_BITS) / per_block + 1) as u8;
+        let sparse_size = (size as u64).div_ceil(1 << SPAN_BITS);
+        let mut b = Vec::new();
+        b.extend_from_slice(if dtz { &DTZ_MAGIC } else { &WDL_MAGIC });
+        b.push(if has_pawns { 3 } else { 1 });
+        for _ in values {
+            b.push(0);
+            b.extend(pieces.iter().map(|&p| p | p << 4));
+        }
+        b.resize(b.len().next_multiple_of(2), 0);
+        for _ in values.iter().flatten() {
+            b.extend_from_slice(&[flags, BLOCK_BITS, SPAN_BITS, padding]);
+            b.extend_from_slice(&(num_blocks as u32).to_le_bytes());
+            b.extend_from_slice(&[bits, bits, 0, 0]);
+            b.extend_from_slice(&(1u16 << bits).to_le_bytes());
+            for sym in 0 .. 1u16 << bits {
+                b.extend_from_slice(&[sym as u8, (sym >> 8) as u8 | 0xF0, 0xFF]);
+            }
+        }
+        for _ in values.iter().flatten() {
+            for k in 0 .. sparse_size {
+                let i = k * (1 << SPAN_BITS) + (1 << (SPAN_BITS - 1));
+                b.extend_from_slice(&((i / per_block) as u32).to_le_bytes());
+                b.extend_from_slice(&((i % per_block) as u16).to_le_bytes());
+            }
+        }
+        for _ in values.iter().flatten() {
+            for _ in 0 .. num_blocks + padding as u64 {
+                b.extend_from_slice(&(per_block as u16 - 1).to_le_bytes());
+            }
+        }
+        for side in values.iter().flatten() {
+            b.resize(b.len().next_multiple_of(64), 0);
+            for block in 0 .. num_blocks {
+                let mut bits_out = vec![false; 8 << BLOCK_BITS];
+                for j in 0 .. per_block {
+                    let value = side.get((block * per_block + j) as usize).copied().unwrap_or(0);
+                    for bit in 0 .. bits as u64 {
+                        bits_out[(j * bits as u64 + bit) as usize] = value >> (bits as u64 - 1 - bit) & 1 != 0;
+                    }
+                }
+                b.extend(bits_out.chunks(8).map(|c| c.iter().fold(0u8, |byte, &bit| byte << 1 | bit as u8)));
+            }
+        }
+        b
+    }
+
+    // Writes the table of test_table_bytes with the value of every test
+    // placement of the men, white's first, at the side, file and index
+    // that index_of gives it.
+    fn write_test_table(
+        path: &Path, pieces: [u8; 3], dtz: bool, flags: u8, bits: u8,
+        index_of: &TestIndexFn<'_>,
+        value_of: &dyn Fn(&Position) -> Option<u8>,
+    ) {
+        let files = if pieces[0] & 7 == 1 { 4 } else { 1 };
+        let size = if files == 4 { 6 * 63 * 62 } else { 31332 };
+        let mut values = vec![vec![vec![u8::MAX; size]; if dtz { 1 } else { 2 }]; files];
+        for stm in [COLOR_WHITE, COLOR_BLACK] {
+            for squares in (0 .. 64 * 64 * 64).map(|i| [i % 64, i / 64 % 64, i / 4096]) {
+                let mut pos = empty_position();
+                pos.active_color = stm;
+                for (&sq, &code) in squares.iter().zip(pieces.iter()) {
+                    let base = [P_BASE, N_BASE, B_BASE, R_BASE, Q_BASE, K_BASE][(code & 7) as usize - 1];
+                    set_piece_at_sq(&mut pos, sq as Sq, if code & 8 == 0 { base } else { -base });
+                }
+                if !is_test_placement(&pos) {
+                    continue;
+                }
+                let (Some(value), Some((side, file, idx))) = (value_of(&pos), index_of(&pos)) else {
+                    continue;
+                };
+                let stored = &mut values[file][side][idx as usize];
+                assert!(*stored == u8::MAX || *stored == value, "{}", encode_fen(&pos));
+                *stored = value;
+            }
+        }
+        for value in values.iter_mut().flatten().flatten().filter(|v| **v == u8::MAX) {
+            *value = 0;
+        }
+        File::create(path).unwrap().write_all(&test_table_bytes(pieces, dtz, flags, bits, &values)).unwrap();
+    }
+
+    // Writes WDL and DTZ tables for KRvK from the generated one and probes
+    // them for every position, with each side stronger.
+    #[test]
+    fn syzygy() {
+        let x = &*SYZYGY_INDEX;
+        assert_eq!(x.map_kk.iter().flatten().max(), Some(&461));
+        assert_eq!(x.map_pawns[8], 47);
+        assert_eq!(x.map_pawns[55], 36);
+        assert_eq!(x.lead_pawns_size[1], [6, 6, 6, 6]);
+        assert_eq!(x.binomial[2][5], 10);
+        assert!(syzygy_material("KQvK").unwrap().has_unique_pieces);
+        assert!(!syzygy_material("KRRvKNN").unwrap().has_unique_pieces);
+        assert_eq!(syzygy_material("KPvKPP").unwrap().pawn_count, [1, 2]);
+        assert_eq!(syzygy_material("KPPvKP").unwrap().pawn_count, [1, 2]);
+        assert!(syzygy_material("KvK8").is_none());
+        let mut tbs = new_tablebases();
+        generate_tablebase(&mut tbs, "KRvK").unwrap();
+        let dir = std::env::temp_dir().join(format!("syzygy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let wdl = |pos: &Position| tb_probe(&tbs, pos).map(|outcome| match outcome {
+            TbOutcome::Win(_) => 4,
+            TbOutcome::Draw => 2,
+            TbOutcome::Loss(_) => 0,
+        });
+        // In plies, less one, for white to move: white has nothing to capture.
+        let dtz = |pos: &Position| match tb_probe(&tbs, pos) {
+            Some(TbOutcome::Win(moves)) if pos.active_color == COLOR_WHITE => Some(2 * moves as u8 - 2),
+            Some(_) if pos.active_color == COLOR_WHITE => Some(0),
+            _ => None,
+        };
+        // The index of every position comes from the table itself, read
+        // back with empty data, so this checks the layout and the decoding,
+        // not that the index agrees with real files.
+        let m = syzygy_material("KRvK").unwrap();
+        let layout = |dtz: bool, flags: u8| {
+            let mut table = SyzygyTable{
+                file: Mutex::new(File::open(std::env::current_exe().unwrap()).unwrap()),
+                items: Default::default(),
+                dtz_map: Vec::new(),
+            };
+            let empty = vec![vec![Vec::new(); if dtz { 1 } else { 2 }]];
+            syzygy_parse_header(&mut table, &test_table_bytes([6, 4, 14], dtz, flags, 1, &empty), &m, dtz).unwrap();
+            table
+        };
+        let (wdl_layout, dtz_layout) = (layout(false, 0), layout(true, FLAG_WIN_PLIES));
+        write_test_table(&dir.join("KRvK.rtbw"), [6, 4, 14], false, 0, 3,
+            &|pos| syzygy_index(&wdl_layout, &m, pos, false, false), &wdl);
+        write_test_table(&dir.join("KRvK.rtbz"), [6, 4, 14], true, FLAG_WIN_PLIES, 5,
+            &|pos| syzygy_index(&dtz_layout, &m, pos, false, true), &dtz);
+        let mut sz = new_syzygy();
+        assert_eq!(syzygy_init(&mut sz, dir.to_str().unwrap()), 1);
+        assert_eq!(sz.max_men, 3);
+        for i in (0 .. 64 * 64 * 64 * 2).step_by(7) {
+            let squares = [i % 64, i / 64 % 64, i / 4096 % 64];
+            for black_stronger in [false, true] {
+                let mut pos = empty_position();
+                pos.active_color = if i / 262144 == 0 { COLOR_WHITE } else { COLOR_BLACK };
+                let sign = if black_stronger { -1 } else { 1 };
+                for (&sq, piece) in squares.iter().zip([K_BASE * sign, R_BASE * sign, -K_BASE * sign]) {
+                    set_piece_at_sq(&mut pos, (if black_stronger { sq ^ 56 } else { sq }) as Sq, piece);
+                }
+                let Some(outcome) = (if is_test_placement(&pos) { tb_probe(&tbs, &pos) } else { None }) else {
+                    continue;
+                };
+                let expected = match outcome {
+                    TbOutcome::Win(_) => WDL_WIN,
+                    TbOutcome::Draw => WDL_DRAW,
+                    TbOutcome::Loss(_) => WDL_LOSS,
+                };
+                assert_eq!(syzygy_probe_wdl(&sz, &pos), Some(expected), "{}", encode_fen(&pos));
+                if i % 5 != 0 {
+                    continue;
+                }
+                let expected = match outcome {
+                    TbOutcome::Win(moves) => 2 * moves as i32 - 1,
+                    TbOutcome::Draw => 0,
+                    TbOutcome::Loss(0) => -1,
+                    TbOutcome::Loss(moves) => -2 * moves as i32,
+                };
+                assert_eq!(syzygy_probe_dtz(&sz, &pos), Some(expected), "{}", encode_fen(&pos));
+            }
+        }
+        // Every winning move is kept while the fifty moves leave room, and
+        // only the quickest when they do not.
+        let mut pos = decode_fen(String::from("8/8/8/8/8/2k5/1R6/K7 w - - 0 1"));
+        let (ranked, by_dtz) = syzygy_rank_root_moves(&sz, &pos, false).unwrap();
+        assert!(by_dtz);
+        let wins = ranked.iter().filter(|(_, rank, _)| *rank == 1000).count();
+        assert!(wins > 1 && ranked.iter().all(|(_, rank, _)| *rank == 1000 || *rank == 0));
+        pos.halfmoves = 90;
+        let (ranked, _) = syzygy_rank_root_moves(&sz, &pos, false).unwrap();
+        let best = ranked.iter().map(|(_, rank, _)| *rank).max().unwrap();
+        assert_eq!(best, 1000 - 31 - 90);
+        assert_eq!(syzygy_rank_score(best, 900), 39);
+        // At the root the search keeps to the winning moves and shows the
+        // win; below it a capture into the table wins at once.
+        let mut s = new_searcher(1);
+        s.syzygy = sz;
+        let mut limits = default_search_limits();
+        limits.depth = Some(2);
+        let run = |s: &mut Searcher, fen: &str| {
+            let pos = decode_fen(String::from(fen));
+            let result = search(s, &pos, &[], &limits, &mut |_| {});
+            (move_to_string(&unpack_move(&pos, result.best_move), &pos), result.score)
+        };
+        assert_eq!(run(&mut s, "8/8/8/8/8/2k5/1R6/K7 w - - 0 1").1, MATE_BOUND - 1);
+        assert_eq!(run(&mut s, "8/1n6/8/8/8/2k5/1R6/K7 w - - 0 1"), (String::from("Rxb7"), MATE_BOUND - 2));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The index of a KPvK position as the published layout has it, worked
+    // out here rather than with SYZYGY_INDEX: with the board turned so that
+    // the pawn is white and on files a to d, the rank of the pawn, then
+    // each king on the squares left by the men before it.
+    fn kpvk_reference_index(pos: &Position) -> Option<(usize, usize, u64)> {
+        let color = if pieces_bb(pos, P_BASE) != 0 { COLOR_WHITE } else { COLOR_BLACK };
+        let flip = if color == COLOR_WHITE { 0 } else { 56 };
+        let square = |piece: Piece| pieces_bb(pos, piece * color).trailing_zeros() as Sq ^ flip;
+        let [mut p, mut k1, mut k2] = [square(P_BASE), square(K_BASE), square(-K_BASE)];
+        if p & 7 > 3 {
+            [p, k1, k2] = [p ^ 7, k1 ^ 7, k2 ^ 7];
+        }
+        let k1_idx = k1 as u64 - (k1 > p) as u64;
+        let k2_idx = k2 as u64 - (k2 > p) as u64 - (k2 > k1) as u64;
+        let side = (pos.active_color != color) as usize;
+        Some((side, (p & 7) as usize, (p >> 3) as u64 - 1 + 6 * k1_idx + 6 * 63 * k2_idx))
+    }
+
+    // Writes KPvK tables at the index above and probes them with the pawn
+    // on either side: the WDL against the generated table, and the DTZ,
+    // made up from the index as a pawn table holds no plain distances to
+    // check against, for the value stored there.
+    #[test]
+    fn syzygy_pawns() {
+        let mut tbs = new_tablebases();
+        generate_tablebase(&mut tbs, "KPvK").unwrap();
+        let dir = std::env::temp_dir().join(format!("syzygy-pawns-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let wdl = |pos: &Position| tb_probe(&tbs, pos).map(|outcome| match outcome {
+            TbOutcome::Win(_) => 4,
+            TbOutcome::Draw => 2,
+            TbOutcome::Loss(_) => 0,
+        });
+        let dtz = |pos: &Position| match kpvk_reference_index(pos) {
+            Some((0, _, idx)) => Some((idx % 31) as u8),
+            _ => None,
+        };
+        write_test_table(&dir.join("KPvK.rtbw"), [1, 6, 14], false, 0, 3, &kpvk_reference_index, &wdl);
+        write_test_table(&dir.join("KPvK.rtbz"), [1, 6, 14], true, FLAG_WIN_PLIES, 5, &kpvk_reference_index, &dtz);
+        let mut sz = new_syzygy();
+        assert_eq!(syzygy_init(&mut sz, dir.to_str().unwrap()), 1);
+        for i in (0 .. 64 * 64 * 64 * 2).step_by(3) {
+            let squares = [i % 64, i / 64 % 64, i / 4096 % 64];
+            for black_pawn in [false, true] {
+                let mut pos = empty_position();
+                pos.active_color = if i / 262144 == 0 { COLOR_WHITE } else { COLOR_BLACK };
+                let sign = if black_pawn { -1 } else { 1 };
+                for (&sq, piece) in squares.iter().zip([P_BASE * sign, K_BASE * sign, -K_BASE * sign]) {
+                    set_piece_at_sq(&mut pos, (if black_pawn { sq ^ 56 } else { sq }) as Sq, piece);
+                }
+                if !is_test_placement(&pos) || validate(&pos).is_err() {
+                    continue;
+                }
+                let Some(outcome) = tb_probe(&tbs, &pos) else {
+                    continue;
+                };
+                let expected = match outcome {
+                    TbOutcome::Win(_) => WDL_WIN,
+                    TbOutcome::Draw => WDL_DRAW,
+                    TbOutcome::Loss(_) => WDL_LOSS,
+                };
+                assert_eq!(syzygy_probe_wdl(&sz, &pos), Some(expected), "{}", encode_fen(&pos));
+                let probed = syzygy_probe_table(&sz, &pos, true, WDL_WIN);
+                match dtz(&pos) {
+                    Some(value) => assert!(matches!(probed, Some(TableProbe::Value(v)) if v == value as i32 + 1),
+                        "{}", encode_fen(&pos)),
+                    None => assert!(matches!(probed, Some(TableProbe::ChangeStm)), "{}", encode_fen(&pos)),
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Probes the real tables in SYZYGY_PATH, when set, where the results
+    // are plain: a capture, promotion or mate next for the winner, or a
+    // rook pawn against the king in the corner. A DTZ of None is not
+    // checked, as tables without exact plies can round a loss up a ply.
+    #[test]
+    fn syzygy_files() {
+        let Ok(path) = std::env::var("SYZYGY_PATH") else {
+            return;
+        };
+        let mut sz = new_syzygy();
+        assert!(syzygy_init(&mut sz, &path) > 0, "no tables in {}", path);
+        let positions = [
+            // KPvK
+            ("8/4P3/8/8/8/8/k7/4K3 w - - 0 1", WDL_WIN, Some(1)),
+            ("8/4P3/8/8/8/8/k7/4K3 b - - 0 1", WDL_LOSS, None),
+            ("k7/8/K7/P7/8/8/8/8 w - - 0 1", WDL_DRAW, Some(0)),
+            // KRPvKR
+            ("3r3k/8/P7/8/8/8/8/K2R4 w - - 0 1", WDL_WIN, Some(1)),
+            // KQvKR
+            ("3r3k/8/8/8/8/8/8/K2Q4 w - - 0 1", WDL_WIN, Some(1)),
+            // KBNvK
+            ("7k/B3N3/6K1/8/8/8/8/8 w - - 0 1", WDL_WIN, Some(1)),
+            ("7k/4N3/6K1/8/3B4/8/8/8 b - - 0 1", WDL_LOSS, None),
+            // KRPPvKP
+            ("8/4P3/8/k7/8/p7/P7/K6R w - - 0 1", WDL_WIN, Some(1)),
+            ("8/4P3/8/k7/8/p7/P7/K6R b - - 0 1", WDL_LOSS, None),
+        ];
+        for (fen, wdl, dtz) in positions {
+            let pos = decode_fen(String::from(fen));
+            assert_eq!(syzygy_probe_wdl(&sz, &pos), Some(wdl), "{}", fen);
+            let probed = syzygy_probe_dtz(&sz, &pos);
+            match dtz {
+                Some(dtz) => assert_eq!(probed, Some(dtz), "{}", fen),
+                None => assert_eq!(probed.map(i32::signum), Some(wdl.signum()), "{}", fen),
+            }
+        }
+    }
+}
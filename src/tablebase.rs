@@ -0,0 +1,841 @@
+// Endgame tablebases of up to five men, computed by retrograde analysis.
+// A table holds, for every position of one material signature such as
+// KQvK or KRPvKR, whether the side to move wins, draws or loses and in
+// how many moves it mates or is mated. Positions are indexed by the
+// squares of the pieces, reduced by symmetry: eight ways for pawnless
+// tables, left-right mirroring with pawns. Castling and en passant are
+// left out and the fifty move rule is ignored.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::*;
+
+pub const TB_MAX_MEN: usize = 5;
+// The value of a position. 1 ..= 127: the side to move mates in that many
+// moves; TB_LOSS + n: it is mated in n moves.
+pub const TB_DRAW: u8 = 0;
+const TB_LOSS: u8 = 128;
+const TB_UNKNOWN: u8 = 254;
+const TB_ILLEGAL: u8 = 255;
+const TB_MAGIC: &[u8; 4] = b"OXTB";
+const TB_VERSION: u8 = 1;
+// In place of the longest out-of-table win of the opponent, for positions
+// with a drawing or winning way out of the table.
+const NEVER_LOSES: u8 = 255;
+// The order of the pieces of a side, in signatures and in the index.
+const TB_PIECE_ORDER: [Piece; 6] = [K_BASE, Q_BASE, R_BASE, B_BASE, N_BASE, P_BASE];
+const TB_PIECE_CHARS: [char; 6] = ['K', 'Q', 'R', 'B', 'N', 'P'];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TbOutcome {
+    // For the side to move, in moves to mate.
+    Win(u32),
+    Draw,
+    Loss(u32),
+}
+
+pub struct Tablebase {
+    pub signature: String,
+    // Signed, in index order: the white king, the black king, then the
+    // other white and black pieces in TB_PIECE_ORDER.
+    pieces: Vec<Piece>,
+    has_pawns: bool,
+    // 64 * white king + black king to king pair index, or -1, and back.
+    kk_index: Vec<i32>,
+    kk_squares: Vec<(Sq, Sq)>,
+    values: Vec<u8>,
+}
+
+pub struct Tablebases {
+    tables: HashMap<String, Tablebase>,
+}
+
+pub struct TbStats {
+    pub positions: u64,
+    pub wins: u64,
+    pub draws: u64,
+    pub losses: u64,
+    // The longest win, in moves, and a position with it.
+    pub max_dtm: u32,
+    pub max_dtm_fen: String,
+}
+
+pub fn new_tablebases() -> Tablebases {
+    Tablebases{tables: HashMap::new()}
+}
+
+pub fn tablebase_signatures(tbs: &Tablebases) -> Vec<String> {
+    let mut signatures: Vec<String> = tbs.tables.keys().cloned().collect();
+    signatures.sort();
+    signatures
+}
+
+fn tb_piece_rank(piece: Piece) -> usize {
+    TB_PIECE_ORDER.iter().position(|&pb| pb == piece_base(piece)).unwrap()
+}
+
+// Kings first, then white pieces, then black ones.
+fn tb_order_key(piece: Piece) -> usize {
+    match piece {
+        K_BASE => 0,
+        p if p == -K_BASE => 1,
+        p if p > 0 => 2 + tb_piece_rank(p),
+        p => 8 + tb_piece_rank(p),
+    }
+}
+
+fn tb_side_string(pieces: &[Piece], color: Color) -> String {
+    let mut side: Vec<Piece> = pieces.iter().copied().filter(|&p| p * color > 0).collect();
+    side.sort_by_key(|&p| tb_piece_rank(p));
+    side.iter().map(|&p| TB_PIECE_CHARS[tb_piece_rank(p)]).collect()
+}
+
+// Stronger sides compare greater: more pieces, then more material.
+fn tb_side_key(pieces: &[Piece], color: Color) -> (usize, Score, String) {
+    let side: Vec<Piece> = pieces.iter().copied().filter(|&p| p * color > 0).collect();
+    let material = side.iter()
+        .filter(|&&p| piece_base(p) != K_BASE)
+        .map(|&p| PIECE_VALUES[piece_index(p)])
+        .sum();
+    (side.len(), material, tb_side_string(pieces, color))
+}
+
+// Whether white is the stronger side, as tables are stored.
+fn tb_is_canonical(pieces: &[Piece]) -> bool {
+    tb_side_key(pieces, COLOR_WHITE) >= tb_side_key(pieces, COLOR_BLACK)
+}
+
+pub fn tb_signature(pieces: &[Piece]) -> String {
+    format!("{}v{}", tb_side_string(pieces, COLOR_WHITE), tb_side_string(pieces, COLOR_BLACK))
+}
+
+// The pieces of a signature such as "KRPvKR", white first. None unless
+// each side has one king, written first, and there are at most five men.
+pub fn tb_pieces_from_signature(signature: &str) -> Option<Vec<Piece>> {
+    let (white, black) = signature.split_once('v')?;
+    let mut pieces = Vec::new();
+    for (side, color) in [(white, COLOR_WHITE), (black, COLOR_BLACK)] {
+        if !side.starts_with('K') || side[1 ..].contains('K') {
+            return None;
+        }
+        for c in side.chars() {
+            let rank = TB_PIECE_CHARS.iter().position(|&pc| pc == c.to_ascii_uppercase())?;
+            pieces.push(TB_PIECE_ORDER[rank] * color);
+        }
+    }
+    if pieces.len() > TB_MAX_MEN {
+        return None;
+    }
+    pieces.sort_by_key(|&p| tb_order_key(p));
+    Some(pieces)
+}
+
+fn tb_file(sq: Sq) -> Sq {
+    sq & 7
+}
+
+fn tb_rank(sq: Sq) -> Sq {
+    sq >> 3
+}
+
+// One of the eight symmetries of the board: bit 0 mirrors the files, bit
+// 1 the ranks and bit 2 swaps files and ranks.
+fn tb_transform(sq: Sq, t: usize) -> Sq {
+    let mut sq = sq;
+    if t & 1 != 0 {
+        sq ^= 7;
+    }
+    if t & 2 != 0 {
+        sq ^= 56;
+    }
+    if t & 4 != 0 {
+        sq = (tb_file(sq) << 3) | tb_rank(sq);
+    }
+    sq
+}
+
+// The king placements a canonical index starts from. With pawns the
+// white king stays on files a-d; without, it stays in the a1-d1-d4
+// triangle, and the black king on or below the diagonal when the white
+// king is on it.
+fn tb_king_pairs(has_pawns: bool) -> (Vec<i32>, Vec<(Sq, Sq)>) {
+    let mut index = vec![-1; 64 * 64];
+    let mut squares = Vec::new();
+    for wk in 0 .. 64 {
+        let in_region = if has_pawns {
+            tb_file(wk) <= 3
+        } else {
+            tb_file(wk) <= 3 && tb_rank(wk) <= tb_file(wk)
+        };
+        if !in_region {
+            continue;
+        }
+        for bk in 0 .. 64 {
+            if bk == wk || king_attacks(wk) & sq_bb(bk) != 0 {
+                continue;
+            }
+            if !has_pawns && tb_file(wk) == tb_rank(wk) && tb_rank(bk) > tb_file(bk) {
+                continue;
+            }
+            index[64 * wk as usize + bk as usize] = squares.len() as i32;
+            squares.push((wk, bk));
+        }
+    }
+    (index, squares)
+}
+
+fn new_tablebase(pieces: Vec<Piece>) -> Tablebase {
+    let has_pawns = pieces.iter().any(|&p| piece_base(p) == P_BASE);
+    let (kk_index, kk_squares) = tb_king_pairs(has_pawns);
+    Tablebase{
+        signature: tb_signature(&pieces),
+        pieces,
+        has_pawns,
+        kk_index,
+        kk_squares,
+        values: Vec::new(),
+    }
+}
+
+fn tb_range(piece: Piece) -> usize {
+    if piece_base(piece) == P_BASE { 48 } else { 64 }
+}
+
+pub fn tb_size(tb: &Tablebase) -> usize {
+    2 * tb.kk_squares.len() * tb.pieces[2 ..].iter().map(|&p| tb_range(p)).product::<usize>()
+}
+
+// The index of squares as they are, without the side to move, if the
+// kings stand where a canonical index has them. Like pieces are taken in
+// order of their squares.
+fn tb_raw_index(tb: &Tablebase, squares: &[Sq]) -> Option<usize> {
+    let kk = tb.kk_index[64 * squares[0] as usize + squares[1] as usize];
+    if kk < 0 {
+        return None;
+    }
+    let n = squares.len();
+    let mut sorted = [0; TB_MAX_MEN];
+    sorted[.. n].copy_from_slice(squares);
+    let mut start = 2;
+    while start < n {
+        let mut end = start + 1;
+        while end < n && tb.pieces[end] == tb.pieces[start] {
+            end += 1;
+        }
+        sorted[start .. end].sort_unstable();
+        start = end;
+    }
+    let mut index = kk as usize;
+    for (&piece, &sq) in tb.pieces[2 ..].iter().zip(sorted[2 .. n].iter()) {
+        let offset = if piece_base(piece) == P_BASE { 8 } else { 0 };
+        index = index * tb_range(piece) + (sq - offset) as usize;
+    }
+    Some(index)
+}
+
+// The canonical index of a position with the pieces of tb on squares:
+// the least raw index over the symmetries.
+fn tb_index(tb: &Tablebase, squares: &[Sq], stm: Color) -> usize {
+    let symmetries = if tb.has_pawns { 2 } else { 8 };
+    let mut best = usize::MAX;
+    let mut transformed = [0; TB_MAX_MEN];
+    for t in 0 .. symmetries {
+        for (i, &sq) in squares.iter().enumerate() {
+            transformed[i] = tb_transform(sq, t);
+        }
+        if let Some(index) = tb_raw_index(tb, &transformed[.. squares.len()]) {
+            best = best.min(index);
+        }
+    }
+    2 * best + if stm == COLOR_WHITE { 0 } else { 1 }
+}
+
+fn tb_decode(tb: &Tablebase, index: usize) -> ([Sq; TB_MAX_MEN], Color) {
+    let stm = if index & 1 == 0 { COLOR_WHITE } else { COLOR_BLACK };
+    let mut rest = index / 2;
+    let mut squares = [0; TB_MAX_MEN];
+    for i in (2 .. tb.pieces.len()).rev() {
+        let range = tb_range(tb.pieces[i]);
+        let offset = if piece_base(tb.pieces[i]) == P_BASE { 8 } else { 0 };
+        squares[i] = (rest % range) as Sq + offset;
+        rest /= range;
+    }
+    (squares[0], squares[1]) = tb.kk_squares[rest];
+    (squares, stm)
+}
+
+fn tb_occupied(squares: &[Sq]) -> Bitboard {
+    squares.iter().fold(0, |bb, &sq| bb | sq_bb(sq))
+}
+
+fn tb_attacked(pieces: &[Piece], squares: &[Sq], occupied: Bitboard, target: Sq, by: Color) -> bool {
+    pieces.iter().zip(squares.iter()).any(|(&piece, &sq)| {
+        piece * by > 0 && match piece_base(piece) {
+            P_BASE => pawn_attacks(by, sq) & sq_bb(target) != 0,
+            pb => piece_attacks(pb, sq, occupied) & sq_bb(target) != 0,
+        }
+    })
+}
+
+fn tb_king_square(squares: &[Sq], color: Color) -> Sq {
+    if color == COLOR_WHITE { squares[0] } else { squares[1] }
+}
+
+// Calls func with the pieces and squares after every legal move of stm,
+// and whether the material stays the same (no capture or promotion).
+fn tb_for_each_move(
+    pieces: &[Piece], squares: &[Sq], stm: Color, mut func: impl FnMut(&[Piece], &[Sq], bool),
+) {
+    let n = pieces.len();
+    let occupied = tb_occupied(squares);
+    let mut own = 0;
+    for i in 0 .. n {
+        if pieces[i] * stm > 0 {
+            own |= sq_bb(squares[i]);
+        }
+    }
+    let mut try_move = |i: usize, to: Sq, promotion: Piece| {
+        let mut cp = [EMPTY; TB_MAX_MEN];
+        let mut cs = [0; TB_MAX_MEN];
+        let mut m = 0;
+        let mut captured = false;
+        for j in 0 .. n {
+            if j != i && squares[j] == to {
+                captured = true;
+                continue;
+            }
+            cp[m] = if j == i && promotion != EMPTY { promotion } else { pieces[j] };
+            cs[m] = if j == i { to } else { squares[j] };
+            m += 1;
+        }
+        let occupied = tb_occupied(&cs[.. m]);
+        if !tb_attacked(&cp[.. m], &cs[.. m], occupied, tb_king_square(&cs, stm), -stm) {
+            func(&cp[.. m], &cs[.. m], !captured && promotion == EMPTY);
+        }
+    };
+    for i in 0 .. n {
+        if pieces[i] * stm <= 0 {
+            continue;
+        }
+        let from = squares[i];
+        if piece_base(pieces[i]) != P_BASE {
+            for_each_sq_in_bb(piece_attacks(piece_base(pieces[i]), from, occupied) & !own, |to| {
+                try_move(i, to, EMPTY);
+            });
+            continue;
+        }
+        let last_rank = if stm == COLOR_WHITE { 7 } else { 0 };
+        let mut pawn_move = |to: Sq| {
+            if tb_rank(to) == last_rank {
+                for pb in [Q_BASE, R_BASE, B_BASE, N_BASE] {
+                    try_move(i, to, pb * stm);
+                }
+            } else {
+                try_move(i, to, EMPTY);
+            }
+        };
+        let push = from + 8 * stm;
+        if occupied & sq_bb(push) == 0 {
+            pawn_move(push);
+            let start_rank = if stm == COLOR_WHITE { 1 } else { 6 };
+            let double = push + 8 * stm;
+            if tb_rank(from) == start_rank && occupied & sq_bb(double) == 0 {
+                pawn_move(double);
+            }
+        }
+        for_each_sq_in_bb(pawn_attacks(stm, from) & occupied & !own, pawn_move);
+    }
+}
+
+// Calls func with the squares of every position from which the side not
+// to move could have reached this one without capturing or promoting.
+fn tb_for_each_unmove(pieces: &[Piece], squares: &[Sq], stm: Color, mut func: impl FnMut(&[Sq])) {
+    let n = pieces.len();
+    let mover = -stm;
+    let occupied = tb_occupied(squares);
+    let mut try_unmove = |i: usize, from: Sq| {
+        let mut ps = [0; TB_MAX_MEN];
+        ps[.. n].copy_from_slice(squares);
+        ps[i] = from;
+        // The side to move here must not have been left in check.
+        let occupied = tb_occupied(&ps[.. n]);
+        if !tb_attacked(pieces, &ps[.. n], occupied, tb_king_square(&ps, stm), mover) {
+            func(&ps[.. n]);
+        }
+    };
+    for i in 0 .. n {
+        if pieces[i] * mover <= 0 {
+            continue;
+        }
+        let to = squares[i];
+        if piece_base(pieces[i]) != P_BASE {
+            for_each_sq_in_bb(piece_attacks(piece_base(pieces[i]), to, occupied) & !occupied, |from| {
+                try_unmove(i, from);
+            });
+            continue;
+        }
+        let back = to - 8 * mover;
+        if !(1 ..= 6).contains(&tb_rank(back)) || occupied & sq_bb(back) != 0 {
+            continue;
+        }
+        try_unmove(i, back);
+        let double_rank = if mover == COLOR_WHITE { 3 } else { 4 };
+        let start = back - 8 * mover;
+        if tb_rank(to) == double_rank && occupied & sq_bb(start) == 0 {
+            try_unmove(i, start);
+        }
+    }
+}
+
+fn tb_win_value(ply: usize) -> u8 {
+    let moves = ply.div_ceil(2);
+    assert!(moves <= 127, "Unexpected! mate in {} does not fit", moves);
+    moves as u8
+}
+
+fn tb_loss_value(ply: usize) -> u8 {
+    assert!(ply / 2 <= 125, "Unexpected! mated in {} does not fit", ply / 2);
+    TB_LOSS + (ply / 2) as u8
+}
+
+// Plies to mate of a win or loss.
+fn tb_value_ply(value: u8) -> Option<usize> {
+    match value {
+        1 ..= 127 => Some(2 * value as usize - 1),
+        TB_LOSS ..= 253 => Some(2 * (value - TB_LOSS) as usize),
+        _ => None,
+    }
+}
+
+pub fn tb_value_outcome(value: u8) -> TbOutcome {
+    match value {
+        1 ..= 127 => TbOutcome::Win(value as u32),
+        TB_LOSS ..= 253 => TbOutcome::Loss((value - TB_LOSS) as u32),
+        _ => TbOutcome::Draw,
+    }
+}
+
+// The value for stm of pieces on squares, from the table of their
+// material in whichever colors it is stored. Bare kings are a draw; None
+// if the table is missing or the position illegal.
+fn tb_probe_pieces(tbs: &Tablebases, pieces: &[Piece], squares: &[Sq], stm: Color) -> Option<u8> {
+    if pieces.len() == 2 {
+        return Some(TB_DRAW);
+    }
+    let flip = !tb_is_canonical(pieces);
+    let mut placed = [(EMPTY, 0); TB_MAX_MEN];
+    for (i, (&piece, &sq)) in pieces.iter().zip(squares.iter()).enumerate() {
+        placed[i] = if flip { (-piece, sq ^ 56) } else { (piece, sq) };
+    }
+    let placed = &mut placed[.. pieces.len()];
+    placed.sort_by_key(|&(piece, _)| tb_order_key(piece));
+    let mut ordered = [EMPTY; TB_MAX_MEN];
+    let mut ordered_squares = [0; TB_MAX_MEN];
+    for (i, &(piece, sq)) in placed.iter().enumerate() {
+        ordered[i] = piece;
+        ordered_squares[i] = sq;
+    }
+    let tb = tbs.tables.get(&tb_signature(&ordered[.. pieces.len()]))?;
+    let stm = if flip { -stm } else { stm };
+    Some(tb.values[tb_index(tb, &ordered_squares[.. pieces.len()], stm)]).filter(|&v| v != TB_ILLEGAL)
+}
+
+// The tables a capture or promotion leads to, in their canonical colors.
+fn tb_child_signatures(pieces: &[Piece]) -> Vec<String> {
+    let mut children = Vec::new();
+    let mut add = |child: Vec<Piece>| {
+        if child.len() > 2 {
+            let child = if tb_is_canonical(&child) { child } else { child.iter().map(|&p| -p).collect() };
+            let signature = tb_signature(&child);
+            if !children.contains(&signature) {
+                children.push(signature);
+            }
+        }
+    };
+    for i in 2 .. pieces.len() {
+        let mut child = pieces.to_vec();
+        child.remove(i);
+        add(child);
+        if piece_base(pieces[i]) == P_BASE {
+            for pb in [Q_BASE, R_BASE, B_BASE, N_BASE] {
+                let mut child = pieces.to_vec();
+                child[i] = pb * pieces[i].signum();
+                add(child);
+            }
+        }
+    }
+    children
+}
+
+fn tb_legal_and_canonical(tb: &Tablebase, index: usize) -> bool {
+    let (squares, stm) = tb_decode(tb, index);
+    let n = tb.pieces.len();
+    let squares = &squares[.. n];
+    if tb_occupied(squares).count_ones() as usize != n {
+        return false;
+    }
+    if tb_index(tb, squares, stm) != index {
+        return false;
+    }
+    let occupied = tb_occupied(squares);
+    !tb_attacked(&tb.pieces, squares, occupied, tb_king_square(squares, -stm), stm)
+}
+
+// Computes the table of signature, and first those its captures and
+// promotions lead to, unless they are in tbs already.
+pub fn generate_tablebase(tbs: &mut Tablebases, signature: &str) -> Result<(), String> {
+    let Some(mut pieces) = tb_pieces_from_signature(signature) else {
+        return Err(format!("bad material signature '{}'", signature));
+    };
+    if !tb_is_canonical(&pieces) {
+        pieces = pieces.iter().map(|&p| -p).collect();
+        pieces.sort_by_key(|&p| tb_order_key(p));
+    }
+    if pieces.len() == 2 || tbs.tables.contains_key(&tb_signature(&pieces)) {
+        return Ok(());
+    }
+    for child in tb_child_signatures(&pieces) {
+        generate_tablebase(tbs, &child)?;
+    }
+    let mut tb = new_tablebase(pieces);
+    let size = tb_size(&tb);
+    let n = tb.pieces.len();
+    let mut values = vec![TB_ILLEGAL; size];
+    // In-table moves not yet known to lose, and the longest out-of-table
+    // win of the opponent.
+    let mut counters = vec![0u8; size];
+    let mut out_max = vec![0u8; size];
+    // Positions to look at, by plies to mate.
+    let mut buckets: Vec<Vec<u32>> = Vec::new();
+    let push = |buckets: &mut Vec<Vec<u32>>, ply: usize, index: usize| {
+        if buckets.len() <= ply {
+            buckets.resize(ply + 1, Vec::new());
+        }
+        buckets[ply].push(index as u32);
+    };
+    let mut children = Vec::new();
+    for index in 0 .. size {
+        if !tb_legal_and_canonical(&tb, index) {
+            continue;
+        }
+        values[index] = TB_UNKNOWN;
+        let (squares, stm) = tb_decode(&tb, index);
+        let squares = &squares[.. n];
+        let mut n_moves = 0;
+        let mut best_win = usize::MAX;
+        let mut never_loses = false;
+        let mut longest = 0;
+        children.clear();
+        tb_for_each_move(&tb.pieces, squares, stm, |cp, cs, same| {
+            n_moves += 1;
+            if same {
+                children.push(tb_index(&tb, cs, -stm));
+                return;
+            }
+            let value = tb_probe_pieces(tbs, cp, cs, -stm).unwrap();
+            match (value, tb_value_ply(value)) {
+                (TB_LOSS ..= 253, Some(ply)) => { best_win = best_win.min(ply + 1); },
+                (_, Some(ply)) => { longest = longest.max(ply); },
+                _ => { never_loses = true; },
+            }
+        });
+        if n_moves == 0 {
+            let in_check = tb_attacked(&tb.pieces, squares, tb_occupied(squares),
+                tb_king_square(squares, stm), -stm);
+            if in_check {
+                values[index] = tb_loss_value(0);
+                push(&mut buckets, 0, index);
+            } else {
+                values[index] = TB_DRAW;
+            }
+            continue;
+        }
+        children.sort_unstable();
+        children.dedup();
+        counters[index] = u8::try_from(children.len()).unwrap();
+        if best_win != usize::MAX {
+            push(&mut buckets, best_win, index);
+            never_loses = true;
+        }
+        out_max[index] = if never_loses { NEVER_LOSES } else { longest as u8 };
+        if children.is_empty() && !never_loses {
+            values[index] = tb_loss_value(longest + 1);
+            push(&mut buckets, longest + 1, index);
+        }
+    }
+    let mut ply = 0;
+    let mut predecessors = Vec::new();
+    while ply < buckets.len() {
+        // A win out of the table may be found in it at the same ply too.
+        let mut bucket = std::mem::take(&mut buckets[ply]);
+        bucket.sort_unstable();
+        bucket.dedup();
+        for &index in bucket.iter() {
+            let index = index as usize;
+            // A win out of the table, unless one in it came first.
+            if values[index] == TB_UNKNOWN {
+                values[index] = tb_win_value(ply);
+            }
+            if tb_value_ply(values[index]) != Some(ply) {
+                continue;
+            }
+            let (squares, stm) = tb_decode(&tb, index);
+            predecessors.clear();
+            tb_for_each_unmove(&tb.pieces, &squares[.. n], stm, |ps| {
+                predecessors.push(tb_index(&tb, ps, -stm));
+            });
+            predecessors.sort_unstable();
+            predecessors.dedup();
+            for &p in predecessors.iter() {
+                if values[p] != TB_UNKNOWN {
+                    continue;
+                }
+                if ply % 2 == 0 {
+                    values[p] = tb_win_value(ply + 1);
+                    push(&mut buckets, ply + 1, p);
+                    continue;
+                }
+                counters[p] -= 1;
+                if counters[p] == 0 && out_max[p] != NEVER_LOSES {
+                    let loss = ply.max(out_max[p] as usize) + 1;
+                    values[p] = tb_loss_value(loss);
+                    push(&mut buckets, loss, p);
+                }
+            }
+        }
+        ply += 1;
+    }
+    for value in values.iter_mut() {
+        if *value == TB_UNKNOWN {
+            *value = TB_DRAW;
+        }
+    }
+    tb.values = values;
+    tbs.tables.insert(tb.signature.clone(), tb);
+    Ok(())
+}
+
+// The outcome of pos for the side to move, if it has few enough men, no
+// castling rights or en passant square and its table is loaded.
+pub fn tb_probe(tbs: &Tablebases, pos: &Position) -> Option<TbOutcome> {
+    let occupied = occupied_bb(pos);
+    if tbs.tables.is_empty() || bb_popcount(occupied) as usize > TB_MAX_MEN
+        || pos.castling.iter().any(|&c| c) || pos.en_passant.is_some() {
+        return None;
+    }
+    let mut pieces = [EMPTY; TB_MAX_MEN];
+    let mut squares = [0; TB_MAX_MEN];
+    let mut n = 0;
+    for_each_sq_in_bb(occupied, |sq| {
+        pieces[n] = piece_at_sq(pos, sq);
+        squares[n] = sq;
+        n += 1;
+    });
+    tb_probe_pieces(tbs, &pieces[.. n], &squares[.. n], pos.active_color).map(tb_value_outcome)
+}
+
+// As a search score at ply from the root.
+pub fn tb_outcome_score(outcome: TbOutcome, ply: usize) -> Score {
+    match outcome {
+        TbOutcome::Win(moves) => MATE_SCORE - ply as Score - 2 * moves as Score + 1,
+        TbOutcome::Draw => 0,
+        TbOutcome::Loss(moves) => -MATE_SCORE + ply as Score + 2 * moves as Score,
+    }
+}
+
+fn tb_fen(pieces: &[Piece], squares: &[Sq], stm: Color) -> String {
+    let mut placement = [EMPTY; 64];
+    for (&piece, &sq) in pieces.iter().zip(squares.iter()) {
+        placement[sq as usize] = piece;
+    }
+    let mut ranks = Vec::new();
+    for rank in (0 .. 8).rev() {
+        let mut s = String::new();
+        let mut empty = 0;
+        for file in 0 .. 8 {
+            let piece = placement[8 * rank + file];
+            if piece == EMPTY {
+                empty += 1;
+                continue;
+            }
+            if empty > 0 {
+                s += &empty.to_string();
+                empty = 0;
+            }
+            let c = TB_PIECE_CHARS[tb_piece_rank(piece)];
+            s.push(if piece > 0 { c } else { c.to_ascii_lowercase() });
+        }
+        if empty > 0 {
+            s += &empty.to_string();
+        }
+        ranks.push(s);
+    }
+    format!("{} {} - - 0 1", ranks.join("/"), if stm == COLOR_WHITE { "w" } else { "b" })
+}
+
+pub fn tablebase_stats(tb: &Tablebase) -> TbStats {
+    let mut stats = TbStats{
+        positions: 0, wins: 0, draws: 0, losses: 0, max_dtm: 0, max_dtm_fen: String::new(),
+    };
+    let mut max_index = None;
+    for (index, &value) in tb.values.iter().enumerate() {
+        match value {
+            TB_ILLEGAL => { continue; },
+            TB_DRAW => { stats.draws += 1; },
+            1 ..= 127 => {
+                stats.wins += 1;
+                if value as u32 > stats.max_dtm {
+                    stats.max_dtm = value as u32;
+                    max_index = Some(index);
+                }
+            },
+            _ => { stats.losses += 1; },
+        }
+        stats.positions += 1;
+    }
+    if let Some(index) = max_index {
+        let (squares, stm) = tb_decode(tb, index);
+        stats.max_dtm_fen = tb_fen(&tb.pieces, &squares[.. tb.pieces.len()], stm);
+    }
+    stats
+}
+
+pub fn tablebase<'a>(tbs: &'a Tablebases, signature: &str) -> Option<&'a Tablebase> {
+    tbs.tables.get(signature)
+}
+
+// As <signature>.oxtb in dir: a header with the signature, then a byte
+// per index.
+pub fn write_tablebase(tb: &Tablebase, dir: &Path) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(dir.join(format!("{}.oxtb", tb.signature)))?);
+    file.write_all(TB_MAGIC)?;
+    file.write_all(&[TB_VERSION, tb.signature.len() as u8])?;
+    file.write_all(tb.signature.as_bytes())?;
+    file.write_all(&tb.values)?;
+    file.flush()
+}
+
+fn tb_bad_file(path: &Path) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: not a tablebase", path.display()))
+}
+
+pub fn read_tablebase(path: &Path) -> std::io::Result<Tablebase> {
+    let bytes = std::fs::read(path)?;
+    if bytes.len() < 6 || &bytes[.. 4] != TB_MAGIC || bytes[4] != TB_VERSION {
+        return Err(tb_bad_file(path));
+    }
+    let end = 6 + bytes[5] as usize;
+    let signature = bytes.get(6 .. end).and_then(|s| std::str::from_utf8(s).ok()).ok_or_else(|| tb_bad_file(path))?;
+    let pieces = tb_pieces_from_signature(signature).filter(|p| tb_is_canonical(p)).ok_or_else(|| tb_bad_file(path))?;
+    let mut tb = new_tablebase(pieces);
+    if bytes.len() - end != tb_size(&tb) {
+        return Err(tb_bad_file(path));
+    }
+    tb.values = bytes[end ..].to_vec();
+    Ok(tb)
+}
+
+// Every .oxtb file in dir.
+pub fn load_tablebases(dir: &Path) -> std::io::Result<Tablebases> {
+    let mut tbs = new_tablebases();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "oxtb") {
+            let tb = read_tablebase(&path)?;
+            tbs.tables.insert(tb.signature.clone(), tb);
+        }
+    }
+    Ok(tbs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks every position of a table against its children, reached with
+    // the move generator of the engine: a win must be one move longer than
+    // the quickest loss among them, a loss as long as the slowest win, and
+    // anything else a draw.
+    fn check_tablebase_consistency(tbs: &Tablebases, signature: &str) {
+        let tb = tablebase(tbs, signature).unwrap();
+        for index in 0 .. tb.values.len() {
+            if tb.values[index] == TB_ILLEGAL {
+                continue;
+            }
+            let (squares, stm) = tb_decode(tb, index);
+            let pos = decode_fen(tb_fen(&tb.pieces, &squares[.. tb.pieces.len()], stm));
+            let list = generate_legal_moves(&pos);
+            let mut quickest_loss = None;
+            let mut slowest_win = Some(0);
+            for &packed in move_list_slice(&list) {
+                let mut child = copy_position(&pos);
+                make_move(&mut child, &unpack_move(&pos, packed));
+                child.en_passant = None;
+                match tb_probe(tbs, &child).unwrap() {
+                    TbOutcome::Loss(moves) => { quickest_loss = quickest_loss.min(Some(moves)).or(Some(moves)); },
+                    TbOutcome::Win(moves) => { slowest_win = slowest_win.map(|w: u32| w.max(moves)); },
+                    TbOutcome::Draw => { slowest_win = None; },
+                }
+            }
+            let expected = match (quickest_loss, slowest_win) {
+                _ if list.len == 0 && !is_king_in_check(&pos, false) => TbOutcome::Draw,
+                (Some(moves), _) => TbOutcome::Win(moves + 1),
+                (None, Some(moves)) => TbOutcome::Loss(moves),
+                (None, None) => TbOutcome::Draw,
+            };
+            assert_eq!(tb_value_outcome(tb.values[index]), expected, "{}", encode_fen(&pos));
+        }
+    }
+
+    // Generates the three man tables and checks their longest mates against
+    // the known ones, every pawn ending against the move generator, some
+    // positions, a round trip through a file and a search.
+    #[test]
+    fn generated_tables() {
+        assert_eq!(tb_king_pairs(false).1.len(), 462);
+        assert_eq!(tb_pieces_from_signature("KRPvKR"), Some(vec![K_BASE, -K_BASE, R_BASE, P_BASE, -R_BASE]));
+        assert_eq!(tb_pieces_from_signature("KQRBNvK"), None);
+        assert_eq!(tb_pieces_from_signature("KQvQ"), None);
+        assert_eq!(tb_child_signatures(&tb_pieces_from_signature("KPvKR").unwrap()),
+            vec!["KRvK", "KQvKR", "KRvKR", "KRvKB", "KRvKN", "KPvK"]);
+        let mut tbs = new_tablebases();
+        for (signature, max_dtm) in [("KQvK", 10), ("KRvK", 16), ("KPvK", 28)] {
+            generate_tablebase(&mut tbs, signature).unwrap();
+            assert_eq!(tablebase_stats(tablebase(&tbs, signature).unwrap()).max_dtm, max_dtm, "{}", signature);
+        }
+        check_tablebase_consistency(&tbs, "KPvK");
+        let stats = tablebase_stats(tablebase(&tbs, "KBvK").unwrap());
+        assert_eq!((stats.wins, stats.losses), (0, 0));
+        let probe = |fen: &str| tb_probe(&tbs, &decode_fen(String::from(fen)));
+        assert_eq!(probe("k7/8/1K6/8/8/7Q/8/8 w - - 0 1"), Some(TbOutcome::Win(1)));
+        assert_eq!(probe("k7/8/1K6/8/8/7Q/8/8 b - - 0 1"), Some(TbOutcome::Loss(1)));
+        // Stalemate.
+        assert_eq!(probe("k7/2Q5/1K6/8/8/8/8/8 b - - 0 1"), Some(TbOutcome::Draw));
+        // The first one with colors reversed.
+        assert_eq!(probe("8/8/7q/8/8/1k6/8/K7 b - - 0 1"), Some(TbOutcome::Win(1)));
+        // Black is in check with white to move.
+        assert_eq!(probe("k7/8/1K6/8/8/8/8/7Q w - - 0 1"), None);
+        // The king on the sixth in front of its pawn wins, whoever moves.
+        assert!(matches!(probe("4k3/8/4K3/4P3/8/8/8/8 w - - 0 1"), Some(TbOutcome::Win(_))));
+        assert!(matches!(probe("4k3/8/4K3/4P3/8/8/8/8 b - - 0 1"), Some(TbOutcome::Loss(_))));
+        assert_eq!(probe("4k3/4P3/4K3/8/8/8/8/8 b - - 0 1"), Some(TbOutcome::Draw));
+        assert_eq!(probe("8/8/8/8/8/8/3kP3/7K b - - 0 1"), Some(TbOutcome::Draw));
+        assert_eq!(probe("4k3/8/8/8/8/8/3PP3/4K3 w - - 0 1"), None);
+        let dir = std::env::temp_dir();
+        write_tablebase(tablebase(&tbs, "KQvK").unwrap(), &dir).unwrap();
+        let path = dir.join("KQvK.oxtb");
+        let tb = read_tablebase(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(tb.values == tablebase(&tbs, "KQvK").unwrap().values);
+        // The search sees the longest rook mate at depth one.
+        let mut s = new_searcher(1);
+        s.tablebases = tbs;
+        let mut limits = default_search_limits();
+        limits.depth = Some(1);
+        let pos = decode_fen(String::from("8/8/8/8/8/2k5/1R6/K7 w - - 0 1"));
+        let result = search(&mut s, &pos, &[], &limits, &mut |_| {});
+        assert_eq!(mate_in_moves(result.score), 16);
+    }
+}
@@ -0,0 +1,92 @@
+// Time management for games on a clock. Each move gets a soft limit,
+// after which the search starts no new iteration, and a hard limit, at
+// which it stops whatever it is doing. The soft limit is stretched while
+// the best move keeps changing or the score falls from one iteration to
+// the next.
+
+use std::time::Duration;
+
+use crate::*;
+
+// Kept back from every move for the GUI and the communication with it.
+const MOVE_OVERHEAD: Duration = Duration::from_millis(30);
+// How many moves a game without moves to go (sudden death or Fischer) is
+// taken to have left.
+const SUDDEN_DEATH_MOVES: u32 = 30;
+// The hard limit as a multiple of the soft one.
+const HARD_FACTOR: u32 = 4;
+// The soft limit may be stretched to this many percent of itself.
+const MAX_STRETCH: u32 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeAllocation {
+    pub soft: Duration,
+    pub hard: Duration,
+}
+
+// A share of the clock in limits.time: an even split over the moves to
+// the next control (classical) or an assumed number of moves (sudden
+// death), plus most of the increment (Fischer). A lead over the
+// opponent's clock is spent a quarter at a time. None without a clock.
+pub fn allocate_time(limits: &SearchLimits) -> Option<TimeAllocation> {
+    let time = limits.time?;
+    let available = time.saturating_sub(MOVE_OVERHEAD);
+    let moves = limits.moves_to_go.unwrap_or(SUDDEN_DEATH_MOVES).max(1);
+    let lead = limits.opponent_time.map_or(Duration::ZERO, |t| time.saturating_sub(t));
+    // Something is left for the moves after this one, unless it is the
+    // last before the control.
+    let max = if moves == 1 { available } else { available * 3 / 4 };
+    let soft = (available / moves + limits.increment * 3 / 4 + lead / (4 * moves)).min(max);
+    Some(TimeAllocation{soft, hard: (soft * HARD_FACTOR).min(max)})
+}
+
+// Decays the count of best move changes, in percent, by an iteration and
+// adds the change in this one if any.
+pub fn update_best_move_changes(changes: u32, changed: bool) -> u32 {
+    changes / 2 + if changed { 100 } else { 0 }
+}
+
+// How many percent of the soft limit to use: more while the best move is
+// unsettled and when the score has dropped since the last iteration.
+pub fn time_stretch(best_move_changes: u32, score_drop: Score) -> u32 {
+    let drop = score_drop.clamp(0, 100) as u32;
+    (100 + best_move_changes / 2 + drop).min(MAX_STRETCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the allocations and stretches for a few time controls.
+    #[test]
+    fn time_management() {
+        let mut limits = default_search_limits();
+        assert_eq!(allocate_time(&limits), None);
+        let ms = Duration::from_millis;
+        // Classical, 40 moves in 100s.
+        limits.time = Some(ms(100_030));
+        limits.moves_to_go = Some(40);
+        assert_eq!(allocate_time(&limits), Some(TimeAllocation{soft: ms(2500), hard: ms(10_000)}));
+        // 40s ahead on the clock.
+        limits.opponent_time = Some(ms(60_030));
+        assert_eq!(allocate_time(&limits), Some(TimeAllocation{soft: ms(2750), hard: ms(11_000)}));
+        limits.opponent_time = None;
+        // The last move before the control may use it all.
+        limits.time = Some(ms(1030));
+        limits.moves_to_go = Some(1);
+        assert_eq!(allocate_time(&limits), Some(TimeAllocation{soft: ms(1000), hard: ms(1000)}));
+        // Sudden death.
+        limits.time = Some(ms(60_030));
+        limits.moves_to_go = None;
+        assert_eq!(allocate_time(&limits), Some(TimeAllocation{soft: ms(2000), hard: ms(8000)}));
+        // Fischer, low on time: the increment is spent but the clock kept.
+        limits.time = Some(ms(430));
+        limits.increment = ms(2000);
+        assert_eq!(allocate_time(&limits), Some(TimeAllocation{soft: ms(300), hard: ms(300)}));
+        assert_eq!(time_stretch(0, -50), 100);
+        let changes = update_best_move_changes(update_best_move_changes(0, true), true);
+        assert_eq!(changes, 150);
+        assert_eq!(time_stretch(changes, 40), 215);
+        assert_eq!(time_stretch(1000, 1000), MAX_STRETCH);
+    }
+}
@@ -0,0 +1,316 @@
+// The UCI protocol on stdin/stdout. Commands are read on the main thread;
+// searches run on a thread of their own so that stop, ponderhit and
+// isready are answered while they think.
+
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::*;
+
+pub const DEFAULT_HASH_MEGABYTES: usize = 16;
+const MAX_HASH_MEGABYTES: usize = 65536;
+
+pub struct UciState {
+    searcher: Arc<Mutex<Searcher>>,
+    // Shared with the searcher, so they can be set while it holds the lock.
+    stop: Arc<AtomicBool>,
+    pondering: Arc<AtomicBool>,
+    game: Game,
+    thread: Option<JoinHandle<()>>,
+    // From the OwnBook, BookFile and BookBestMove options.
+    own_book: bool,
+    book: Option<PolyglotBook>,
+    book_selection: BookSelection,
+    rng: u64,
+}
+
+pub fn new_uci_state() -> UciState {
+    let searcher = new_searcher(DEFAULT_HASH_MEGABYTES);
+    let stop = searcher.stop.clone();
+    let pondering = searcher.pondering.clone();
+    UciState{
+        searcher: Arc::new(Mutex::new(searcher)),
+        stop,
+        pondering,
+        game: new_game(&decode_fen(String::from(STARTING_FEN))),
+        thread: None,
+        own_book: false,
+        book: None,
+        book_selection: BookSelection::Weighted,
+        rng: book_rng_seed(),
+    }
+}
+
+// "e2e4 e7e5 ...", or "0000" for the null move.
+pub fn packed_moves_to_uci_string(moves: &[PackedMove]) -> String {
+    let strings: Vec<String> = moves.iter()
+        .map(|&pm| if pm == NULL_PACKED_MOVE { String::from("0000") } else { packed_move_to_uci_string(pm) })
+        .collect();
+    strings.join(" ")
+}
+
+pub fn search_info_to_uci_string(info: &SearchInfo) -> String {
+    let millis = info.time.as_millis() as u64;
+    let nps = info.nodes * 1000 / millis.max(1);
+    format!("info depth {} seldepth {} score {} nodes {} nps {} hashfull {} time {} pv {}",
+        info.depth, info.seldepth, score_to_uci_string(info.score), info.nodes, nps,
+        info.hashfull, millis, packed_moves_to_uci_string(&info.pv))
+}
+
+// Tells a running search to finish and waits until it has printed its
+// bestmove.
+fn uci_wait_for_search(state: &mut UciState) {
+    if let Some(thread) = state.thread.take() {
+        state.pondering.store(false, Ordering::Relaxed);
+        state.stop.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
+    }
+}
+
+// position [startpos | fen <fen>] [moves <move> ...]
+fn uci_position(state: &mut UciState, words: &[&str]) {
+    let moves_at = words.iter().position(|&w| w == "moves").unwrap_or(words.len());
+    let start = match words.first() {
+        Some(&"startpos") => decode_fen(String::from(STARTING_FEN)),
+        Some(&"fen") => match parse_fen_with_options(&words[1 .. moves_at].join(" "), true) {
+            Ok(pos) => pos,
+            Err(e) => {
+                println!("info string bad fen: {}", e);
+                return;
+            },
+        },
+        _ => {
+            println!("info string expected startpos or fen");
+            return;
+        },
+    };
+    state.game = new_game(&start);
+    for &word in words.get(moves_at + 1 ..).unwrap_or(&[]) {
+        match parse_uci(&state.game.position, word) {
+            Ok(mov) => { game_push(&mut state.game, &mov); },
+            Err(e) => {
+                println!("info string {}", e);
+                return;
+            },
+        }
+    }
+}
+
+// Reads the parameters of go into limits, the clock of the side to move as
+// its own and the other as the opponent's. Returns whether this is a
+// ponder search.
+fn uci_parse_go(pos: &Position, words: &[&str], limits: &mut SearchLimits) -> bool {
+    let mut ponder = false;
+    let white = pos.active_color == COLOR_WHITE;
+    let mut i = 0;
+    while i < words.len() {
+        let value = words.get(i + 1).and_then(|w| w.parse::<u64>().ok());
+        let millis = |v: Option<u64>| v.map(Duration::from_millis);
+        match words[i] {
+            "searchmoves" => {
+                while let Some(Ok(mov)) = words.get(i + 1).map(|w| parse_uci(pos, w)) {
+                    limits.searchmoves.push(pack_move(pos, &mov));
+                    i += 1;
+                }
+            },
+            "ponder" => { ponder = true; },
+            "infinite" => { limits.infinite = true; },
+            "wtime" if white => { limits.time = millis(value); },
+            "btime" if !white => { limits.time = millis(value); },
+            "wtime" => { limits.opponent_time = millis(value); },
+            "btime" => { limits.opponent_time = millis(value); },
+            "winc" if white => { limits.increment = millis(value).unwrap_or(Duration::ZERO); },
+            "binc" if !white => { limits.increment = millis(value).unwrap_or(Duration::ZERO); },
+            "movestogo" => { limits.moves_to_go = value.map(|v| v as u32); },
+            "depth" => { limits.depth = value.map(|v| v as u32); },
+            "nodes" => { limits.nodes = value; },
+            "mate" => { limits.mate = value.map(|v| v as u32); },
+            "movetime" => { limits.movetime = millis(value); },
+            _ => {},
+        }
+        i += 1;
+    }
+    ponder
+}
+
+fn uci_go(state: &mut UciState, words: &[&str]) {
+    uci_wait_for_search(state);
+    let pos = copy_position(&state.game.position);
+    let mut limits = default_search_limits();
+    let ponder = uci_parse_go(&pos, words, &mut limits);
+    // A book move is played at once, unless told to think.
+    if state.own_book && !ponder && !limits.infinite && limits.searchmoves.is_empty() {
+        let book_move = state.book.as_ref()
+            .and_then(|book| polyglot_choose_move(book, &pos, state.book_selection, &mut state.rng));
+        if let Some(mov) = book_move {
+            println!("bestmove {}", packed_move_to_uci_string(pack_move(&pos, &mov)));
+            return;
+        }
+    }
+    let history = game_history_hashes(&state.game);
+    let searcher = state.searcher.clone();
+    let stop = state.stop.clone();
+    let pondering = state.pondering.clone();
+    stop.store(false, Ordering::Relaxed);
+    pondering.store(ponder, Ordering::Relaxed);
+    state.thread = Some(std::thread::spawn(move || {
+        let mut s = searcher.lock().unwrap();
+        let result = search(&mut s, &pos, &history, &limits, &mut |info| {
+            println!("{}", search_info_to_uci_string(info));
+        });
+        // Only stop or ponderhit may end an infinite or ponder search.
+        while !stop.load(Ordering::Relaxed)
+            && (limits.infinite || pondering.load(Ordering::Relaxed)) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let mut line = format!("bestmove {}", packed_moves_to_uci_string(&[result.best_move]));
+        if result.ponder_move != NULL_PACKED_MOVE {
+            line += &format!(" ponder {}", packed_move_to_uci_string(result.ponder_move));
+        }
+        println!("{}", line);
+    }));
+}
+
+// setoption name <name> [value <value>]
+fn uci_setoption(state: &mut UciState, words: &[&str]) {
+    let value_at = words.iter().position(|&w| w == "value").unwrap_or(words.len());
+    let name = words.get(1 .. value_at).unwrap_or(&[]).join(" ").to_lowercase();
+    let value = words.get(value_at + 1 ..).unwrap_or(&[]).join(" ");
+    uci_wait_for_search(state);
+    let mut s = state.searcher.lock().unwrap();
+    match name.as_str() {
+        "hash" => match value.parse::<usize>() {
+            Ok(mb) if (1 ..= MAX_HASH_MEGABYTES).contains(&mb) => {
+                s.tt = new_transposition_table(mb);
+            },
+            _ => println!("info string bad Hash value '{}'", value),
+        },
+        "clear hash" => { tt_clear(&mut s.tt); },
+        "ownbook" => { state.own_book = value == "true"; },
+        "bookfile" if value.is_empty() || value == "<empty>" => { state.book = None; },
+        "bookfile" => match read_polyglot_book(&value) {
+            Ok(book) => { state.book = Some(book); },
+            Err(e) => println!("info string cannot read book '{}': {}", value, e),
+        },
+        "bookbestmove" => {
+            state.book_selection = if value == "true" { BookSelection::Best } else { BookSelection::Weighted };
+        },
+        "tablebasepath" if value.is_empty() || value == "<empty>" => { s.tablebases = new_tablebases(); },
+        "tablebasepath" => match load_tablebases(std::path::Path::new(&value)) {
+            Ok(tbs) => {
+                println!("info string {} tablebases loaded", tablebase_signatures(&tbs).len());
+                s.tablebases = tbs;
+            },
+            Err(e) => println!("info string cannot read tablebases '{}': {}", value, e),
+        },
+        "syzygypath" if value.is_empty() || value == "<empty>" => { s.syzygy = new_syzygy(); },
+        "syzygypath" => {
+            let count = syzygy_init(&mut s.syzygy, &value);
+            println!("info string {} syzygy tables found, up to {} men", count, s.syzygy.max_men);
+        },
+        "syzygyprobedepth" => match value.parse::<i32>() {
+            Ok(depth) if (1 ..= 100).contains(&depth) => { s.syzygy.probe_depth = depth; },
+            _ => println!("info string bad SyzygyProbeDepth value '{}'", value),
+        },
+        "syzygy50moverule" => { s.syzygy.rule50 = value == "true"; },
+        "syzygyprobelimit" => match value.parse::<usize>() {
+            Ok(limit) if limit <= SYZYGY_MAX_MEN => { s.syzygy.probe_limit = limit; },
+            _ => println!("info string bad SyzygyProbeLimit value '{}'", value),
+        },
+        // The GUI decides when to ponder; nothing to set up.
+        "ponder" => {},
+        _ => println!("info string unknown option '{}'", name),
+    }
+}
+
+// Handles one line of input. Returns false on quit.
+pub fn uci_command(state: &mut UciState, line: &str) -> bool {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&command, args)) = words.split_first() else {
+        return true;
+    };
+    match command {
+        "uci" => {
+            println!("id name oxidwig {}", env!("CARGO_PKG_VERSION"));
+            println!("id author gtzampanakis");
+            println!("option name Hash type spin default {} min 1 max {}",
+                DEFAULT_HASH_MEGABYTES, MAX_HASH_MEGABYTES);
+            println!("option name Clear Hash type button");
+            println!("option name Ponder type check default false");
+            println!("option name OwnBook type check default false");
+            println!("option name BookFile type string default <empty>");
+            println!("option name BookBestMove type check default false");
+            println!("option name TablebasePath type string default <empty>");
+            println!("option name SyzygyPath type string default <empty>");
+            println!("option name SyzygyProbeDepth type spin default 1 min 1 max 100");
+            println!("option name Syzygy50MoveRule type check default true");
+            println!("option name SyzygyProbeLimit type spin default {} min 0 max {}", SYZYGY_MAX_MEN, SYZYGY_MAX_MEN);
+            println!("uciok");
+        },
+        "isready" => println!("readyok"),
+        "setoption" => uci_setoption(state, args),
+        "ucinewgame" => {
+            uci_wait_for_search(state);
+            searcher_clear(&mut state.searcher.lock().unwrap());
+            state.game = new_game(&decode_fen(String::from(STARTING_FEN)));
+        },
+        "position" => {
+            uci_wait_for_search(state);
+            uci_position(state, args);
+        },
+        "go" => uci_go(state, args),
+        "stop" => uci_wait_for_search(state),
+        "ponderhit" => { state.pondering.store(false, Ordering::Relaxed); },
+        "quit" => {
+            uci_wait_for_search(state);
+            return false;
+        },
+        "debug" | "register" => {},
+        _ => println!("info string unknown command '{}'", command),
+    }
+    true
+}
+
+pub fn run_uci<R: BufRead>(reader: R) {
+    let mut state = new_uci_state();
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if !uci_command(&mut state, &line) {
+            return;
+        }
+    }
+    uci_wait_for_search(&mut state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the parsing of position and go.
+    #[test]
+    fn uci() {
+        let mut state = new_uci_state();
+        uci_command(&mut state, "position startpos moves e2e4 c7c5 g1f3");
+        assert_eq!(encode_fen(&state.game.position),
+            "rnbqkbnr/pp1ppppp/8/2p5/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+        assert_eq!(game_history_hashes(&state.game).len(), 3);
+        uci_command(&mut state, "position fen 4k3/8/8/8/8/8/4P3/4K3 w - - moves e2e4 e8d7");
+        assert_eq!(encode_fen(&state.game.position), "8/3k4/8/8/4P3/8/8/4K3 w - - 1 2");
+        let mut limits = default_search_limits();
+        let words: Vec<&str> = "wtime 60000 btime 30000 winc 0 binc 500 movestogo 20 depth 7 ponder searchmoves d7c7 d7d6 nodes 5000"
+            .split_whitespace().collect();
+        let pos = decode_fen(String::from("8/3k4/8/8/4P3/8/8/4K3 b - - 1 2"));
+        assert!(uci_parse_go(&pos, &words, &mut limits));
+        assert_eq!((limits.time, limits.opponent_time),
+            (Some(Duration::from_millis(30000)), Some(Duration::from_millis(60000))));
+        assert_eq!(limits.increment, Duration::from_millis(500));
+        assert_eq!((limits.moves_to_go, limits.depth, limits.nodes), (Some(20), Some(7), Some(5000)));
+        assert_eq!(packed_moves_to_uci_string(&limits.searchmoves), "d7c7 d7d6");
+        assert!(!limits.infinite);
+    }
+}
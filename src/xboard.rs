@@ -0,0 +1,557 @@
+// The XBoard/WinBoard protocol (CECP version 2) on stdin/stdout, driving
+// the same search as the UCI front end. The engine plays one side, or none
+// in force mode, and searches on a thread of its own that hands the game
+// back when it has made its move. With pondering on (hard), that thread
+// goes on to think on the reply it expects until the opponent moves.
+
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+// Mates are reported as 100000 + N for mate in N, negated when mated.
+const XBOARD_MATE_SCORE: i32 = 100000;
+
+pub struct XboardState {
+    searcher: Arc<Mutex<Searcher>>,
+    stop: Arc<AtomicBool>,
+    pondering: Arc<AtomicBool>,
+    ponderhit_limits: Arc<Mutex<Option<SearchLimits>>>,
+    // The reply being pondered on, in coordinate notation.
+    ponder_move: Arc<Mutex<Option<String>>>,
+    // Set with stop when the move found should not be played.
+    abort: Arc<AtomicBool>,
+    post: Arc<AtomicBool>,
+    // With the search thread while it runs.
+    game: Option<Game>,
+    thread: Option<JoinHandle<Game>>,
+    // While the game is with the search thread, the position the opponent
+    // moves in: the one analyzed, or the one after the engine's move. None
+    // while the engine thinks on its move.
+    awaited_position: Arc<Mutex<Option<Position>>>,
+    // For the analysis status: when the search started, and the depth and
+    // nodes of its last iteration.
+    search_started: Instant,
+    last_iteration: Arc<Mutex<(u32, u64)>>,
+    // The side the engine plays, COLOR_EMPTY in force and analyze mode.
+    engine_color: Color,
+    analyzing: bool,
+    // From hard and easy.
+    ponder: bool,
+    // From level: moves per time control (0 for the whole game), base
+    // time and increment.
+    moves_per_control: u32,
+    base_time: Duration,
+    increment: Duration,
+    // From st and sd.
+    move_time: Option<Duration>,
+    depth: Option<u32>,
+    // From time and otim, the engine's clock and the opponent's.
+    engine_time: Option<Duration>,
+    opponent_time: Option<Duration>,
+}
+
+pub fn new_xboard_state() -> XboardState {
+    let searcher = new_searcher(DEFAULT_HASH_MEGABYTES);
+    let stop = searcher.stop.clone();
+    let pondering = searcher.pondering.clone();
+    let ponderhit_limits = searcher.ponderhit_limits.clone();
+    XboardState{
+        searcher: Arc::new(Mutex::new(searcher)),
+        stop,
+        pondering,
+        ponderhit_limits,
+        ponder_move: Arc::new(Mutex::new(None)),
+        abort: Arc::new(AtomicBool::new(false)),
+        post: Arc::new(AtomicBool::new(false)),
+        game: Some(new_game(&decode_fen(String::from(STARTING_FEN)))),
+        thread: None,
+        awaited_position: Arc::new(Mutex::new(None)),
+        search_started: Instant::now(),
+        last_iteration: Arc::new(Mutex::new((0, 0))),
+        engine_color: COLOR_BLACK,
+        analyzing: false,
+        ponder: false,
+        moves_per_control: 40,
+        base_time: Duration::from_secs(300),
+        increment: Duration::ZERO,
+        move_time: None,
+        depth: None,
+        engine_time: None,
+        opponent_time: None,
+    }
+}
+
+pub fn score_to_xboard(score: Score) -> i32 {
+    if !is_mate_score(score) {
+        score
+    } else if score > 0 {
+        XBOARD_MATE_SCORE + mate_in_moves(score)
+    } else {
+        -XBOARD_MATE_SCORE + mate_in_moves(score)
+    }
+}
+
+// Moves in SAN, played one after the other from pos.
+pub fn packed_moves_to_san_string(pos: &Position, moves: &[PackedMove]) -> String {
+    let mut cur = copy_position(pos);
+    let mut result = Vec::new();
+    for &pm in moves {
+        let mov = unpack_move(&cur, pm);
+        result.push(move_to_string(&mov, &cur));
+        make_move(&mut cur, &mov);
+    }
+    result.join(" ")
+}
+
+// Thinking output: ply, score, time in centiseconds, nodes and the PV.
+pub fn search_info_to_xboard_string(pos: &Position, info: &SearchInfo) -> String {
+    format!("{} {} {} {} {}",
+        info.depth, score_to_xboard(info.score), info.time.as_millis() / 10, info.nodes,
+        packed_moves_to_san_string(pos, &info.pv))
+}
+
+// The result line to send if the position at the cursor ends the game.
+pub fn xboard_result_claim(game: &mut Game) -> Option<String> {
+    let reason = match game_update_result(game)? {
+        GameTermination::Checkmate if game.result == "1-0" => "White mates",
+        GameTermination::Checkmate => "Black mates",
+        GameTermination::Stalemate => "Stalemate",
+        GameTermination::ThreefoldRepetition => "Draw by repetition",
+        GameTermination::FiftyMoveRule => "Draw by fifty move rule",
+        GameTermination::InsufficientMaterial => "Insufficient material",
+        _ => { return None; },
+    };
+    Some(format!("{} {{{}}}", game.result, reason))
+}
+
+// Gets the game back from the search thread. With abort, the search is
+// cut short and its move dropped; otherwise it is waited for. Pondering is
+// always abandoned, taking back the reply it was on.
+fn xboard_finish_search(state: &mut XboardState, abort: bool) {
+    if let Some(thread) = state.thread.take() {
+        if abort || state.pondering.load(Ordering::Relaxed) {
+            state.abort.store(true, Ordering::Relaxed);
+            state.stop.store(true, Ordering::Relaxed);
+        }
+        state.game = Some(thread.join().unwrap());
+        state.pondering.store(false, Ordering::Relaxed);
+    }
+}
+
+// Analysis only ends when stopped, so it is aborted rather than waited for.
+fn xboard_game(state: &mut XboardState) -> &mut Game {
+    let analyzing = state.analyzing;
+    xboard_finish_search(state, analyzing);
+    state.game.as_mut().unwrap()
+}
+
+// The position the opponent's next move is played in, without waiting for
+// the search. None while the engine thinks on its move.
+fn xboard_awaited_position(state: &XboardState) -> Option<Position> {
+    match &state.game {
+        Some(game) => Some(copy_position(&game.position)),
+        None => state.awaited_position.lock().unwrap().as_ref().map(copy_position),
+    }
+}
+
+fn xboard_limits(state: &XboardState, pos: &Position) -> SearchLimits {
+    let mut limits = default_search_limits();
+    limits.depth = state.depth;
+    if state.analyzing {
+        limits.infinite = true;
+    } else if let Some(t) = state.move_time {
+        limits.movetime = Some(t);
+    } else {
+        limits.time = Some(state.engine_time.unwrap_or(state.base_time));
+        limits.opponent_time = state.opponent_time;
+        limits.increment = state.increment;
+        if state.moves_per_control > 0 {
+            let played = (pos.fullmoves - 1) as u32 % state.moves_per_control;
+            limits.moves_to_go = Some(state.moves_per_control - played);
+        }
+    }
+    limits
+}
+
+// Starts thinking on the position at the cursor: to play a move for the
+// engine, or in analyze mode until told to stop.
+fn xboard_start_search(state: &mut XboardState) {
+    xboard_finish_search(state, true);
+    let mut game = state.game.take().unwrap();
+    let limits = xboard_limits(state, &game.position);
+    let searcher = state.searcher.clone();
+    let stop = state.stop.clone();
+    let pondering = state.pondering.clone();
+    let ponder_move = state.ponder_move.clone();
+    let abort = state.abort.clone();
+    let post = state.post.clone();
+    let analyzing = state.analyzing;
+    let ponder = state.ponder && !analyzing;
+    let awaited_position = state.awaited_position.clone();
+    *awaited_position.lock().unwrap() = if analyzing { Some(copy_position(&game.position)) } else { None };
+    let last_iteration = state.last_iteration.clone();
+    *last_iteration.lock().unwrap() = (0, 0);
+    state.search_started = Instant::now();
+    stop.store(false, Ordering::Relaxed);
+    abort.store(false, Ordering::Relaxed);
+    pondering.store(false, Ordering::Relaxed);
+    state.thread = Some(std::thread::spawn(move || {
+        let mut s = searcher.lock().unwrap();
+        loop {
+            let pos = copy_position(&game.position);
+            let history = game_history_hashes(&game);
+            let result = search(&mut s, &pos, &history, &limits, &mut |info| {
+                *last_iteration.lock().unwrap() = (info.depth, info.nodes);
+                if post.load(Ordering::Relaxed) {
+                    println!("{}", search_info_to_xboard_string(&pos, info));
+                }
+            });
+            // Only stop may end analysis, and a ponder search waits for the
+            // opponent's move.
+            while !stop.load(Ordering::Relaxed)
+                && (analyzing || pondering.load(Ordering::Relaxed)) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            if analyzing {
+                return game;
+            }
+            // Still set after a ponder search unless the reply was played.
+            if ponder_move.lock().unwrap().take().is_some() {
+                game_pop(&mut game);
+                return game;
+            }
+            if abort.load(Ordering::Relaxed) || result.best_move == NULL_PACKED_MOVE {
+                return game;
+            }
+            let mov = unpack_move(&game.position, result.best_move);
+            game_push(&mut game, &mov);
+            *awaited_position.lock().unwrap() = Some(copy_position(&game.position));
+            let claim = xboard_result_claim(&mut game);
+            // Ponder on the expected reply, set up before the move is sent
+            // so that the reply cannot arrive first.
+            let reply = result.ponder_move;
+            let ponder_next = ponder && claim.is_none() && reply != NULL_PACKED_MOVE;
+            if ponder_next {
+                *ponder_move.lock().unwrap() = Some(packed_move_to_uci_string(reply));
+                let mov = unpack_move(&game.position, reply);
+                game_push(&mut game, &mov);
+                pondering.store(true, Ordering::Relaxed);
+            }
+            println!("move {}", packed_move_to_uci_string(result.best_move));
+            if let Some(claim) = claim {
+                println!("{}", claim);
+            }
+            if !ponder_next {
+                return game;
+            }
+        }
+    }));
+}
+
+// Thinks if the engine is to move, or analyzes, unless the game is over.
+fn xboard_maybe_search(state: &mut XboardState) {
+    let game = xboard_game(state);
+    if game.termination.is_some() {
+        return;
+    }
+    let to_move = game.position.active_color;
+    if state.analyzing || state.engine_color == to_move {
+        xboard_start_search(state);
+    }
+}
+
+fn xboard_usermove(state: &mut XboardState, s: &str) {
+    // The reply being pondered on: that search goes on, now on the clock
+    // the GUI has just sent.
+    if state.pondering.load(Ordering::Relaxed) {
+        let mut ponder_move = state.ponder_move.lock().unwrap();
+        if ponder_move.as_deref() == Some(s) {
+            *ponder_move = None;
+            let mut pos = xboard_awaited_position(state).unwrap();
+            let mov = parse_uci(&pos, s).unwrap();
+            make_move(&mut pos, &mov);
+            *state.ponderhit_limits.lock().unwrap() = Some(xboard_limits(state, &pos));
+            state.pondering.store(false, Ordering::Relaxed);
+            return;
+        }
+    }
+    // Anything else ends the search, but only once it is known to be a
+    // legal move.
+    let Some(pos) = xboard_awaited_position(state) else {
+        println!("Illegal move (out of turn): {}", s);
+        return;
+    };
+    let Ok(mov) = parse_uci(&pos, s).or_else(|_| parse_san(&pos, s)) else {
+        println!("Illegal move: {}", s);
+        return;
+    };
+    xboard_finish_search(state, true);
+    let game = xboard_game(state);
+    game_push(game, &mov);
+    if let Some(claim) = xboard_result_claim(game) {
+        println!("{}", claim);
+    }
+    xboard_maybe_search(state);
+}
+
+// The analysis status line for ".": time in centiseconds, nodes and depth
+// of the last iteration, and the moves of the position (all searched).
+fn xboard_analysis_status(state: &XboardState) -> Option<String> {
+    let pos = xboard_awaited_position(state).filter(|_| state.analyzing)?;
+    let (depth, nodes) = *state.last_iteration.lock().unwrap();
+    let n_moves = generate_legal_moves(&pos).len;
+    Some(format!("stat01: {} {} {} 0 {}",
+        state.search_started.elapsed().as_millis() / 10, nodes, depth, n_moves))
+}
+
+// "40", "5" or "2:30" minutes, seconds possibly fractional.
+fn xboard_parse_level(state: &mut XboardState, args: &[&str]) -> bool {
+    let [mps, base, inc] = args else {
+        return false;
+    };
+    let base_secs = match base.split_once(':') {
+        Some((m, s)) => m.parse::<u64>().ok().zip(s.parse::<u64>().ok()).map(|(m, s)| 60 * m + s),
+        None => base.parse::<u64>().ok().map(|m| 60 * m),
+    };
+    match (mps.parse::<u32>(), base_secs, inc.parse::<f64>()) {
+        (Ok(mps), Some(base_secs), Ok(inc)) if inc >= 0.0 => {
+            state.moves_per_control = mps;
+            state.base_time = Duration::from_secs(base_secs);
+            state.increment = Duration::from_secs_f64(inc);
+            state.move_time = None;
+            true
+        },
+        _ => false,
+    }
+}
+
+// Handles one line of input. Returns false on quit.
+pub fn xboard_command(state: &mut XboardState, line: &str) -> bool {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&command, args)) = words.split_first() else {
+        return true;
+    };
+    let arg = args.first().copied().unwrap_or("");
+    match command {
+        "xboard" | "accepted" | "rejected" | "random" | "computer" | "name" | "rating"
+            | "ics" | "draw" | "white" | "black" | "memory"
+            | "cores" | "hint" | "bk" => {},
+        "protover" => {
+            println!("feature done=0");
+            println!("feature myname=\"oxidwig {}\" setboard=1 usermove=1 ping=1 playother=0 \
+                san=0 time=1 draw=0 sigint=0 sigterm=0 reuse=1 analyze=1 colors=0 \
+                name=0 nps=0 egt=\"syzygy\"", env!("CARGO_PKG_VERSION"));
+            println!("feature done=1");
+        },
+        "new" => {
+            xboard_finish_search(state, true);
+            searcher_clear(&mut state.searcher.lock().unwrap());
+            state.game = Some(new_game(&decode_fen(String::from(STARTING_FEN))));
+            state.engine_color = COLOR_BLACK;
+            state.analyzing = false;
+            state.depth = None;
+            state.engine_time = None;
+            state.opponent_time = None;
+        },
+        "setboard" => {
+            xboard_finish_search(state, true);
+            match parse_fen_with_options(&args.join(" "), true) {
+                Ok(pos) if validate(&pos).is_ok() => {
+                    state.game = Some(new_game(&pos));
+                    xboard_maybe_search(state);
+                },
+                _ => println!("tellusererror Illegal position"),
+            }
+        },
+        "usermove" => xboard_usermove(state, arg),
+        "go" => {
+            xboard_finish_search(state, true);
+            state.engine_color = xboard_game(state).position.active_color;
+            xboard_maybe_search(state);
+        },
+        "force" => {
+            xboard_finish_search(state, true);
+            state.engine_color = COLOR_EMPTY;
+        },
+        // Move now: the search stops and plays the best move it has found.
+        "?" if !state.analyzing && state.thread.is_some() => {
+            state.stop.store(true, Ordering::Relaxed);
+            xboard_finish_search(state, false);
+        },
+        "?" => {},
+        "level" => {
+            if !xboard_parse_level(state, args) {
+                println!("Error (bad level): {}", line);
+            }
+        },
+        "st" => match arg.parse::<f64>() {
+            Ok(secs) if secs > 0.0 => { state.move_time = Some(Duration::from_secs_f64(secs)); },
+            _ => println!("Error (bad time): {}", line),
+        },
+        "sd" => match arg.parse::<u32>() {
+            Ok(depth) if depth > 0 => { state.depth = Some(depth); },
+            _ => println!("Error (bad depth): {}", line),
+        },
+        "time" => match arg.parse::<u64>() {
+            Ok(cs) => { state.engine_time = Some(Duration::from_millis(10 * cs)); },
+            _ => println!("Error (bad time): {}", line),
+        },
+        "otim" => match arg.parse::<u64>() {
+            Ok(cs) => { state.opponent_time = Some(Duration::from_millis(10 * cs)); },
+            _ => println!("Error (bad time): {}", line),
+        },
+        "undo" | "remove" => {
+            xboard_finish_search(state, true);
+            let n = if command == "undo" { 1 } else { 2 };
+            let game = xboard_game(state);
+            for _ in 0 .. n {
+                game_pop(game);
+            }
+            if state.analyzing {
+                xboard_maybe_search(state);
+            }
+        },
+        // egtpath syzygy <path>
+        "egtpath" if arg == "syzygy" => {
+            xboard_finish_search(state, true);
+            syzygy_init(&mut state.searcher.lock().unwrap().syzygy, &args[1 ..].join(" "));
+            xboard_maybe_search(state);
+        },
+        "egtpath" => println!("Error (unsupported tablebases): {}", arg),
+        "analyze" => {
+            xboard_finish_search(state, true);
+            state.analyzing = true;
+            state.engine_color = COLOR_EMPTY;
+            xboard_maybe_search(state);
+        },
+        "exit" => {
+            xboard_finish_search(state, true);
+            state.analyzing = false;
+        },
+        "hard" => { state.ponder = true; },
+        "easy" => {
+            state.ponder = false;
+            if state.pondering.load(Ordering::Relaxed) {
+                xboard_finish_search(state, true);
+            }
+        },
+        "post" => state.post.store(true, Ordering::Relaxed),
+        "nopost" => state.post.store(false, Ordering::Relaxed),
+        "ping" => println!("pong {}", arg),
+        "." => {
+            if let Some(status) = xboard_analysis_status(state) {
+                println!("{}", status);
+            }
+        },
+        "result" => {
+            xboard_finish_search(state, true);
+            state.engine_color = COLOR_EMPTY;
+        },
+        "quit" => {
+            xboard_finish_search(state, true);
+            return false;
+        },
+        // Moves may come without usermove when the feature was rejected.
+        _ if xboard_awaited_position(state).is_some_and(|pos| parse_uci(&pos, command).is_ok()) => {
+            xboard_usermove(state, command);
+        },
+        _ => println!("Error (unknown command): {}", command),
+    }
+    true
+}
+
+pub fn run_xboard<R: BufRead>(reader: R) {
+    let mut state = new_xboard_state();
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if !xboard_command(&mut state, &line) {
+            return;
+        }
+    }
+    xboard_finish_search(&mut state, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Drives the protocol in force mode, where nothing is searched.
+    #[test]
+    fn xboard() {
+        let mut state = new_xboard_state();
+        for line in ["new", "force", "usermove e2e4", "usermove e7e5", "usermove g1f3", "undo"] {
+            assert!(xboard_command(&mut state, line));
+        }
+        assert_eq!(encode_fen(&xboard_game(&mut state).position),
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2");
+        xboard_command(&mut state, "remove");
+        assert_eq!(encode_fen(&xboard_game(&mut state).position),
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        xboard_command(&mut state, "setboard 6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1");
+        let game = xboard_game(&mut state);
+        let mov = parse_uci(&game.position, "d1d8").unwrap();
+        game_push(game, &mov);
+        assert_eq!(xboard_result_claim(game).as_deref(), Some("1-0 {White mates}"));
+        assert!(xboard_parse_level(&mut state, &["40", "2:30", "0.5"]));
+        assert_eq!((state.moves_per_control, state.base_time, state.increment),
+            (40, Duration::from_secs(150), Duration::from_millis(500)));
+        let limits = xboard_limits(&state, &decode_fen(String::from(STARTING_FEN)));
+        assert_eq!((limits.time, limits.moves_to_go), (Some(Duration::from_secs(150)), Some(40)));
+        assert!(!xboard_parse_level(&mut state, &["40", "x", "0"]));
+        // Pondering on e7e5 after e2e4: when it is played, the search goes
+        // on with the clocks sent before it.
+        for line in ["new", "force", "usermove e2e4"] {
+            xboard_command(&mut state, line);
+        }
+        state.pondering.store(true, Ordering::Relaxed);
+        *state.ponder_move.lock().unwrap() = Some(String::from("e7e5"));
+        for line in ["time 1000", "otim 2000", "usermove e7e5"] {
+            xboard_command(&mut state, line);
+        }
+        assert!(!state.pondering.load(Ordering::Relaxed));
+        let limits = state.ponderhit_limits.lock().unwrap().take().unwrap();
+        assert_eq!((limits.time, limits.opponent_time, limits.moves_to_go),
+            (Some(Duration::from_secs(10)), Some(Duration::from_secs(20)), Some(39)));
+        assert_eq!(score_to_xboard(MATE_SCORE - 3), 100002);
+        assert_eq!(score_to_xboard(-MATE_SCORE + 2), -100001);
+        xboard_command(&mut state, "otim 6000");
+        let limits = xboard_limits(&state, &decode_fen(String::from(STARTING_FEN)));
+        assert_eq!(limits.opponent_time, Some(Duration::from_secs(60)));
+        // Neither other commands nor illegal moves get in the way of the
+        // analysis.
+        for line in ["new", "force", "analyze", "ping 7", "bogus", "e2e5"] {
+            xboard_command(&mut state, line);
+        }
+        assert!(state.thread.is_some());
+        assert!(xboard_analysis_status(&state).unwrap().ends_with(" 0 20"));
+        xboard_command(&mut state, "e2e4");
+        assert_eq!(xboard_awaited_position(&state).map(|pos| pos.active_color), Some(COLOR_BLACK));
+        // Analyzing again starts over rather than waiting for the analysis.
+        xboard_command(&mut state, "analyze");
+        assert_eq!(xboard_awaited_position(&state).map(|pos| pos.active_color), Some(COLOR_BLACK));
+        xboard_command(&mut state, "exit");
+        assert!(xboard_analysis_status(&state).is_none());
+        assert!(!xboard_command(&mut state, "quit"));
+    }
+
+    // "?" cuts a long search short and plays its move.
+    #[test]
+    fn xboard_move_now() {
+        let mut state = new_xboard_state();
+        let started = Instant::now();
+        for line in ["new", "level 0 60 0", "go", "?"] {
+            xboard_command(&mut state, line);
+        }
+        assert!(state.thread.is_none());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(xboard_game(&mut state).position.active_color, COLOR_BLACK);
+        xboard_command(&mut state, "?");
+        assert!(!xboard_command(&mut state, "quit"));
+    }
+}